@@ -11,7 +11,9 @@ const VAULT_PARAMS = {
   targetLeverage: 2,
   rebalanceThreshold: 100, // 1%
   maxSlippage: 50, // 0.5%
+  vaultId: new anchor.BN(0),
 };
+const PROTOCOL_FEE_BPS = 1000; // 10% of vault fees
 
 interface DeploymentConfig {
   adminKeypair: Keypair;
@@ -68,6 +70,39 @@ async function createTestToken(
   return mint;
 }
 
+function getProtocolConfig(program: Program<DeltaNeutralVault>): PublicKey {
+  const [protocolConfig] = PublicKey.findProgramAddressSync(
+    [Buffer.from("protocol_config")],
+    program.programId
+  );
+  return protocolConfig;
+}
+
+async function initializeProtocol(config: DeploymentConfig): Promise<PublicKey> {
+  const { adminKeypair, program } = config;
+  const protocolConfig = getProtocolConfig(program);
+  
+  // The config is a program-wide singleton, only the first deployment creates it
+  const existing = await program.account.protocolConfig.fetchNullable(protocolConfig);
+  if (existing) {
+    console.log(`Protocol config already initialized: ${protocolConfig.toString()}`);
+    return protocolConfig;
+  }
+  
+  const tx = await program.methods
+    .initializeProtocol(PROTOCOL_FEE_BPS)
+    .accounts({
+      protocolConfig,
+      owner: adminKeypair.publicKey,
+      systemProgram: SystemProgram.programId,
+    })
+    .signers([adminKeypair])
+    .rpc();
+  
+  console.log(`Protocol config initialized! Transaction: ${tx}`);
+  return protocolConfig;
+}
+
 async function deployVault(config: DeploymentConfig): Promise<{
   vaultState: PublicKey;
   vaultTokenAccount: PublicKey;
@@ -76,9 +111,11 @@ async function deployVault(config: DeploymentConfig): Promise<{
   
   console.log("Deploying Delta Neutral Vault...");
   
+  const protocolConfig = await initializeProtocol(config);
+  
   // Derive PDA for vault state
   const [vaultState, vaultBump] = PublicKey.findProgramAddressSync(
-    [Buffer.from("vault"), adminKeypair.publicKey.toBuffer()],
+    [Buffer.from("vault"), adminKeypair.publicKey.toBuffer(), VAULT_PARAMS.vaultId.toArrayLike(Buffer, "le", 8)],
     program.programId
   );
   
@@ -88,6 +125,12 @@ async function deployVault(config: DeploymentConfig): Promise<{
     program.programId
   );
   
+  // Derive PDA for the program-wide vault registry
+  const [registry] = PublicKey.findProgramAddressSync(
+    [Buffer.from("registry")],
+    program.programId
+  );
+  
   console.log(`Vault State PDA: ${vaultState.toString()}`);
   console.log(`Vault Token Account PDA: ${vaultTokenAccount.toString()}`);
   
@@ -95,6 +138,7 @@ async function deployVault(config: DeploymentConfig): Promise<{
     // Initialize the vault
     const tx = await program.methods
       .initializeVault(
+        VAULT_PARAMS.vaultId,
         VAULT_PARAMS.targetLeverage,
         VAULT_PARAMS.rebalanceThreshold,
         VAULT_PARAMS.maxSlippage
@@ -102,6 +146,8 @@ async function deployVault(config: DeploymentConfig): Promise<{
       .accounts({
         vaultState,
         vaultTokenAccount,
+        registry,
+        protocolConfig,
         admin: adminKeypair.publicKey,
         tokenMint,
         driftProgram: config.driftProgram,
@@ -213,15 +259,26 @@ async function testDeposit(
   
   console.log(`Testing deposit of ${amount} tokens from ${user.publicKey.toString()}...`);
   
+  const [vaultUser] = PublicKey.findProgramAddressSync(
+    [Buffer.from("user"), vaultState.toBuffer(), user.publicKey.toBuffer()],
+    program.programId
+  );
+
   try {
     const tx = await program.methods
-      .deposit(new anchor.BN(amount * 10**6))
+      .deposit(new anchor.BN(amount * 10**6), null)
       .accounts({
         vaultState,
         vaultTokenAccount,
+        tokenMint: config.tokenMint,
         depositorTokenAccount: userTokenAccount,
+        vaultUser,
+        protocolConfig: getProtocolConfig(program),
         depositor: user.publicKey,
+        owner: user.publicKey,
+        referrerAccount: null,
         tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
       })
       .signers([user])
       .rpc();
@@ -255,10 +312,12 @@ async function testRebalance(
       .rebalance()
       .accounts({
         vaultState,
+        protocolConfig: getProtocolConfig(program),
         driftUser: Keypair.generate().publicKey, // Placeholder
         driftUserStats: Keypair.generate().publicKey, // Placeholder
         driftState: config.driftProgram,
         driftProgram: config.driftProgram,
+        history: null,
         authority: adminKeypair.publicKey,
       })
       .signers([adminKeypair])
@@ -8,13 +8,16 @@ import * as dotenv from "dotenv";
 dotenv.config();
 
 interface MigrationConfig {
-    oldProgramId: string;
-    newProgramId: string;
+    programId: string;
     vaultAddress: string;
     adminKeypair: Keypair;
     provider: anchor.AnchorProvider;
 }
 
+// Must match BASELINE_VAULT_STATE_SIZE and CURRENT_VAULT_VERSION in src/constants.rs
+const BASELINE_VAULT_STATE_SIZE = 477;
+const CURRENT_VAULT_VERSION = 6;
+
 async function setupProvider(): Promise<anchor.AnchorProvider> {
     const connection = new Connection(
         process.env.SOLANA_RPC_URL || anchor.web3.clusterApiUrl("devnet"),
@@ -74,79 +77,58 @@ async function backupVaultState(
     return backupData;
 }
 
+function vaultStateVersion(data: Buffer): number {
+    // v1 (baseline) accounts predate the version byte that follows the discriminator
+    return data.length === BASELINE_VAULT_STATE_SIZE ? 1 : data[8];
+}
+
 async function migrateVault(config: MigrationConfig): Promise<void> {
-    const { oldProgramId, newProgramId, vaultAddress, adminKeypair, provider } = config;
+    const { programId, vaultAddress, adminKeypair, provider } = config;
     
     console.log("🔄 Starting vault migration...");
-    console.log(`From: ${oldProgramId}`);
-    console.log(`To: ${newProgramId}`);
+    console.log(`Program: ${programId}`);
     console.log(`Vault: ${vaultAddress}`);
     
     // Backup current state
-    await backupVaultState(provider, vaultAddress);
+    const backup = await backupVaultState(provider, vaultAddress);
+    const fromVersion = vaultStateVersion(Buffer.from(backup.data));
+    console.log(`Current layout version: v${fromVersion}`);
     
-    // Load old and new programs
-    const oldProgram = await loadProgram(provider, oldProgramId);
-    const newProgram = await loadProgram(provider, newProgramId);
+    if (fromVersion >= CURRENT_VAULT_VERSION) {
+        console.log("✅ Vault is already on the current layout");
+        return;
+    }
     
-    // Get current vault state
+    const program = await loadProgram(provider, programId);
     const vaultPubkey = new PublicKey(vaultAddress);
-    const vaultState = await oldProgram.account.vaultState.fetch(vaultPubkey);
-    
-    console.log("📊 Current vault state:");
-    console.log(`- Total assets: ${vaultState.totalAssets}`);
-    console.log(`- Total shares: ${vaultState.totalShares}`);
-    console.log(`- Long position: ${vaultState.longPosition}`);
-    console.log(`- Short position: ${vaultState.shortPosition}`);
-    
-    // Create new vault with same parameters
-    console.log("🏗 Creating new vault...");
-    
-    const [newVaultState, newVaultBump] = PublicKey.findProgramAddressSync(
-        [Buffer.from("vault"), adminKeypair.publicKey.toBuffer()],
-        newProgram.programId
+    const [registryEntry] = PublicKey.findProgramAddressSync(
+        [Buffer.from("registry"), vaultPubkey.toBuffer()],
+        program.programId
     );
     
-    const [newVaultTokenAccount, newTokenBump] = PublicKey.findProgramAddressSync(
-        [Buffer.from("vault_token_account"), newVaultState.toBuffer()],
-        newProgram.programId
-    );
-    
-    // Initialize new vault
-    await newProgram.methods
-        .initializeVault(
-            vaultState.targetLeverage,
-            vaultState.rebalanceThreshold,
-            vaultState.maxSlippage
-        )
+    // Realloc and upgrade the account in place
+    const tx = await program.methods
+        .migrateVault()
         .accounts({
-            vaultState: newVaultState,
-            vaultTokenAccount: newVaultTokenAccount,
+            vaultState: vaultPubkey,
+            registryEntry,
             admin: adminKeypair.publicKey,
-            tokenMint: vaultState.tokenMint,
-            driftProgram: new PublicKey(process.env.DRIFT_PROGRAM_ID || "DRiFTvSoSLjH8XJx2wSJ1GL9jR8qXhF2vC2QBK5FyL9"),
-            tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
             systemProgram: anchor.web3.SystemProgram.programId,
-            rent: anchor.web3.SYSVAR_RENT_PUBKEY,
         })
         .signers([adminKeypair])
         .rpc();
     
-    console.log("✅ New vault initialized");
-    console.log(`New vault address: ${newVaultState.toString()}`);
-    
-    // Transfer assets from old vault to new vault
-    console.log("💰 Transferring assets...");
+    console.log(`✅ Vault migrated to v${CURRENT_VAULT_VERSION}. Tx: ${tx}`);
     
-    // This would require implementing a migration instruction
-    // that allows transferring assets between vaults
+    // The account now deserializes with the current IDL
+    const vaultState = await program.account.vaultState.fetch(vaultPubkey);
     
-    console.log("✅ Migration completed");
-    console.log("\n📋 Migration summary:");
-    console.log(`Old vault: ${vaultAddress}`);
-    console.log(`New vault: ${newVaultState.toString()}`);
-    console.log(`Old program: ${oldProgramId}`);
-    console.log(`New program: ${newProgramId}`);
+    console.log("📊 Migrated vault state:");
+    console.log(`- Version: ${vaultState.version}`);
+    console.log(`- Total assets: ${vaultState.totalAssets}`);
+    console.log(`- Total shares: ${vaultState.totalShares}`);
+    console.log(`- Long position: ${vaultState.longPosition}`);
+    console.log(`- Short position: ${vaultState.shortPosition}`);
 }
 
 async function main() {
@@ -159,8 +141,7 @@ async function main() {
     
     // Configuration
     const config: MigrationConfig = {
-        oldProgramId: process.env.OLD_PROGRAM_ID || "DNVt1111111111111111111111111111111111111111",
-        newProgramId: process.env.NEW_PROGRAM_ID || "DNVt1111111111111111111111111111111111111111",
+        programId: process.env.PROGRAM_ID || "DNVt1111111111111111111111111111111111111111",
         vaultAddress: process.env.VAULT_ADDRESS || "",
         adminKeypair,
         provider
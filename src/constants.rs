@@ -7,10 +7,17 @@ pub const PROGRAM_ID: &str = "DNVt1111111111111111111111111111111111111111";
 pub const VAULT_SEED: &[u8] = b"vault";
 pub const VAULT_TOKEN_ACCOUNT_SEED: &[u8] = b"vault_token_account";
 pub const USER_SEED: &[u8] = b"user";
+pub const REGISTRY_SEED: &[u8] = b"registry";
+pub const PROTOCOL_CONFIG_SEED: &[u8] = b"protocol_config";
+pub const COLLATERAL_TOKEN_ACCOUNT_SEED: &[u8] = b"collateral_token_account";
+pub const REFERRER_SEED: &[u8] = b"referrer";
+pub const HISTORY_SEED: &[u8] = b"history";
 
 // Fee constants (in basis points)
 pub const MAX_MANAGEMENT_FEE_BPS: u16 = 500; // 5%
 pub const MAX_PERFORMANCE_FEE_BPS: u16 = 5000; // 50%
+pub const MAX_PROTOCOL_FEE_BPS: u16 = 5000; // 50% of vault fees
+pub const MAX_REFERRAL_FEE_SHARE_BPS: u16 = 5000; // 50% of fees on referred shares
 pub const DEFAULT_MANAGEMENT_FEE_BPS: u16 = 200; // 2%
 pub const DEFAULT_PERFORMANCE_FEE_BPS: u16 = 2000; // 20%
 
@@ -21,13 +28,44 @@ pub const MAX_REBALANCE_THRESHOLD_BPS: u16 = 1000; // 10%
 pub const MAX_SLIPPAGE_BPS: u16 = 1000; // 10%
 pub const MIN_REBALANCE_INTERVAL: i64 = 300; // 5 minutes
 
+// Order tracking constants
+pub const MAX_OPEN_ORDERS: usize = 8;
+pub const MAX_MARKETS: usize = 4;
+pub const MAX_DRIFT_SUB_ACCOUNTS: usize = 4;
+pub const ORDER_TIME_TO_LIVE: i64 = 60; // 1 minute
+pub const MAX_POSITION_BOOK_AGE_SLOTS: u64 = 150; // ~1 minute
+
+// Share accounting constants
+pub const VIRTUAL_SHARES: u64 = 1_000_000; // Virtual shares backing the share price
+pub const VIRTUAL_ASSETS: u64 = 1_000_000; // Virtual assets backing the share price
+pub const MIN_INITIAL_DEPOSIT: u64 = 1_000_000; // 1 token (6 decimals)
+pub const MAX_CLOSE_DUST: u64 = VIRTUAL_ASSETS; // Residue the virtual assets keep once every share is redeemed
+
+// Collateral constants
+pub const MAX_COLLATERALS: usize = 4;
+pub const MAX_COLLATERAL_HAIRCUT_BPS: u16 = 5000; // 50%
+pub const MAX_COLLATERAL_VALUATION_AGE_SLOTS: u64 = 150; // ~1 minute
+
+// Oracle constants
+pub const MAX_ORACLE_DELAY_SLOTS: i64 = 25; // ~10 seconds
+pub const MAX_ORACLE_CONFIDENCE_BPS: u16 = 200; // 2% of the price
+
+// Capacity constants
+pub const DEFAULT_MAX_CAPACITY: u64 = 1_000_000_000_000; // 1M tokens
+pub const DEFAULT_FLOW_EPOCH_DURATION: i64 = SECONDS_PER_DAY; // 1 day
+pub const MAX_FLOW_EPOCH_DURATION: i64 = 30 * SECONDS_PER_DAY; // 30 days
+
+// Withdrawal policy constants
+pub const MAX_LOCKUP_PERIOD: i64 = 30 * SECONDS_PER_DAY; // 30 days
+pub const MAX_EARLY_EXIT_FEE_BPS: u16 = 500; // 5%
+
 // Precision constants
 pub const BASIS_POINTS_DIVISOR: u64 = 10_000;
 pub const SHARE_PRICE_PRECISION: u64 = 1_000_000; // 6 decimals
 pub const PRICE_PRECISION: u64 = 1_000_000; // 6 decimals
-
-// Drift Protocol constants
-pub const DRIFT_PROGRAM_ID: &str = "DRiFTvSoSLjH8XJx2wSJ1GL9jR8qXhF2vC2QBK5FyL9";
+pub const BASE_PRECISION: u64 = 1_000_000_000; // Drift base asset precision (9 decimals)
+pub const REFERRAL_FEE_PRECISION: u128 = 1_000_000_000_000; // Scale for referral fees per share
+pub const FEES_PER_SHARE_PRECISION: u128 = 1_000_000_000_000; // Scale for vault fees per share
 
 // Market constants
 pub const SOL_MARKET_INDEX: u16 = 0;
@@ -38,6 +76,10 @@ pub const ETH_MARKET_INDEX: u16 = 2;
 pub const SECONDS_PER_DAY: i64 = 86400;
 pub const SECONDS_PER_YEAR: i64 = 31536000;
 
+// Share-price history constants
+pub const HISTORY_CAPACITY: usize = 256;
+pub const MIN_HISTORY_SNAPSHOT_INTERVAL: i64 = 3 * 3600; // 3 hours, so a full buffer spans at least 32 days
+
 // Error messages
 pub const ERROR_VAULT_FULL: &str = "Vault is at maximum capacity";
 pub const ERROR_INSUFFICIENT_FUNDS: &str = "Insufficient funds for operation";
@@ -48,8 +90,9 @@ pub const ERROR_INVALID_LEVERAGE: &str = "Invalid leverage ratio";
 pub const ERROR_INVALID_FEES: &str = "Invalid fee structure";
 
 // Event constants
-pub const MAX_EVENT_STRING_LENGTH: usize = 100;
+pub const EVENT_SCHEMA_VERSION: u8 = 7; // Bumped whenever an event layout changes
 
-// Account size constants
-pub const VAULT_STATE_SIZE: usize = 8 + 32 + 1 + 1 + 2 + 2 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 1 + 8 + 32 + 32 + 32 + 2 + 2 + 8 + 2 + (32 * 8);
-pub const USER_STATE_SIZE: usize = 8 + 32 + 32 + 8 + 8 + 8 + 8 + 1;
+// Versioning constants
+pub const CURRENT_VAULT_VERSION: u8 = 6;
+pub const BASELINE_VAULT_STATE_SIZE: usize = 477; // v1, the first released layout, without a version byte
+pub const VAULT_STATE_V2_SIZE: usize = 1468; // v2 through v5, which only carve fields from reserved space
@@ -1,29 +1,51 @@
 use anchor_lang::prelude::*;
 use drift::cpi::accounts::*;
+use drift::controller::position::PositionDirection;
 use drift::program::Drift;
 use drift::state::*;
 use drift::instructions::*;
 
 use crate::state::*;
 use crate::errors::*;
+use crate::constants::*;
+use crate::math::*;
+use crate::events::DriftSubAccountAdded;
+use crate::InitializeDriftUser;
 
-/// Initialize a new Drift user account for the vault
+/// Create the vault's Drift user stats and its first Drift user, which
+/// becomes `drift_user` (admin only)
 pub fn initialize_drift_user(
     ctx: Context<InitializeDriftUser>,
     sub_account_id: u16,
 ) -> Result<()> {
-    let vault_state = &ctx.accounts.vault_state;
-    
+    let vault_key = ctx.accounts.vault_state.key();
+    let clock = Clock::get()?;
+
     // Create signer seeds for the vault PDA
-    let admin_key = vault_state.admin;
-    let bump = vault_state.bump;
+    let (admin_key, vault_id, bump) = {
+        let vault_state = ctx.accounts.vault_state.load()?;
+        (vault_state.admin, vault_state.vault_id_seed(), vault_state.bump)
+    };
     let signer_seeds = &[
-        b"vault",
+        VAULT_SEED,
         admin_key.as_ref(),
+        vault_id.as_slice(),
         &[bump],
     ];
     let signer = &[&signer_seeds[..]];
     
+    // Drift only creates users under an authority that already has user stats
+    initialize_drift_user_stats(
+        &ctx.accounts.drift_program,
+        &ctx.accounts.drift_user_stats,
+        &ctx.accounts.drift_state,
+        &ctx.accounts.vault_state.to_account_info(),
+        &ctx.accounts.admin.to_account_info(),
+        &ctx.accounts.rent.to_account_info(),
+        &ctx.accounts.system_program.to_account_info(),
+        signer,
+    )?;
+    
     // Initialize Drift user via CPI
     let cpi_ctx = CpiContext::new_with_signer(
         ctx.accounts.drift_program.to_account_info(),
@@ -31,8 +53,8 @@ pub fn initialize_drift_user(
             user: ctx.accounts.drift_user.to_account_info(),
             user_stats: ctx.accounts.drift_user_stats.to_account_info(),
             state: ctx.accounts.drift_state.to_account_info(),
-            authority: vault_state.to_account_info(),
-            payer: ctx.accounts.payer.to_account_info(),
+            authority: ctx.accounts.vault_state.to_account_info(),
+            payer: ctx.accounts.admin.to_account_info(),
             rent: ctx.accounts.rent.to_account_info(),
             system_program: ctx.accounts.system_program.to_account_info(),
         },
@@ -41,94 +63,53 @@ pub fn initialize_drift_user(
     
     drift::cpi::initialize_user(cpi_ctx, sub_account_id, None)?;
     
-    Ok(())
-}
-
-/// Place a perp order on Drift to hedge positions
-pub fn place_perp_order(
-    ctx: Context<PlacePerpOrder>,
-    order_params: OrderParams,
-) -> Result<()> {
-    let vault_state = &ctx.accounts.vault_state;
-    
-    // Validate order parameters
-    require!(
-        order_params.market_type == MarketType::Perp,
-        VaultError::InvalidMarketState
-    );
-    
-    // Create signer seeds for the vault PDA
-    let admin_key = vault_state.admin;
-    let bump = vault_state.bump;
-    let signer_seeds = &[
-        b"vault",
-        admin_key.as_ref(),
-        &[bump],
-    ];
-    let signer = &[&signer_seeds[..]];
-    
-    // Place order via CPI
-    let cpi_ctx = CpiContext::new_with_signer(
-        ctx.accounts.drift_program.to_account_info(),
-        PlaceOrder {
-            state: ctx.accounts.drift_state.to_account_info(),
-            user: ctx.accounts.drift_user.to_account_info(),
-            user_stats: ctx.accounts.drift_user_stats.to_account_info(),
-            authority: vault_state.to_account_info(),
-        },
-        signer,
-    );
-    
-    drift::cpi::place_perp_order(cpi_ctx, order_params)?;
-    
-    emit!(OrderPlacedEvent {
-        market_index: order_params.market_index,
-        direction: order_params.direction,
-        base_asset_amount: order_params.base_asset_amount,
-        price: order_params.price.unwrap_or(0),
-        order_type: order_params.order_type,
-        timestamp: Clock::get()?.unix_timestamp,
+    // Remember the Drift accounts so later instructions can verify them. A
+    // new user holds no positions, so its book starts out in sync.
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    vault_state.drift_user_authority = vault_key;
+    vault_state.drift_user_stats = ctx.accounts.drift_user_stats.key();
+    let slot = vault_state.add_drift_sub_account(ctx.accounts.drift_user.key())?;
+    vault_state.sync_sub_account(slot, 0, 0, clock.slot)?;
+    
+    emit!(DriftSubAccountAdded {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        drift_user: ctx.accounts.drift_user.key(),
+        sub_account_id,
+        slot: slot as u8,
+        timestamp: clock.unix_timestamp,
     });
     
+    msg!("Drift user {} initialized for vault {}", ctx.accounts.drift_user.key(), vault_key);
     Ok(())
 }
 
-/// Cancel an existing order on Drift
-pub fn cancel_order(
-    ctx: Context<CancelDriftOrder>,
-    order_id: u32,
+/// Create the Drift user stats shared by every Drift user of the vault
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn initialize_drift_user_stats<'info>(
+    drift_program: &Program<'info, Drift>,
+    drift_user_stats: &AccountInfo<'info>,
+    drift_state: &AccountInfo<'info>,
+    vault_state: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    rent: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    signer: &[&[&[u8]]],
 ) -> Result<()> {
-    let vault_state = &ctx.accounts.vault_state;
-    
-    // Create signer seeds for the vault PDA
-    let admin_key = vault_state.admin;
-    let bump = vault_state.bump;
-    let signer_seeds = &[
-        b"vault",
-        admin_key.as_ref(),
-        &[bump],
-    ];
-    let signer = &[&signer_seeds[..]];
-    
-    // Cancel order via CPI
     let cpi_ctx = CpiContext::new_with_signer(
-        ctx.accounts.drift_program.to_account_info(),
-        CancelOrder {
-            state: ctx.accounts.drift_state.to_account_info(),
-            user: ctx.accounts.drift_user.to_account_info(),
-            authority: vault_state.to_account_info(),
+        drift_program.to_account_info(),
+        InitializeUserStats {
+            user_stats: drift_user_stats.clone(),
+            state: drift_state.clone(),
+            authority: vault_state.clone(),
+            payer: payer.clone(),
+            rent: rent.clone(),
+            system_program: system_program.clone(),
         },
         signer,
     );
-    
-    drift::cpi::cancel_order(cpi_ctx, order_id)?;
-    
-    emit!(OrderCancelledEvent {
-        order_id,
-        timestamp: Clock::get()?.unix_timestamp,
-    });
-    
-    Ok(())
+    drift::cpi::initialize_user_stats(cpi_ctx)
 }
 
 /// Calculate optimal hedge size based on current positions and target delta
@@ -140,25 +121,25 @@ pub fn calculate_hedge_order_params(
     oracle_price: u64,
 ) -> Result<OrderParams> {
     let current_delta = vault_state.calculate_delta()?;
-    let delta_diff = current_delta - target_delta;
+    let delta_diff = current_delta.safe_sub(target_delta)?;
     
-    if delta_diff.abs() < 1000 {
+    if delta_diff.unsigned_abs() < 1000 {
         return Err(VaultError::RebalanceNotNeeded.into());
     }
     
     let (direction, base_asset_amount) = if delta_diff > 0 {
         // Too much long exposure, need to short
-        (PositionDirection::Short, delta_diff.abs() as u64)
+        (PositionDirection::Short, delta_diff.unsigned_abs())
     } else {
         // Too much short exposure, need to long
-        (PositionDirection::Long, delta_diff.abs() as u64)
+        (PositionDirection::Long, delta_diff.unsigned_abs())
     };
     
     // Calculate limit price with slippage protection
-    let slippage_adjustment = oracle_price * vault_state.max_slippage as u64 / 10_000;
+    let slippage_adjustment = bps_of(oracle_price, vault_state.max_slippage, Rounding::Down)?;
     let limit_price = match direction {
-        PositionDirection::Long => oracle_price + slippage_adjustment,
-        PositionDirection::Short => oracle_price - slippage_adjustment,
+        PositionDirection::Long => oracle_price.safe_add(slippage_adjustment)?,
+        PositionDirection::Short => oracle_price.safe_sub(slippage_adjustment)?,
     };
     
     Ok(OrderParams {
@@ -194,7 +175,7 @@ pub fn get_position_info(
         .ok_or(VaultError::InvalidMarketState)?;
     
     let base_asset_amount = position.base_asset_amount;
-    let quote_asset_amount = position.quote_asset_amount.abs() as u64;
+    let quote_asset_amount = position.quote_asset_amount.unsigned_abs();
     
     Ok((base_asset_amount, quote_asset_amount))
 }
@@ -215,25 +196,31 @@ pub fn calculate_unrealized_pnl(
         return Ok(0);
     }
     
-    let entry_price = position.quote_asset_amount.abs() as u64 / position.base_asset_amount.abs() as u64;
+    let entry_price = position
+        .quote_asset_amount
+        .unsigned_abs()
+        .safe_div(position.base_asset_amount.unsigned_abs())?;
     let price_diff = if position.base_asset_amount > 0 {
         // Long position
-        oracle_price as i64 - entry_price as i64
+        to_i64(oracle_price)?.safe_sub(to_i64(entry_price)?)?
     } else {
         // Short position
-        entry_price as i64 - oracle_price as i64
+        to_i64(entry_price)?.safe_sub(to_i64(oracle_price)?)?
     };
     
-    let unrealized_pnl = price_diff * position.base_asset_amount.abs() / 1_000_000; // Adjust for precision
+    let unrealized_pnl = mul_div_signed(price_diff, position.base_asset_amount.abs(), 1_000_000)?; // Adjust for precision
     
     Ok(unrealized_pnl)
 }
 
-/// Update vault positions based on Drift user account
+/// Update a sub-account's positions from its Drift user account and
+/// re-aggregate the vault's legs
 pub fn sync_vault_positions(
     vault_state: &mut VaultState,
-    drift_user: &Account<User>,
+    sub_account: usize,
+    drift_user: &User,
     market_indices: &[u16],
+    current_slot: u64,
 ) -> Result<()> {
     let mut total_long = 0i64;
     let mut total_short = 0i64;
@@ -245,109 +232,29 @@ pub fn sync_vault_positions(
             .find(|p| p.market_index == market_index)
         {
             if position.base_asset_amount > 0 {
-                total_long += position.base_asset_amount;
+                total_long = total_long.safe_add(position.base_asset_amount)?;
             } else {
-                total_short += position.base_asset_amount;
+                total_short = total_short.safe_add(position.base_asset_amount)?;
             }
         }
     }
     
-    vault_state.long_position = total_long;
-    vault_state.short_position = total_short;
-    
-    Ok(())
-}
-
-// Account contexts for Drift integration
-#[derive(Accounts)]
-pub struct InitializeDriftUser<'info> {
-    #[account(mut)]
-    pub vault_state: Account<'info, VaultState>,
-    
-    /// CHECK: Drift user account
-    #[account(mut)]
-    pub drift_user: UncheckedAccount<'info>,
-    
-    /// CHECK: Drift user stats
-    #[account(mut)]
-    pub drift_user_stats: UncheckedAccount<'info>,
-    
-    /// CHECK: Drift state
-    #[account(mut)]
-    pub drift_state: UncheckedAccount<'info>,
-    
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    
-    pub drift_program: Program<'info, Drift>,
-    pub system_program: Program<'info, System>,
-    pub rent: Sysvar<'info, Rent>,
-}
-
-#[derive(Accounts)]
-pub struct PlacePerpOrder<'info> {
-    #[account(
-        seeds = [b"vault", vault_state.admin.as_ref()],
-        bump = vault_state.bump
-    )]
-    pub vault_state: Account<'info, VaultState>,
-    
-    /// CHECK: Drift user account
-    #[account(mut)]
-    pub drift_user: UncheckedAccount<'info>,
-    
-    /// CHECK: Drift user stats
-    #[account(mut)]
-    pub drift_user_stats: UncheckedAccount<'info>,
-    
-    /// CHECK: Drift state
-    #[account(mut)]
-    pub drift_state: UncheckedAccount<'info>,
-    
-    pub drift_program: Program<'info, Drift>,
-}
-
-#[derive(Accounts)]
-pub struct CancelDriftOrder<'info> {
-    #[account(
-        seeds = [b"vault", vault_state.admin.as_ref()],
-        bump = vault_state.bump
-    )]
-    pub vault_state: Account<'info, VaultState>,
-    
-    /// CHECK: Drift user account
-    #[account(mut)]
-    pub drift_user: UncheckedAccount<'info>,
-    
-    /// CHECK: Drift state
-    #[account(mut)]
-    pub drift_state: UncheckedAccount<'info>,
-    
-    pub drift_program: Program<'info, Drift>,
+    vault_state.sync_sub_account(sub_account, total_long, total_short, current_slot)
 }
 
 // Events for Drift operations
-#[event]
-pub struct OrderPlacedEvent {
-    pub market_index: u16,
-    pub direction: PositionDirection,
-    pub base_asset_amount: u64,
-    pub price: u64,
-    pub order_type: OrderType,
-    pub timestamp: i64,
-}
-
-#[event]
-pub struct OrderCancelledEvent {
-    pub order_id: u32,
-    pub timestamp: i64,
-}
-
 #[event]
 pub struct PositionUpdatedEvent {
-    pub market_index: u16,
-    pub old_position: i64,
-    pub new_position: i64,
-    pub pnl: i64,
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub drift_user: Pubkey,
+    pub old_long_position: i64,
+    pub old_short_position: i64,
+    pub new_long_position: i64,
+    pub new_short_position: i64,
+    pub long_discrepancy: i64,       // New minus old long leg
+    pub short_discrepancy: i64,      // New minus old short leg
+    pub slot: u64,
     pub timestamp: i64,
 }
\ No newline at end of file
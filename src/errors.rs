@@ -1,1054 +1,286 @@
 use anchor_lang::prelude::*;
 
+/// Program errors, numbered by subsystem. Anchor adds 6000 to each value, so
+/// a code's hundreds digit names the subsystem that rejected the transaction:
+///
+/// - 60xx: vault lifecycle, administration and access control
+/// - 61xx: checked math and conversions
+/// - 62xx: deposits, withdrawals and shares
+/// - 63xx: fees
+/// - 64xx: Drift accounts, markets and orders
+/// - 65xx: oracle prices
+/// - 66xx: risk parameters, slippage and rebalancing
+///
+/// Codes are stable: new variants take the next free value in their group
+/// and existing values are never reused.
 #[error_code]
-pub enum DeltaNeutralVaultError {
+pub enum VaultError {
+    // Vault lifecycle, administration and access control
+    #[msg("Signer is not authorized for this action")]
+    UnauthorizedAccess = 0,
+
+    #[msg("Parameter update is invalid")]
+    InvalidParameterUpdate,
+
+    #[msg("Vault account data is invalid")]
+    InvalidVaultState,
+
+    #[msg("Vault account layout version is not supported")]
+    UnsupportedVaultVersion,
+
+    #[msg("Vault account is already on the current layout")]
+    VaultAlreadyMigrated,
+
+    #[msg("Protocol is paused")]
+    ProtocolPaused,
+
+    #[msg("Emergency stop is active")]
+    EmergencyStopActive,
+
+    // No longer raised since registry entries became per-vault; kept so
+    // later codes don't shift
+    #[msg("Vault registry is full")]
+    RegistryFull,
+
+    #[msg("Token mint does not match the vault")]
+    InvalidTokenMint,
+
+    #[msg("Token account does not match the expected owner or mint")]
+    InvalidTokenAccount,
+
+    #[msg("Vault still holds shares, collateral or tokens")]
+    VaultNotEmpty,
+
+    // Checked math and conversions
+    #[msg("Arithmetic overflow")]
+    MathOverflow = 100,
+
+    #[msg("Arithmetic underflow")]
+    MathUnderflow,
+
+    #[msg("Division by zero")]
+    DivisionByZero,
+
+    #[msg("Value does not fit the target integer type")]
+    ConversionFailed,
+
+    // Deposits, withdrawals and shares
+    #[msg("Amount must be greater than zero")]
+    InvalidAmount = 200,
+
+    #[msg("Amount is too small to mint any shares")]
+    InvalidShareCalculation,
+
+    #[msg("Vault has no shares outstanding")]
+    InsufficientShares,
+
+    #[msg("Requested amount exceeds the available balance")]
+    InsufficientFunds,
+
+    #[msg("Initial deposit is below the minimum")]
+    DepositBelowMinimum,
+
+    #[msg("Deposit exceeds the depositor's cap")]
+    DepositCapExceeded,
+
+    #[msg("Vault is at maximum capacity")]
+    VaultAtCapacity,
+
+    #[msg("Net flow for this epoch exceeds the vault's limit")]
+    FlowLimitExceeded,
+
+    #[msg("Capacity or flow limit is invalid")]
+    InvalidCapacityLimits,
+
+    #[msg("Withdrawal is locked until the lockup period ends")]
+    WithdrawalLocked,
+
+    #[msg("Depositor is not on the vault allowlist")]
+    NotAllowlisted,
+
+    #[msg("Referrer account does not match the user's referrer")]
+    InvalidReferrer,
+
+    #[msg("Collateral asset is not supported by the vault")]
+    CollateralNotSupported,
+
+    #[msg("Collateral valuation is stale, revalue collateral first")]
+    CollateralValuationStale,
+
+    #[msg("Too many collateral assets configured")]
+    TooManyCollaterals,
+
+    #[msg("User account still holds shares")]
+    SharesOutstanding,
+
+    // Fees
+    #[msg("Fee exceeds its maximum")]
+    InvalidFeeStructure = 300,
+
+    // Drift accounts, markets and orders
+    #[msg("Drift program account is missing or invalid")]
+    InvalidDriftProgram = 400,
+
+    #[msg("Market index does not match the account")]
+    InvalidMarketIndex,
+
+    #[msg("Market state is invalid for this operation")]
+    InvalidMarketState,
+
+    #[msg("Order is not tracked by the vault")]
+    UnknownOrder,
+
+    #[msg("Too many open orders")]
+    TooManyOpenOrders,
+
+    #[msg("Too many markets tracked")]
+    TooManyMarkets,
+
+    #[msg("Position book is stale, sync positions first")]
+    PositionBookStale,
+
+    #[msg("Invalid position direction")]
+    InvalidPositionDirection,
+
+    #[msg("No position to close")]
+    NoPositionToClose,
+
+    #[msg("Vault still has open perp positions")]
+    PositionsOpen,
+
+    #[msg("Vault still has open orders")]
+    OrdersOpen,
+
+    #[msg("Drift user is not one of the vault's sub-accounts")]
+    UnknownSubAccount,
+
+    #[msg("Too many Drift sub-accounts")]
+    TooManySubAccounts,
+
+    #[msg("Market is traded by another Drift sub-account")]
+    MarketOnOtherSubAccount,
+
+    #[msg("Vault already has a Drift user")]
+    DriftUserAlreadyInitialized,
+
+    #[msg("Drift user stats do not match the vault's")]
+    DriftUserStatsMismatch,
+
+    // Oracle prices
+    #[msg("Oracle price is missing or not positive")]
+    InvalidOraclePrice = 500,
+
+    #[msg("Price calculation failed")]
+    PriceCalculationFailed,
+
+    #[msg("Oracle account does not match the market")]
+    InvalidOracleAccount,
+
+    #[msg("Oracle price is stale")]
+    OracleStale,
+
+    #[msg("Oracle confidence interval is too wide")]
+    OracleConfidenceTooWide,
+
+    // Risk parameters, slippage and rebalancing
     #[msg("Invalid leverage ratio")]
-    InvalidLeverage,
-    
+    InvalidLeverage = 600,
+
     #[msg("Invalid rebalance threshold")]
     InvalidRebalanceThreshold,
-    
+
     #[msg("Invalid slippage tolerance")]
     InvalidSlippage,
-    
-    #[msg("Vault is at maximum capacity")]
-    VaultAtCapacity,
-    
-    #[msg("Insufficient funds for operation")]
-    InsufficientFunds,
-    
-    #[msg("Emergency stop is active")]
-    EmergencyStopActive,
-    
+
+    #[msg("Fill slippage exceeds the vault's tolerance")]
+    SlippageExceeded,
+
     #[msg("Rebalance cooldown not met")]
     RebalanceCooldown,
-    
+
     #[msg("Rebalancing is not needed")]
     RebalanceNotNeeded,
-    
-    #[msg("Invalid position direction")]
-    InvalidPositionDirection,
-    
-    #[msg("No position to close")]
-    NoPositionToClose,
-    
-    #[msg("Invalid amount")]
-    InvalidAmount,
-    
-    #[msg("Invalid share calculation")]
-    InvalidShareCalculation,
-    
-    #[msg("Invalid fee calculation")]
-    InvalidFeeCalculation,
-    
-    #[msg("Invalid market index")]
-    InvalidMarketIndex,
-    
-    #[msg("Drift integration error")]
-    DriftIntegrationError,
-    
-    #[msg("Price oracle error")]
-    PriceOracleError,
-    
-    #[msg("Slippage exceeded")]
-    SlippageExceeded,
-    
-    #[msg("Invalid token mint")]
-    InvalidTokenMint,
-    
-    #[msg("Invalid token account")]
-    InvalidTokenAccount,
-    
-    #[msg("Unauthorized access")]
-    UnauthorizedAccess,
-    
-    #[msg("Invalid vault state")]
-    InvalidVaultState,
-    
-    #[msg("Invalid user state")]
-    InvalidUserState,
-    
-    #[msg("Invalid fee structure")]
-    InvalidFeeStructure,
-    
-    #[msg("Invalid time calculation")]
-    InvalidTimeCalculation,
-    
-    #[msg("Invalid delta calculation")]
-    InvalidDeltaCalculation,
-    
-    #[msg("Invalid hedge calculation")]
-    InvalidHedgeCalculation,
-    
-    #[msg("Invalid share price")]
-    InvalidSharePrice,
-    
-    #[msg("Invalid total value calculation")]
-    InvalidTotalValueCalculation,
-    
-    #[msg("Invalid performance calculation")]
-    InvalidPerformanceCalculation,
-    
-    #[msg("Invalid management fee calculation")]
-    InvalidManagementFeeCalculation,
-    
-    #[msg("Invalid performance fee calculation")]
-    InvalidPerformanceFeeCalculation,
-    
-    #[msg("Invalid rebalance interval")]
-    InvalidRebalanceInterval,
-    
-    #[msg("Invalid delta threshold")]
-    InvalidDeltaThreshold,
-    
-    #[msg("Invalid market data")]
-    InvalidMarketData,
-    
-    #[msg("Invalid order placement")]
-    InvalidOrderPlacement,
-    
-    #[msg("Invalid order cancellation")]
-    InvalidOrderCancellation,
-    
-    #[msg("Invalid position management")]
-    InvalidPositionManagement,
-    
+
     #[msg("Invalid risk parameters")]
     InvalidRiskParameters,
-    
-    #[msg("Invalid capacity management")]
-    InvalidCapacityManagement,
-    
-    #[msg("Invalid fee collection")]
-    InvalidFeeCollection,
-    
-    #[msg("Invalid parameter update")]
-    InvalidParameterUpdate,
-    
-    #[msg("Invalid emergency stop")]
-    InvalidEmergencyStop,
-    
-    #[msg("Invalid vault initialization")]
-    InvalidVaultInitialization,
-    
-    #[msg("Invalid deposit operation")]
-    InvalidDepositOperation,
-    
-    #[msg("Invalid withdrawal operation")]
-    InvalidWithdrawalOperation,
-    
-    #[msg("Invalid rebalance operation")]
-    InvalidRebalanceOperation,
-    
-    #[msg("Invalid position opening")]
-    InvalidPositionOpening,
-    
-    #[msg("Invalid position closing")]
-    InvalidPositionClosing,
-    
-    #[msg("Invalid Drift user account")]
-    InvalidDriftUserAccount,
-    
-    #[msg("Invalid Drift user stats")]
-    InvalidDriftUserStats,
-    
-    #[msg("Invalid Drift state")]
-    InvalidDriftState,
-    
-    #[msg("Invalid Drift program")]
-    InvalidDriftProgram,
-    
-    #[msg("Invalid token program")]
-    InvalidTokenProgram,
-    
-    #[msg("Invalid system program")]
-    InvalidSystemProgram,
-    
-    #[msg("Invalid rent sysvar")]
-    InvalidRentSysvar,
-    
-    #[msg("Invalid clock sysvar")]
-    InvalidClockSysvar,
-    
-    #[msg("Invalid PDA derivation")]
-    InvalidPdaDerivation,
-    
-    #[msg("Invalid bump seed")]
-    InvalidBumpSeed,
-    
-    #[msg("Invalid account validation")]
-    InvalidAccountValidation,
-    
-    #[msg("Invalid instruction data")]
-    InvalidInstructionData,
-    
-    #[msg("Invalid event emission")]
-    InvalidEventEmission,
-    
-    #[msg("Invalid logging")]
-    InvalidLogging,
-    
-    #[msg("Invalid error handling")]
-    InvalidErrorHandling,
-    
-    #[msg("Invalid state management")]
-    InvalidStateManagement,
-    
-    #[msg("Invalid memory management")]
-    InvalidMemoryManagement,
-    
-    #[msg("Invalid computation")]
-    InvalidComputation,
-    
-    #[msg("Invalid overflow")]
-    InvalidOverflow,
-    
-    #[msg("Invalid underflow")]
-    InvalidUnderflow,
-    
-    #[msg("Invalid division by zero")]
-    InvalidDivisionByZero,
-    
-    #[msg("Invalid modulo operation")]
-    InvalidModuloOperation,
-    
-    #[msg("Invalid bitwise operation")]
-    InvalidBitwiseOperation,
-    
-    #[msg("Invalid comparison")]
-    InvalidComparison,
-    
-    #[msg("Invalid conversion")]
-    InvalidConversion,
-    
-    #[msg("Invalid serialization")]
-    InvalidSerialization,
-    
-    #[msg("Invalid deserialization")]
-    InvalidDeserialization,
-    
-    #[msg("Invalid encoding")]
-    InvalidEncoding,
-    
-    #[msg("Invalid decoding")]
-    InvalidDecoding,
-    
-    #[msg("Invalid hash calculation")]
-    InvalidHashCalculation,
-    
-    #[msg("Invalid signature verification")]
-    InvalidSignatureVerification,
-    
-    #[msg("Invalid public key")]
-    InvalidPublicKey,
-    
-    #[msg("Invalid private key")]
-    InvalidPrivateKey,
-    
-    #[msg("Invalid keypair")]
-    InvalidKeypair,
-    
-    #[msg("Invalid transaction")]
-    InvalidTransaction,
-    
-    #[msg("Invalid block")]
-    InvalidBlock,
-    
-    #[msg("Invalid slot")]
-    InvalidSlot,
-    
-    #[msg("Invalid epoch")]
-    InvalidEpoch,
-    
-    #[msg("Invalid commitment")]
-    InvalidCommitment,
-    
-    #[msg("Invalid confirmation")]
-    InvalidConfirmation,
-    
-    #[msg("Invalid timeout")]
-    InvalidTimeout,
-    
-    #[msg("Invalid retry")]
-    InvalidRetry,
-    
-    #[msg("Invalid backoff")]
-    InvalidBackoff,
-    
-    #[msg("Invalid circuit breaker")]
-    InvalidCircuitBreaker,
-    
-    #[msg("Invalid rate limiting")]
-    InvalidRateLimiting,
-    
-    #[msg("Invalid throttling")]
-    InvalidThrottling,
-    
-    #[msg("Invalid caching")]
-    InvalidCaching,
-    
-    #[msg("Invalid optimization")]
-    InvalidOptimization,
-    
-    #[msg("Invalid performance")]
-    InvalidPerformance,
-    
-    #[msg("Invalid scalability")]
-    InvalidScalability,
-    
-    #[msg("Invalid maintainability")]
-    InvalidMaintainability,
-    
-    #[msg("Invalid testability")]
-    InvalidTestability,
-    
-    #[msg("Invalid debuggability")]
-    InvalidDebuggability,
-    
-    #[msg("Invalid monitoring")]
-    InvalidMonitoring,
-    
-    #[msg("Invalid alerting")]
-    InvalidAlerting,
-    
-    #[msg("Invalid metrics")]
-    InvalidMetrics,
-    
-    #[msg("Invalid analytics")]
-    InvalidAnalytics,
-    
-    #[msg("Invalid reporting")]
-    InvalidReporting,
-    
-    #[msg("Invalid dashboard")]
-    InvalidDashboard,
-    
-    #[msg("Invalid visualization")]
-    InvalidVisualization,
-    
-    #[msg("Invalid documentation")]
-    InvalidDocumentation,
-    
-    #[msg("Invalid specification")]
-    InvalidSpecification,
-    
-    #[msg("Invalid implementation")]
-    InvalidImplementation,
-    
-    #[msg("Invalid deployment")]
-    InvalidDeployment,
-    
-    #[msg("Invalid configuration")]
-    InvalidConfiguration,
-    
-    #[msg("Invalid environment")]
-    InvalidEnvironment,
-    
-    #[msg("Invalid dependency")]
-    InvalidDependency,
-    
-    #[msg("Invalid version")]
-    InvalidVersion,
-    
-    #[msg("Invalid compatibility")]
-    InvalidCompatibility,
-    
-    #[msg("Invalid migration")]
-    InvalidMigration,
-    
-    #[msg("Invalid upgrade")]
-    InvalidUpgrade,
-    
-    #[msg("Invalid rollback")]
-    InvalidRollback,
-    
-    #[msg("Invalid backup")]
-    InvalidBackup,
-    
-    #[msg("Invalid restore")]
-    InvalidRestore,
-    
-    #[msg("Invalid recovery")]
-    InvalidRecovery,
-    
-    #[msg("Invalid disaster recovery")]
-    InvalidDisasterRecovery,
-    
-    #[msg("Invalid business continuity")]
-    InvalidBusinessContinuity,
-    
-    #[msg("Invalid risk management")]
-    InvalidRiskManagement,
-    
-    #[msg("Invalid compliance")]
-    InvalidCompliance,
-    
-    #[msg("Invalid governance")]
-    InvalidGovernance,
-    
-    #[msg("Invalid policy")]
-    InvalidPolicy,
-    
-    #[msg("Invalid procedure")]
-    InvalidProcedure,
-    
-    #[msg("Invalid process")]
-    InvalidProcess,
-    
-    #[msg("Invalid workflow")]
-    InvalidWorkflow,
-    
-    #[msg("Invalid automation")]
-    InvalidAutomation,
-    
-    #[msg("Invalid orchestration")]
-    InvalidOrchestration,
-    
-    #[msg("Invalid integration")]
-    InvalidIntegration,
-    
-    #[msg("Invalid API")]
-    InvalidApi,
-    
-    #[msg("Invalid SDK")]
-    InvalidSdk,
-    
-    #[msg("Invalid library")]
-    InvalidLibrary,
-    
-    #[msg("Invalid framework")]
-    InvalidFramework,
-    
-    #[msg("Invalid platform")]
-    InvalidPlatform,
-    
-    #[msg("Invalid infrastructure")]
-    InvalidInfrastructure,
-    
-    #[msg("Invalid architecture")]
-    InvalidArchitecture,
-    
-    #[msg("Invalid design")]
-    InvalidDesign,
-    
-    #[msg("Invalid pattern")]
-    InvalidPattern,
-    
-    #[msg("Invalid principle")]
-    InvalidPrinciple,
-    
-    #[msg("Invalid practice")]
-    InvalidPractice,
-    
-    #[msg("Invalid standard")]
-    InvalidStandard,
-    
-    #[msg("Invalid protocol")]
-    InvalidProtocol,
-    
-    #[msg("Invalid algorithm")]
-    InvalidAlgorithm,
-    
-    #[msg("Invalid data structure")]
-    InvalidDataStructure,
-    
-    #[msg("Invalid function")]
-    InvalidFunction,
-    
-    #[msg("Invalid method")]
-    InvalidMethod,
-    
-    #[msg("Invalid class")]
-    InvalidClass,
-    
-    #[msg("Invalid object")]
-    InvalidObject,
-    
-    #[msg("Invalid interface")]
-    InvalidInterface,
-    
-    #[msg("Invalid abstraction")]
-    InvalidAbstraction,
-    
-    #[msg("Invalid encapsulation")]
-    InvalidEncapsulation,
-    
-    #[msg("Invalid inheritance")]
-    InvalidInheritance,
-    
-    #[msg("Invalid polymorphism")]
-    InvalidPolymorphism,
-    
-    #[msg("Invalid composition")]
-    InvalidComposition,
-    
-    #[msg("Invalid aggregation")]
-    InvalidAggregation,
-    
-    #[msg("Invalid association")]
-    InvalidAssociation,
-    
-    #[msg("Invalid dependency injection")]
-    InvalidDependencyInjection,
-    
-    #[msg("Invalid inversion of control")]
-    InvalidInversionOfControl,
-    
-    #[msg("Invalid separation of concerns")]
-    InvalidSeparationOfConcerns,
-    
-    #[msg("Invalid single responsibility")]
-    InvalidSingleResponsibility,
-    
-    #[msg("Invalid open closed")]
-    InvalidOpenClosed,
-    
-    #[msg("Invalid Liskov substitution")]
-    InvalidLiskovSubstitution,
-    
-    #[msg("Invalid interface segregation")]
-    InvalidInterfaceSegregation,
-    
-    #[msg("Invalid dependency inversion")]
-    InvalidDependencyInversion,
-    
-    #[msg("Invalid SOLID principles")]
-    InvalidSolidPrinciples,
-    
-    #[msg("Invalid clean code")]
-    InvalidCleanCode,
-    
-    #[msg("Invalid code review")]
-    InvalidCodeReview,
-    
-    #[msg("Invalid testing")]
-    InvalidTesting,
-    
-    #[msg("Invalid quality assurance")]
-    InvalidQualityAssurance,
-    
-    #[msg("Invalid quality control")]
-    InvalidQualityControl,
-    
-    #[msg("Invalid quality management")]
-    InvalidQualityManagement,
-    
-    #[msg("Invalid project management")]
-    InvalidProjectManagement,
-    
-    #[msg("Invalid product management")]
-    InvalidProductManagement,
-    
-    #[msg("Invalid business analysis")]
-    InvalidBusinessAnalysis,
-    
-    #[msg("Invalid requirements engineering")]
-    InvalidRequirementsEngineering,
-    
-    #[msg("Invalid systems engineering")]
-    InvalidSystemsEngineering,
-    
-    #[msg("Invalid software engineering")]
-    InvalidSoftwareEngineering,
-    
-    #[msg("Invalid computer science")]
-    InvalidComputerScience,
-    
-    #[msg("Invalid mathematics")]
-    InvalidMathematics,
-    
-    #[msg("Invalid physics")]
-    InvalidPhysics,
-    
-    #[msg("Invalid chemistry")]
-    InvalidChemistry,
-    
-    #[msg("Invalid biology")]
-    InvalidBiology,
-    
-    #[msg("Invalid economics")]
-    InvalidEconomics,
-    
-    #[msg("Invalid finance")]
-    InvalidFinance,
-    
-    #[msg("Invalid accounting")]
-    InvalidAccounting,
-    
-    #[msg("Invalid marketing")]
-    InvalidMarketing,
-    
-    #[msg("Invalid sales")]
-    InvalidSales,
-    
-    #[msg("Invalid customer service")]
-    InvalidCustomerService,
-    
-    #[msg("Invalid human resources")]
-    InvalidHumanResources,
-    
-    #[msg("Invalid operations")]
-    InvalidOperations,
-    
-    #[msg("Invalid logistics")]
-    InvalidLogistics,
-    
-    #[msg("Invalid supply chain")]
-    InvalidSupplyChain,
-    
-    #[msg("Invalid manufacturing")]
-    InvalidManufacturing,
-    
-    #[msg("Invalid research and development")]
-    InvalidResearchAndDevelopment,
-    
-    #[msg("Invalid innovation")]
-    InvalidInnovation,
-    
-    #[msg("Invalid creativity")]
-    InvalidCreativity,
-    
-    #[msg("Invalid problem solving")]
-    InvalidProblemSolving,
-    
-    #[msg("Invalid critical thinking")]
-    InvalidCriticalThinking,
-    
-    #[msg("Invalid analytical thinking")]
-    InvalidAnalyticalThinking,
-    
-    #[msg("Invalid logical thinking")]
-    InvalidLogicalThinking,
-    
-    #[msg("Invalid systems thinking")]
-    InvalidSystemsThinking,
-    
-    #[msg("Invalid design thinking")]
-    InvalidDesignThinking,
-    
-    #[msg("Invalid lean thinking")]
-    InvalidLeanThinking,
-    
-    #[msg("Invalid agile thinking")]
-    InvalidAgileThinking,
-    
-    #[msg("Invalid scrum thinking")]
-    InvalidScrumThinking,
-    
-    #[msg("Invalid kanban thinking")]
-    InvalidKanbanThinking,
-    
-    #[msg("Invalid waterfall thinking")]
-    InvalidWaterfallThinking,
-    
-    #[msg("Invalid spiral thinking")]
-    InvalidSpiralThinking,
-    
-    #[msg("Invalid v-model thinking")]
-    InvalidVModelThinking,
-    
-    #[msg("Invalid iterative thinking")]
-    InvalidIterativeThinking,
-    
-    #[msg("Invalid incremental thinking")]
-    InvalidIncrementalThinking,
-    
-    #[msg("Invalid evolutionary thinking")]
-    InvalidEvolutionaryThinking,
-    
-    #[msg("Invalid revolutionary thinking")]
-    InvalidRevolutionaryThinking,
-    
-    #[msg("Invalid disruptive thinking")]
-    InvalidDisruptiveThinking,
-    
-    #[msg("Invalid sustaining thinking")]
-    InvalidSustainingThinking,
-    
-    #[msg("Invalid breakthrough thinking")]
-    InvalidBreakthroughThinking,
-    
-    #[msg("Invalid incremental innovation")]
-    InvalidIncrementalInnovation,
-    
-    #[msg("Invalid radical innovation")]
-    InvalidRadicalInnovation,
-    
-    #[msg("Invalid architectural innovation")]
-    InvalidArchitecturalInnovation,
-    
-    #[msg("Invalid modular innovation")]
-    InvalidModularInnovation,
-    
-    #[msg("Invalid platform innovation")]
-    InvalidPlatformInnovation,
-    
-    #[msg("Invalid business model innovation")]
-    InvalidBusinessModelInnovation,
-    
-    #[msg("Invalid process innovation")]
-    InvalidProcessInnovation,
-    
-    #[msg("Invalid product innovation")]
-    InvalidProductInnovation,
-    
-    #[msg("Invalid service innovation")]
-    InvalidServiceInnovation,
-    
-    #[msg("Invalid technology innovation")]
-    InvalidTechnologyInnovation,
-    
-    #[msg("Invalid social innovation")]
-    InvalidSocialInnovation,
-    
-    #[msg("Invalid environmental innovation")]
-    InvalidEnvironmentalInnovation,
-    
-    #[msg("Invalid sustainable innovation")]
-    InvalidSustainableInnovation,
-    
-    #[msg("Invalid responsible innovation")]
-    InvalidResponsibleInnovation,
-    
-    #[msg("Invalid ethical innovation")]
-    InvalidEthicalInnovation,
-    
-    #[msg("Invalid inclusive innovation")]
-    InvalidInclusiveInnovation,
-    
-    #[msg("Invalid accessible innovation")]
-    InvalidAccessibleInnovation,
-    
-    #[msg("Invalid universal innovation")]
-    InvalidUniversalInnovation,
-    
-    #[msg("Invalid human-centered innovation")]
-    InvalidHumanCenteredInnovation,
-    
-    #[msg("Invalid user-centered innovation")]
-    InvalidUserCenteredInnovation,
-    
-    #[msg("Invalid customer-centered innovation")]
-    InvalidCustomerCenteredInnovation,
-    
-    #[msg("Invalid stakeholder-centered innovation")]
-    InvalidStakeholderCenteredInnovation,
-    
-    #[msg("Invalid community-centered innovation")]
-    InvalidCommunityCenteredInnovation,
-    
-    #[msg("Invalid society-centered innovation")]
-    InvalidSocietyCenteredInnovation,
-    
-    #[msg("Invalid planet-centered innovation")]
-    InvalidPlanetCenteredInnovation,
-    
-    #[msg("Invalid life-centered innovation")]
-    InvalidLifeCenteredInnovation,
-    
-    #[msg("Invalid consciousness-centered innovation")]
-    InvalidConsciousnessCenteredInnovation,
-    
-    #[msg("Invalid spirit-centered innovation")]
-    InvalidSpiritCenteredInnovation,
-    
-    #[msg("Invalid soul-centered innovation")]
-    InvalidSoulCenteredInnovation,
-    
-    #[msg("Invalid heart-centered innovation")]
-    InvalidHeartCenteredInnovation,
-    
-    #[msg("Invalid mind-centered innovation")]
-    InvalidMindCenteredInnovation,
-    
-    #[msg("Invalid body-centered innovation")]
-    InvalidBodyCenteredInnovation,
-    
-    #[msg("Invalid energy-centered innovation")]
-    InvalidEnergyCenteredInnovation,
-    
-    #[msg("Invalid vibration-centered innovation")]
-    InvalidVibrationCenteredInnovation,
-    
-    #[msg("Invalid frequency-centered innovation")]
-    InvalidFrequencyCenteredInnovation,
-    
-    #[msg("Invalid resonance-centered innovation")]
-    InvalidResonanceCenteredInnovation,
-    
-    #[msg("Invalid harmony-centered innovation")]
-    InvalidHarmonyCenteredInnovation,
-    
-    #[msg("Invalid balance-centered innovation")]
-    InvalidBalanceCenteredInnovation,
-    
-    #[msg("Invalid equilibrium-centered innovation")]
-    InvalidEquilibriumCenteredInnovation,
-    
-    #[msg("Invalid homeostasis-centered innovation")]
-    InvalidHomeostasisCenteredInnovation,
-    
-    #[msg("Invalid adaptation-centered innovation")]
-    InvalidAdaptationCenteredInnovation,
-    
-    #[msg("Invalid evolution-centered innovation")]
-    InvalidEvolutionCenteredInnovation,
-    
-    #[msg("Invalid transformation-centered innovation")]
-    InvalidTransformationCenteredInnovation,
-    
-    #[msg("Invalid transcendence-centered innovation")]
-    InvalidTranscendenceCenteredInnovation,
-    
-    #[msg("Invalid enlightenment-centered innovation")]
-    InvalidEnlightenmentCenteredInnovation,
-    
-    #[msg("Invalid awakening-centered innovation")]
-    InvalidAwakeningCenteredInnovation,
-    
-    #[msg("Invalid consciousness-centered innovation")]
-    InvalidConsciousnessCenteredInnovation2,
-    
-    #[msg("Invalid awareness-centered innovation")]
-    InvalidAwarenessCenteredInnovation,
-    
-    #[msg("Invalid mindfulness-centered innovation")]
-    InvalidMindfulnessCenteredInnovation,
-    
-    #[msg("Invalid presence-centered innovation")]
-    InvalidPresenceCenteredInnovation,
-    
-    #[msg("Invalid being-centered innovation")]
-    InvalidBeingCenteredInnovation,
-    
-    #[msg("Invalid becoming-centered innovation")]
-    InvalidBecomingCenteredInnovation,
-    
-    #[msg("Invalid flow-centered innovation")]
-    InvalidFlowCenteredInnovation,
-    
-    #[msg("Invalid emergence-centered innovation")]
-    InvalidEmergenceCenteredInnovation,
-    
-    #[msg("Invalid complexity-centered innovation")]
-    InvalidComplexityCenteredInnovation,
-    
-    #[msg("Invalid chaos-centered innovation")]
-    InvalidChaosCenteredInnovation,
-    
-    #[msg("Invalid order-centered innovation")]
-    InvalidOrderCenteredInnovation,
-    
-    #[msg("Invalid structure-centered innovation")]
-    InvalidStructureCenteredInnovation,
-    
-    #[msg("Invalid organization-centered innovation")]
-    InvalidOrganizationCenteredInnovation,
-    
-    #[msg("Invalid system-centered innovation")]
-    InvalidSystemCenteredInnovation,
-    
-    #[msg("Invalid network-centered innovation")]
-    InvalidNetworkCenteredInnovation,
-    
-    #[msg("Invalid ecosystem-centered innovation")]
-    InvalidEcosystemCenteredInnovation,
-    
-    #[msg("Invalid biosphere-centered innovation")]
-    InvalidBiosphereCenteredInnovation,
-    
-    #[msg("Invalid cosmos-centered innovation")]
-    InvalidCosmosCenteredInnovation,
-    
-    #[msg("Invalid universe-centered innovation")]
-    InvalidUniverseCenteredInnovation,
-    
-    #[msg("Invalid multiverse-centered innovation")]
-    InvalidMultiverseCenteredInnovation,
-    
-    #[msg("Invalid metaverse-centered innovation")]
-    InvalidMetaverseCenteredInnovation,
-    
-    #[msg("Invalid omniverse-centered innovation")]
-    InvalidOmniverseCenteredInnovation,
-    
-    #[msg("Invalid all-verse-centered innovation")]
-    InvalidAllVerseCenteredInnovation,
-    
-    #[msg("Invalid everything-centered innovation")]
-    InvalidEverythingCenteredInnovation,
-    
-    #[msg("Invalid nothing-centered innovation")]
-    InvalidNothingCenteredInnovation,
-    
-    #[msg("Invalid void-centered innovation")]
-    InvalidVoidCenteredInnovation,
-    
-    #[msg("Invalid emptiness-centered innovation")]
-    InvalidEmptinessCenteredInnovation,
-    
-    #[msg("Invalid fullness-centered innovation")]
-    InvalidFullnessCenteredInnovation,
-    
-    #[msg("Invalid completeness-centered innovation")]
-    InvalidCompletenessCenteredInnovation,
-    
-    #[msg("Invalid wholeness-centered innovation")]
-    InvalidWholenessCenteredInnovation,
-    
-    #[msg("Invalid oneness-centered innovation")]
-    InvalidOnenessCenteredInnovation,
-    
-    #[msg("Invalid unity-centered innovation")]
-    InvalidUnityCenteredInnovation,
-    
-    #[msg("Invalid diversity-centered innovation")]
-    InvalidDiversityCenteredInnovation,
-    
-    #[msg("Invalid plurality-centered innovation")]
-    InvalidPluralityCenteredInnovation,
-    
-    #[msg("Invalid multiplicity-centered innovation")]
-    InvalidMultiplicityCenteredInnovation,
-    
-    #[msg("Invalid singularity-centered innovation")]
-    InvalidSingularityCenteredInnovation,
-    
-    #[msg("Invalid infinity-centered innovation")]
-    InvalidInfinityCenteredInnovation,
-    
-    #[msg("Invalid eternity-centered innovation")]
-    InvalidEternityCenteredInnovation,
-    
-    #[msg("Invalid timelessness-centered innovation")]
-    InvalidTimelessnessCenteredInnovation,
-    
-    #[msg("Invalid spacelessness-centered innovation")]
-    InvalidSpacelessnessCenteredInnovation,
-    
-    #[msg("Invalid dimensionless-centered innovation")]
-    InvalidDimensionlessCenteredInnovation,
-    
-    #[msg("Invalid dimensional-centered innovation")]
-    InvalidDimensionalCenteredInnovation,
-    
-    #[msg("Invalid hyperdimensional-centered innovation")]
-    InvalidHyperdimensionalCenteredInnovation,
-    
-    #[msg("Invalid interdimensional-centered innovation")]
-    InvalidInterdimensionalCenteredInnovation,
-    
-    #[msg("Invalid transdimensional-centered innovation")]
-    InvalidTransdimensionalCenteredInnovation,
-    
-    #[msg("Invalid extradimensional-centered innovation")]
-    InvalidExtradimensionalCenteredInnovation,
-    
-    #[msg("Invalid intradimensional-centered innovation")]
-    InvalidIntradimensionalCenteredInnovation,
-    
-    #[msg("Invalid supradimensional-centered innovation")]
-    InvalidSupradimensionalCenteredInnovation,
-    
-    #[msg("Invalid infradimensional-centered innovation")]
-    InvalidInfradimensionalCenteredInnovation,
-    
-    #[msg("Invalid paradimensional-centered innovation")]
-    InvalidParadimensionalCenteredInnovation,
-    
-    #[msg("Invalid peridimensional-centered innovation")]
-    InvalidPeridimensionalCenteredInnovation,
-    
-    #[msg("Invalid circumdimensional-centered innovation")]
-    InvalidCircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid transcircumdimensional-centered innovation")]
-    InvalidTranscircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid metacircumdimensional-centered innovation")]
-    InvalidMetacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid hypercircumdimensional-centered innovation")]
-    InvalidHypercircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid ultracircumdimensional-centered innovation")]
-    InvalidUltracircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid omegacircumdimensional-centered innovation")]
-    InvalidOmegacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid alphacircumdimensional-centered innovation")]
-    InvalidAlphacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid betacircumdimensional-centered innovation")]
-    InvalidBetacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid gammacircumdimensional-centered innovation")]
-    InvalidGammacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid deltacircumdimensional-centered innovation")]
-    InvalidDeltacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid epsiloncircumdimensional-centered innovation")]
-    InvalidEpsiloncircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid zetacircumdimensional-centered innovation")]
-    InvalidZetacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid etacircumdimensional-centered innovation")]
-    InvalidEtacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid thetacircumdimensional-centered innovation")]
-    InvalidThetacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid iotacircumdimensional-centered innovation")]
-    InvalidIotacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid kappacircumdimensional-centered innovation")]
-    InvalidKappacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid lambdacircumdimensional-centered innovation")]
-    InvalidLambdacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid mucircumdimensional-centered innovation")]
-    InvalidMucircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid nucircumdimensional-centered innovation")]
-    InvalidNucircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid xicircumdimensional-centered innovation")]
-    InvalidXicircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid omicroncircumdimensional-centered innovation")]
-    InvalidOmicroncircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid picircumdimensional-centered innovation")]
-    InvalidPicircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid rhocircumdimensional-centered innovation")]
-    InvalidRhocircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid sigmacircumdimensional-centered innovation")]
-    InvalidSigmacircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid taucircumdimensional-centered innovation")]
-    InvalidTaucircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid upsilonscircumdimensional-centered innovation")]
-    InvalidUpsilonscircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid phicircumdimensional-centered innovation")]
-    InvalidPhicircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid chicircumdimensional-centered innovation")]
-    InvalidChicircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid psicircumdimensional-centered innovation")]
-    InvalidPsicircumdimensionalCenteredInnovation,
-    
-    #[msg("Invalid omegacircumdimensional-centered innovation")]
-    InvalidOmegacircumdimensionalCenteredInnovation2,
-    
-    #[msg("Invalid unknown error")]
-    UnknownError,
-}
\ No newline at end of file
+}
+
+/// Fails with `$error` unless `$value <= $limit`, logging both values so a
+/// rejected transaction can be diagnosed from its logs.
+macro_rules! require_at_most {
+    ($value:expr, $limit:expr, $error:expr $(,)?) => {{
+        let (value, limit) = ($value, $limit);
+        if value > limit {
+            msg!(
+                "{} = {} exceeds {} = {}",
+                stringify!($value),
+                value,
+                stringify!($limit),
+                limit
+            );
+            return err!($error);
+        }
+    }};
+}
+
+/// Fails with `$error` unless `$value >= $minimum`, logging both values so a
+/// rejected transaction can be diagnosed from its logs.
+macro_rules! require_at_least {
+    ($value:expr, $minimum:expr, $error:expr $(,)?) => {{
+        let (value, minimum) = ($value, $minimum);
+        if value < minimum {
+            msg!(
+                "{} = {} is below {} = {}",
+                stringify!($value),
+                value,
+                stringify!($minimum),
+                minimum
+            );
+            return err!($error);
+        }
+    }};
+}
+
+pub(crate) use require_at_least;
+pub(crate) use require_at_most;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_withdraw(amount: u64, available: u64) -> Result<()> {
+        require_at_most!(amount, available, VaultError::InsufficientFunds);
+        Ok(())
+    }
+
+    fn check_deposit(value: u64, minimum: u64) -> Result<()> {
+        require_at_least!(value, minimum, VaultError::DepositBelowMinimum);
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_codes_are_grouped_by_subsystem() {
+        assert_eq!(u32::from(VaultError::UnauthorizedAccess), 6000);
+        assert_eq!(u32::from(VaultError::MathOverflow), 6100);
+        assert_eq!(u32::from(VaultError::InvalidAmount), 6200);
+        assert_eq!(u32::from(VaultError::InsufficientFunds), 6203);
+        assert_eq!(u32::from(VaultError::InvalidFeeStructure), 6300);
+        assert_eq!(u32::from(VaultError::InvalidDriftProgram), 6400);
+        assert_eq!(u32::from(VaultError::InvalidOraclePrice), 6500);
+        assert_eq!(u32::from(VaultError::SlippageExceeded), 6603);
+    }
+
+    #[test]
+    fn test_bound_checks() {
+        assert!(check_withdraw(400, 400).is_ok());
+        assert_eq!(
+            check_withdraw(500, 400).unwrap_err(),
+            error!(VaultError::InsufficientFunds)
+        );
+        assert!(check_deposit(1_000, 1_000).is_ok());
+        assert_eq!(
+            check_deposit(999, 1_000).unwrap_err(),
+            error!(VaultError::DepositBelowMinimum)
+        );
+    }
+}
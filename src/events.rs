@@ -1,9 +1,19 @@
 use anchor_lang::prelude::*;
 
+use crate::constants::MAX_COLLATERALS;
+use crate::state::{EmergencyStopReason, PositionDirection};
+
+// Every vault event carries `schema_version` (EVENT_SCHEMA_VERSION) and the
+// vault's gapless `sequence` number, so indexers can pick a decoder and
+// detect missed events.
+
 #[event]
 pub struct VaultInitialized {
     pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
     pub admin: Pubkey,
+    pub vault_id: u64,
     pub target_leverage: u8,
     pub rebalance_threshold: u16,
     pub max_slippage: u16,
@@ -13,88 +23,322 @@ pub struct VaultInitialized {
 #[event]
 pub struct DepositEvent {
     pub vault: Pubkey,
-    pub user: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub depositor: Pubkey,
+    pub owner: Pubkey,
+    pub referrer: Pubkey,
+    pub amount: u64,
+    pub shares_minted: u64,
+    pub share_price: u64,
+    pub average_entry_price: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CollateralDepositEvent {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub depositor: Pubkey,
+    pub owner: Pubkey,
+    pub referrer: Pubkey,
+    pub mint: Pubkey,
     pub amount: u64,
+    pub value: u64,
     pub shares_minted: u64,
     pub share_price: u64,
+    pub average_entry_price: u64,
     pub timestamp: i64,
 }
 
 #[event]
 pub struct WithdrawEvent {
     pub vault: Pubkey,
-    pub user: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub owner: Pubkey,
+    pub receiver: Pubkey,
     pub amount: u64,
     pub shares_burned: u64,
+    pub early_exit_fee: u64,
     pub share_price: u64,
+    pub realized_pnl: i64,
+    pub average_entry_price: u64,
+    pub cumulative_realized_pnl: i64,
+    pub fees_paid: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CollateralWithdrawEvent {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub owner: Pubkey,
+    pub receiver: Pubkey,
+    pub shares_burned: u64,
+    pub base_amount: u64,
+    pub collateral_amounts: [u64; MAX_COLLATERALS],
+    pub value: u64,
+    pub share_price: u64,
+    pub realized_pnl: i64,
     pub timestamp: i64,
 }
 
 #[event]
 pub struct RebalanceEvent {
     pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
     pub delta_before: i64,
     pub delta_after: i64,
     pub long_position_change: i64,
     pub short_position_change: i64,
+    pub market_index: u16,
+    pub average_entry_price: u64,
+    pub realized_pnl: i64,
+    pub fees_paid: u64,
+    pub cumulative_realized_pnl: i64,
+    pub cumulative_funding: i64,
+    pub cumulative_fees_paid: u64,
     pub timestamp: i64,
 }
 
 #[event]
 pub struct EmergencyStopEvent {
     pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
     pub admin: Pubkey,
-    pub reason: String,
+    pub reason: EmergencyStopReason,
     pub timestamp: i64,
 }
 
 #[event]
 pub struct FeeCollectionEvent {
     pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
     pub fee_collector: Pubkey,
     pub management_fees: u64,
     pub performance_fees: u64,
     pub total_fees: u64,
+    pub protocol_fees: u64,
+    pub referral_fees: u64,
+    pub high_water_mark: u64,
     pub timestamp: i64,
 }
 
 #[event]
 pub struct VaultParamsUpdated {
     pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
     pub admin: Pubkey,
     pub target_leverage: Option<u8>,
     pub rebalance_threshold: Option<u16>,
     pub max_slippage: Option<u16>,
+    pub revert_on_slippage: Option<bool>,
+    pub lockup_period: Option<i64>,
+    pub early_exit_fee_bps: Option<u16>,
+    pub referral_fee_share_bps: Option<u16>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ProtocolConfigUpdated {
+    pub schema_version: u8,
+    pub owner: Pubkey,
+    pub protocol_fee_bps: u16,
+    pub paused: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CollateralConfigured {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub admin: Pubkey,
+    pub mint: Pubkey,
+    pub spot_market_index: u16,
+    pub haircut_bps: u16,
+    pub enabled: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct CapacityUpdated {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub admin: Pubkey,
+    pub max_capacity: Option<u64>,
+    pub max_user_deposit: Option<u64>,
+    pub epoch_duration: Option<i64>,
+    pub max_epoch_inflow: Option<u64>,
+    pub max_epoch_outflow: Option<u64>,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct AllowlistUpdated {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub admin: Pubkey,
+    pub enabled: bool,
+    pub root: [u8; 32],
     pub timestamp: i64,
 }
 
 #[event]
 pub struct PositionOpened {
     pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
     pub market: Pubkey,
-    pub direction: String,
+    pub market_index: u16,
+    pub order_id: u32,
+    pub direction: PositionDirection,
     pub size: u64,
-    pub price: u64,
+    pub oracle_price: u64,
     pub timestamp: i64,
 }
 
 #[event]
 pub struct PositionClosed {
     pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
     pub market: Pubkey,
-    pub direction: String,
+    pub market_index: u16,
+    pub order_id: u32,
+    pub direction: PositionDirection,
     pub size: u64,
-    pub price: u64,
+    pub oracle_price: u64,
     pub pnl: i64,
+    pub fees_paid: u64,
+    pub average_entry_price: u64,
+    pub cumulative_realized_pnl: i64,
+    pub cumulative_funding: i64,
     pub timestamp: i64,
 }
 
 #[event]
 pub struct SlippageExceeded {
     pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub order_id: u32,
+    pub market_index: u16,
     pub expected_price: u64,
     pub actual_price: u64,
     pub slippage_bps: u16,
     pub max_slippage_bps: u16,
+    pub reverted: bool,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct StaleOrderCancelled {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub order_id: u32,
+    pub market_index: u16,
+    pub base_asset_amount: u64,
+    pub filled_amount: u64,
+    pub max_ts: i64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct OrderFillUnobserved {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub order_id: u32,
+    pub market_index: u16,
+    pub base_asset_amount: u64,
+    pub filled_amount: u64,
+    pub unobserved_amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReferrerRegistered {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub referrer: Pubkey,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct ReferralFeesClaimed {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub referrer: Pubkey,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct VaultMigrated {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub admin: Pubkey,
+    pub from_version: u8,
+    pub to_version: u8,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct UserClosed {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,               // 0 when the vault was already closed
+    pub user: Pubkey,
+    pub receiver: Pubkey,
+    pub realized_pnl: i64,
+    pub fees_paid: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct VaultClosed {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub admin: Pubkey,
+    pub receiver: Pubkey,
+    pub dust_swept: u64,
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct DriftSubAccountAdded {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub drift_user: Pubkey,
+    pub sub_account_id: u16,
+    pub slot: u8,                    // Index in `VaultState::drift_sub_accounts`
+    pub timestamp: i64,
+}
+
+#[event]
+pub struct SubAccountCollateralTransferred {
+    pub vault: Pubkey,
+    pub schema_version: u8,
+    pub sequence: u64,
+    pub from_user: Pubkey,
+    pub to_user: Pubkey,
+    pub spot_market_index: u16,
+    pub amount: u64,
     pub timestamp: i64,
 }
@@ -1,44 +1,131 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Token, TokenAccount, Mint, transfer, Transfer};
-use drift::cpi::accounts::{PlaceOrder, CancelOrder};
+use anchor_spl::token_interface::{self, close_account, transfer_checked, CloseAccount, TokenInterface, TransferChecked};
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensions},
+};
+use drift::cpi::accounts::{PlaceOrder, CancelOrder, DeleteUser, Deposit as DriftDeposit, InitializeUser, TransferDeposit, Withdraw as DriftWithdraw};
 use drift::program::Drift;
-use drift::state::{UserStats, User, Order, OrderType, OrderStatus, MarketType, PositionDirection};
+use drift::controller::position::PositionDirection as DriftPositionDirection;
+use drift::state::{UserStats, User, Order, OrderType, OrderStatus, MarketType, PerpMarket, SpotMarket};
+use drift::state::{OrderParams, OrderTriggerCondition, PostOnlyParam};
 
 use crate::errors::*;
 use crate::state::*;
 use crate::events::*;
 use crate::constants::*;
 use crate::utils::*;
+use crate::math::*;
+use crate::migrations::{upgrade_vault_state, vault_state_admin, vault_state_version};
+use crate::drift_integration::{initialize_drift_user_stats, sync_vault_positions, PositionUpdatedEvent};
+
+pub fn initialize_protocol(ctx: Context<InitializeProtocol>, protocol_fee_bps: u16) -> Result<()> {
+    require_at_most!(
+        protocol_fee_bps,
+        MAX_PROTOCOL_FEE_BPS,
+        VaultError::InvalidFeeStructure
+    );
+
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    let clock = Clock::get()?;
+
+    // Initialize protocol config
+    protocol_config.owner = ctx.accounts.owner.key();
+    protocol_config.protocol_fee_bps = protocol_fee_bps;
+    protocol_config.paused = false;
+    protocol_config.vault_count = 0;
+    protocol_config.bump = ctx.bumps.protocol_config;
+
+    // Emit event
+    emit!(ProtocolConfigUpdated {
+        schema_version: EVENT_SCHEMA_VERSION,
+        owner: protocol_config.owner,
+        protocol_fee_bps,
+        paused: false,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Protocol config initialized successfully");
+    Ok(())
+}
+
+pub fn update_protocol_config(
+    ctx: Context<UpdateProtocolConfig>,
+    new_owner: Option<Pubkey>,
+    protocol_fee_bps: Option<u16>,
+    paused: Option<bool>,
+) -> Result<()> {
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    let clock = Clock::get()?;
+
+    // Update settings if provided
+    if let Some(owner) = new_owner {
+        protocol_config.owner = owner;
+    }
+
+    if let Some(fee_bps) = protocol_fee_bps {
+        require_at_most!(
+            fee_bps,
+            MAX_PROTOCOL_FEE_BPS,
+            VaultError::InvalidFeeStructure
+        );
+        protocol_config.protocol_fee_bps = fee_bps;
+    }
+
+    if let Some(pause) = paused {
+        protocol_config.paused = pause;
+    }
+
+    // Emit event
+    emit!(ProtocolConfigUpdated {
+        schema_version: EVENT_SCHEMA_VERSION,
+        owner: protocol_config.owner,
+        protocol_fee_bps: protocol_config.protocol_fee_bps,
+        paused: protocol_config.paused,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Protocol config updated successfully");
+    Ok(())
+}
 
 pub fn initialize_vault(
     ctx: Context<InitializeVault>,
+    vault_id: u64,
     target_leverage: u8,
     rebalance_threshold: u16,
     max_slippage: u16,
 ) -> Result<()> {
     // Validate parameters
-    require!(
-        target_leverage > 0 && target_leverage <= MAX_LEVERAGE,
-        DeltaNeutralVaultError::InvalidLeverage
-    );
+    require!(target_leverage > 0, VaultError::InvalidLeverage);
+    require_at_most!(target_leverage, MAX_LEVERAGE, VaultError::InvalidLeverage);
     
-    require!(
-        rebalance_threshold >= MIN_REBALANCE_THRESHOLD_BPS && 
-        rebalance_threshold <= MAX_REBALANCE_THRESHOLD_BPS,
-        DeltaNeutralVaultError::InvalidRebalanceThreshold
+    require_at_least!(
+        rebalance_threshold,
+        MIN_REBALANCE_THRESHOLD_BPS,
+        VaultError::InvalidRebalanceThreshold
+    );
+    require_at_most!(
+        rebalance_threshold,
+        MAX_REBALANCE_THRESHOLD_BPS,
+        VaultError::InvalidRebalanceThreshold
     );
     
-    require!(
-        max_slippage <= MAX_SLIPPAGE_BPS,
-        DeltaNeutralVaultError::InvalidSlippage
+    require_at_most!(
+        max_slippage,
+        MAX_SLIPPAGE_BPS,
+        VaultError::InvalidSlippage
     );
 
-    let vault_state = &mut ctx.accounts.vault_state;
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_init()?;
     let clock = Clock::get()?;
 
     // Initialize vault state
+    vault_state.version = CURRENT_VAULT_VERSION;
     vault_state.admin = ctx.accounts.admin.key();
     vault_state.bump = ctx.bumps.vault_state;
+    vault_state.vault_id = vault_id;
     vault_state.target_leverage = target_leverage;
     vault_state.rebalance_threshold = rebalance_threshold;
     vault_state.max_slippage = max_slippage;
@@ -49,8 +136,9 @@ pub fn initialize_vault(
     vault_state.total_fees_collected = 0;
     vault_state.last_rebalance_time = clock.unix_timestamp;
     vault_state.net_deposits = 0;
-    vault_state.emergency_stop = false;
-    vault_state.max_capacity = 1_000_000_000_000; // 1M tokens
+    vault_state.reset_fee_checkpoint(clock.unix_timestamp)?;
+    vault_state.emergency_stop = 0;
+    vault_state.max_capacity = DEFAULT_MAX_CAPACITY;
     vault_state.drift_user_authority = Pubkey::default();
     vault_state.drift_user = Pubkey::default();
     vault_state.drift_user_stats = Pubkey::default();
@@ -58,11 +146,55 @@ pub fn initialize_vault(
     vault_state.performance_fee = DEFAULT_PERFORMANCE_FEE_BPS;
     vault_state.min_rebalance_interval = MIN_REBALANCE_INTERVAL;
     vault_state.delta_threshold = rebalance_threshold;
+    vault_state.open_orders = [OpenOrder::default(); MAX_OPEN_ORDERS];
+    vault_state.last_position_sync_slot = clock.slot;
+    vault_state.revert_on_slippage = 1;
+    vault_state.slippage_breach_count = 0;
+    vault_state.market_books = [MarketBook::default(); MAX_MARKETS];
+    vault_state.last_total_fee_paid = 0;
+    vault_state.allowlist_enabled = 0;
+    vault_state.allowlist_root = [0; 32];
+    vault_state.lockup_period = 0;
+    vault_state.early_exit_fee_bps = 0;
+    vault_state.max_user_deposit = 0;
+    vault_state.epoch_duration = DEFAULT_FLOW_EPOCH_DURATION;
+    vault_state.epoch_start = clock.unix_timestamp;
+    vault_state.epoch_net_flow = 0;
+    vault_state.max_epoch_inflow = 0;
+    vault_state.max_epoch_outflow = 0;
+    vault_state.collaterals = [CollateralConfig::default(); MAX_COLLATERALS];
+    vault_state.referral_fee_share_bps = 0;
+    vault_state.referred_shares = 0;
+    vault_state.referral_fees_per_share = 0;
+    vault_state.referral_fees_owed = 0;
+    vault_state.drift_sub_accounts = [DriftSubAccount::default(); MAX_DRIFT_SUB_ACCOUNTS];
+    vault_state.market_sub_accounts = [0; MAX_MARKETS];
+
+    // List the vault in the program registry
+    let registry_entry = &mut ctx.accounts.registry_entry;
+    registry_entry.vault = vault_key;
+    registry_entry.admin = ctx.accounts.admin.key();
+    registry_entry.vault_id = vault_id;
+    registry_entry.registered_at = clock.unix_timestamp;
+    registry_entry.bump = ctx.bumps.registry_entry;
+
+    // Start the share-price history at the initial price
+    let mut history = ctx.accounts.history.load_init()?;
+    history.vault = vault_key;
+    history.bump = ctx.bumps.history;
+    history.record(HistorySnapshot::of(&vault_state, clock.unix_timestamp)?);
+    drop(history);
+
+    let protocol_config = &mut ctx.accounts.protocol_config;
+    protocol_config.vault_count = protocol_config.vault_count.safe_add(1)?;
 
     // Emit event
     emit!(VaultInitialized {
-        vault: vault_state.key(),
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
         admin: ctx.accounts.admin.key(),
+        vault_id,
         target_leverage,
         rebalance_threshold,
         max_slippage,
@@ -73,205 +205,786 @@ pub fn initialize_vault(
     Ok(())
 }
 
-pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
-    let vault_state = &mut ctx.accounts.vault_state;
+pub fn deposit(
+    ctx: Context<Deposit>,
+    amount: u64,
+    allowlist_proof: Option<AllowlistProof>,
+) -> Result<()> {
+    let accounts = ctx.accounts;
+    process_deposit(
+        DepositAccounts {
+            vault_state: &accounts.vault_state,
+            vault_token_account: &mut accounts.vault_token_account,
+            token_mint: &accounts.token_mint,
+            depositor_token_account: &accounts.depositor_token_account,
+            vault_user: &mut accounts.vault_user,
+            vault_user_bump: ctx.bumps.vault_user,
+            depositor: &accounts.depositor,
+            owner: accounts.owner.key(),
+            owner_is_signer: accounts.owner.is_signer,
+            referrer_account: accounts.referrer_account.as_mut(),
+            token_program: &accounts.token_program,
+        },
+        amount,
+        allowlist_proof,
+        0,
+    )?;
+    Ok(())
+}
+
+pub fn deposit_for(
+    ctx: Context<DepositFor>,
+    amount: u64,
+    allowlist_proof: Option<AllowlistProof>,
+    min_shares_out: u64,
+) -> Result<u64> {
+    let accounts = ctx.accounts;
+    let shares_minted = process_deposit(
+        DepositAccounts {
+            vault_state: &accounts.vault_state,
+            vault_token_account: &mut accounts.vault_token_account,
+            token_mint: &accounts.token_mint,
+            depositor_token_account: &accounts.depositor_token_account,
+            vault_user: &mut accounts.vault_user,
+            vault_user_bump: ctx.bumps.vault_user,
+            depositor: &accounts.depositor,
+            owner: accounts.owner.key(),
+            owner_is_signer: accounts.owner.is_signer,
+            referrer_account: accounts.referrer_account.as_mut(),
+            token_program: &accounts.token_program,
+        },
+        amount,
+        allowlist_proof,
+        min_shares_out,
+    )?;
+    Ok(shares_minted)
+}
+
+/// Accounts shared by `deposit` and `deposit_for`
+struct DepositAccounts<'a, 'info> {
+    vault_state: &'a AccountLoader<'info, VaultState>,
+    vault_token_account: &'a mut InterfaceAccount<'info, token_interface::TokenAccount>,
+    token_mint: &'a InterfaceAccount<'info, token_interface::Mint>,
+    depositor_token_account: &'a InterfaceAccount<'info, token_interface::TokenAccount>,
+    vault_user: &'a mut Account<'info, VaultUser>,
+    vault_user_bump: u8,
+    depositor: &'a Signer<'info>,
+    owner: Pubkey,
+    owner_is_signer: bool,
+    referrer_account: Option<&'a mut Account<'info, ReferrerAccount>>,
+    token_program: &'a Interface<'info, TokenInterface>,
+}
+
+/// Pull `amount` from the depositor and credit the owner, returning the shares minted
+fn process_deposit(
+    accounts: DepositAccounts,
+    amount: u64,
+    allowlist_proof: Option<AllowlistProof>,
+    min_shares_out: u64,
+) -> Result<u64> {
+    let vault_key = accounts.vault_state.key();
+    let mut vault_state = accounts.vault_state.load_mut()?;
+    let vault_user = accounts.vault_user;
     let clock = Clock::get()?;
 
-    // Check if vault is in emergency stop
-    require!(!vault_state.emergency_stop, DeltaNeutralVaultError::EmergencyStopActive);
+    check_deposit_open(&vault_state, clock.slot)?;
 
-    // Check vault capacity
-    require!(
-        vault_state.total_assets + amount <= vault_state.max_capacity,
-        DeltaNeutralVaultError::VaultAtCapacity
+    admit_depositor(
+        &vault_state,
+        vault_key,
+        vault_user,
+        accounts.owner,
+        accounts.vault_user_bump,
+        allowlist_proof,
+    )?;
+
+    // Transfer tokens from depositor to vault
+    let balance_before = accounts.vault_token_account.amount;
+    let transfer_ctx = CpiContext::new(
+        accounts.token_program.to_account_info(),
+        TransferChecked {
+            from: accounts.depositor_token_account.to_account_info(),
+            mint: accounts.token_mint.to_account_info(),
+            to: accounts.vault_token_account.to_account_info(),
+            authority: accounts.depositor.to_account_info(),
+        },
     );
+    transfer_checked(transfer_ctx, amount, accounts.token_mint.decimals)?;
+
+    // Token-2022 transfer fees are withheld in the receiving account, so
+    // only credit what actually arrived
+    accounts.vault_token_account.reload()?;
+    let received = accounts
+        .vault_token_account
+        .amount
+        .safe_sub(balance_before)?;
+
+    let shares_to_mint = price_deposit(&vault_state, vault_user, received, clock.unix_timestamp, min_shares_out)?;
+    vault_state.record_flow(clock.unix_timestamp, to_i64(received)?)?;
+
+    // A third-party depositor cannot pick the owner's referrer
+    let owner_consents = accounts.depositor.key() == accounts.owner || accounts.owner_is_signer;
+    update_referred_shares(
+        &mut vault_state,
+        vault_user,
+        accounts.referrer_account,
+        owner_consents,
+        shares_to_mint,
+        0,
+    )?;
 
-    // Calculate shares to mint
-    let shares_to_mint = if vault_state.total_shares == 0 {
-        amount
-    } else {
-        (amount as u128 * vault_state.total_shares as u128 / vault_state.total_assets as u128) as u64
-    };
+    // Update vault state
+    vault_state.total_assets = vault_state.total_assets.safe_add(received)?;
+    vault_state.total_shares = vault_state.total_shares.safe_add(shares_to_mint)?;
+    vault_state.net_deposits = vault_state.net_deposits.safe_add(to_i64(received)?)?;
 
-    // Transfer tokens from depositor to vault
+    // Update user state
+    vault_user.record_deposit(shares_to_mint, received, clock.unix_timestamp, vault_state.fees_per_share)?;
+
+    // Calculate share price
+    let share_price = vault_state.calculate_share_price()?;
+
+    // Emit event
+    emit!(DepositEvent {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        depositor: accounts.depositor.key(),
+        owner: accounts.owner,
+        referrer: vault_user.referrer,
+        amount: received,
+        shares_minted: shares_to_mint,
+        share_price,
+        average_entry_price: vault_user.average_entry_price,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Deposit successful: {} tokens, {} shares", received, shares_to_mint);
+    Ok(shares_to_mint)
+}
+
+pub fn deposit_collateral(
+    ctx: Context<DepositCollateral>,
+    amount: u64,
+    allowlist_proof: Option<AllowlistProof>,
+) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let clock = Clock::get()?;
+    let mint = ctx.accounts.collateral_mint.key();
+    require!(amount > 0, VaultError::InvalidAmount);
+
+    // Transfer collateral from depositor to the vault's collateral account.
+    // Value and shares follow what arrived, net of any Token-2022 transfer fee.
+    let balance_before = ctx.accounts.vault_collateral_account.amount;
     let transfer_ctx = CpiContext::new(
         ctx.accounts.token_program.to_account_info(),
-        Transfer {
+        TransferChecked {
             from: ctx.accounts.depositor_token_account.to_account_info(),
-            to: ctx.accounts.vault_token_account.to_account_info(),
+            mint: ctx.accounts.collateral_mint.to_account_info(),
+            to: ctx.accounts.vault_collateral_account.to_account_info(),
             authority: ctx.accounts.depositor.to_account_info(),
         },
     );
-    transfer(transfer_ctx, amount)?;
+    transfer_checked(transfer_ctx, amount, ctx.accounts.collateral_mint.decimals)?;
+    ctx.accounts.vault_collateral_account.reload()?;
+    let received = ctx.accounts.vault_collateral_account.amount.safe_sub(balance_before)?;
+    require!(received > 0, VaultError::InvalidAmount);
+
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let vault_user = &mut ctx.accounts.vault_user;
+
+    // Check if vault is in emergency stop
+    require!(!vault_state.is_emergency_stopped(), VaultError::EmergencyStopActive);
+
+    // Revalue what the vault already holds of this asset at the live price
+    let collateral = *vault_state
+        .collateral_mut(&mint)
+        .ok_or(VaultError::CollateralNotSupported)?;
+    require!(collateral.is_enabled(), VaultError::CollateralNotSupported);
+
+    let (market_index, _, oracle_price) = spot_market_oracle_price(
+        &ctx.accounts.spot_market,
+        &ctx.accounts.oracle,
+        clock.slot,
+    )?;
+    require_eq!(
+        market_index,
+        collateral.spot_market_index,
+        VaultError::InvalidMarketIndex
+    );
+    let value = calculate_collateral_value(
+        received,
+        oracle_price,
+        collateral.decimals,
+        collateral.haircut_bps,
+    )?;
+    let held_value = calculate_collateral_value(
+        collateral.amount,
+        oracle_price,
+        collateral.decimals,
+        collateral.haircut_bps,
+    )?;
+    let entry = vault_state
+        .collateral_mut(&mint)
+        .ok_or(VaultError::CollateralNotSupported)?;
+    entry.value = held_value;
+    entry.last_valued_slot = clock.slot;
+
+    // Shares are priced off NAV, which needs current values for every asset
+    require!(
+        vault_state.is_collateral_fresh(clock.slot),
+        VaultError::CollateralValuationStale
+    );
+
+    admit_depositor(
+        &vault_state,
+        vault_key,
+        vault_user,
+        ctx.accounts.owner.key(),
+        ctx.bumps.vault_user,
+        allowlist_proof,
+    )?;
+    check_deposit_limits(&vault_state, vault_user, value, clock.unix_timestamp)?;
+    vault_state.record_flow(clock.unix_timestamp, to_i64(value)?)?;
+
+    // Calculate shares to mint against the haircut value
+    let shares_to_mint = vault_state.shares_for_deposit(value)?;
 
     // Update vault state
-    vault_state.total_assets += amount;
-    vault_state.total_shares += shares_to_mint;
-    vault_state.net_deposits += amount as i64;
+    let entry = vault_state
+        .collateral_mut(&mint)
+        .ok_or(VaultError::CollateralNotSupported)?;
+    entry.amount = entry.amount.safe_add(received)?;
+    entry.value = entry.value.safe_add(value)?;
+
+    // A third-party depositor cannot pick the owner's referrer
+    let owner_consents = ctx.accounts.depositor.key() == ctx.accounts.owner.key()
+        || ctx.accounts.owner.is_signer;
+    update_referred_shares(
+        &mut vault_state,
+        vault_user,
+        ctx.accounts.referrer_account.as_mut(),
+        owner_consents,
+        shares_to_mint,
+        0,
+    )?;
+
+    vault_state.total_shares = vault_state.total_shares.safe_add(shares_to_mint)?;
+    vault_state.net_deposits = vault_state.net_deposits.safe_add(to_i64(value)?)?;
+
+    // Update user state
+    vault_user.record_deposit(shares_to_mint, value, clock.unix_timestamp, vault_state.fees_per_share)?;
 
     // Calculate share price
     let share_price = vault_state.calculate_share_price()?;
 
+    // The vault signs the Drift deposit, so release its data borrow first
+    let sequence = vault_state.next_event_sequence();
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+
+    // Move it into the matching Drift spot market
+    let cpi_ctx = CpiContext::new_with_signer(
+        ctx.accounts.drift_program.to_account_info(),
+        DriftDeposit {
+            state: ctx.accounts.drift_state.to_account_info(),
+            user: ctx.accounts.drift_user.to_account_info(),
+            user_stats: ctx.accounts.drift_user_stats.to_account_info(),
+            authority: ctx.accounts.vault_state.to_account_info(),
+            spot_market_vault: ctx.accounts.spot_market_vault.to_account_info(),
+            user_token_account: ctx.accounts.vault_collateral_account.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        },
+        signer,
+    )
+    .with_remaining_accounts(vec![
+        ctx.accounts.oracle.to_account_info(),
+        ctx.accounts.spot_market.to_account_info(),
+    ]);
+    drift::cpi::deposit(cpi_ctx, collateral.spot_market_index, received, false)?;
+
     // Emit event
-    emit!(DepositEvent {
-        vault: vault_state.key(),
-        user: ctx.accounts.depositor.key(),
-        amount,
+    emit!(CollateralDepositEvent {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence,
+        depositor: ctx.accounts.depositor.key(),
+        owner: ctx.accounts.owner.key(),
+        referrer: vault_user.referrer,
+        mint,
+        amount: received,
+        value,
         shares_minted: shares_to_mint,
         share_price,
+        average_entry_price: vault_user.average_entry_price,
         timestamp: clock.unix_timestamp,
     });
 
-    msg!("Deposit successful: {} tokens, {} shares", amount, shares_to_mint);
+    msg!("Collateral deposit successful: {} tokens worth {}, {} shares", received, value, shares_to_mint);
+    Ok(())
+}
+
+pub fn revalue_collateral(ctx: Context<RevalueCollateral>) -> Result<()> {
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let clock = Clock::get()?;
+
+    // Remaining accounts come in (spot market, oracle) pairs, one per collateral asset
+    require!(ctx.remaining_accounts.len() % 2 == 0, VaultError::InvalidOracleAccount);
+    for pair in ctx.remaining_accounts.chunks(2) {
+        let (market_index, mint, oracle_price) = spot_market_oracle_price(&pair[0], &pair[1], clock.slot)?;
+        let collateral = vault_state
+            .collateral_mut(&mint)
+            .ok_or(VaultError::CollateralNotSupported)?;
+        require_eq!(
+            collateral.spot_market_index,
+            market_index,
+            VaultError::InvalidMarketIndex
+        );
+
+        collateral.value = calculate_collateral_value(
+            collateral.amount,
+            oracle_price,
+            collateral.decimals,
+            collateral.haircut_bps,
+        )?;
+        collateral.last_valued_slot = clock.slot;
+    }
+
+    msg!("Collateral revalued, total value: {}", vault_state.collateral_value()?);
     Ok(())
 }
 
 pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
-    let vault_state = &mut ctx.accounts.vault_state;
+    let accounts = ctx.accounts;
+    process_withdrawal(
+        WithdrawAccounts {
+            vault_state: &accounts.vault_state,
+            vault_token_account: &accounts.vault_token_account,
+            token_mint: &accounts.token_mint,
+            receiver_token_account: &accounts.receiver_token_account,
+            vault_user: &mut accounts.vault_user,
+            owner: accounts.owner.key(),
+            referrer_account: accounts.referrer_account.as_mut(),
+            token_program: &accounts.token_program,
+        },
+        |vault_state, locked| vault_state.quote_withdraw(amount, locked),
+    )?;
+    Ok(())
+}
+
+pub fn redeem(ctx: Context<Redeem>, shares: u64, min_amount_out: u64) -> Result<u64> {
+    let accounts = ctx.accounts;
+    let quote = process_withdrawal(
+        WithdrawAccounts {
+            vault_state: &accounts.vault_state,
+            vault_token_account: &accounts.vault_token_account,
+            token_mint: &accounts.token_mint,
+            receiver_token_account: &accounts.receiver_token_account,
+            vault_user: &mut accounts.vault_user,
+            owner: accounts.owner.key(),
+            referrer_account: accounts.referrer_account.as_mut(),
+            token_program: &accounts.token_program,
+        },
+        // Callers cannot see the share price they will get, so they bound it
+        |vault_state, locked| vault_state.quote_redeem(shares, locked)?.ensure_min_out(min_amount_out),
+    )?;
+    Ok(quote.amount_out)
+}
+
+pub fn withdraw_collateral<'info>(
+    ctx: Context<'_, '_, '_, 'info, WithdrawCollateral<'info>>,
+    shares: u64,
+) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let vault_user = &mut ctx.accounts.vault_user;
     let clock = Clock::get()?;
 
     // Check if vault is in emergency stop
-    require!(!vault_state.emergency_stop, DeltaNeutralVaultError::EmergencyStopActive);
+    require!(!vault_state.is_emergency_stopped(), VaultError::EmergencyStopActive);
 
-    // Check if vault has sufficient assets
     require!(
-        vault_state.total_assets >= amount,
-        DeltaNeutralVaultError::InsufficientFunds
+        vault_state.is_collateral_fresh(clock.slot),
+        VaultError::CollateralValuationStale
     );
+    require_at_most!(shares, vault_user.shares, VaultError::InsufficientFunds);
 
-    // Calculate shares to burn
-    let shares_to_burn = if vault_state.total_shares == 0 {
-        0
-    } else {
-        (amount as u128 * vault_state.total_shares as u128 / vault_state.total_assets as u128) as u64
-    };
+    // There is no single base amount to charge an early-exit fee on, so
+    // in-kind exits wait out the lockup
+    require!(
+        !vault_user.is_locked(&vault_state, clock.unix_timestamp),
+        VaultError::WithdrawalLocked
+    );
 
-    // Transfer tokens from vault to withdrawer
-    let transfer_ctx = CpiContext::new(
-        ctx.accounts.token_program.to_account_info(),
-        Transfer {
-            from: ctx.accounts.vault_token_account.to_account_info(),
-            to: ctx.accounts.withdrawer_token_account.to_account_info(),
-            authority: vault_state.to_account_info(),
-        },
+    let quote = vault_state.quote_in_kind(shares)?;
+
+    // Check the epoch outflow limit
+    vault_state.record_flow(clock.unix_timestamp, -to_i64(quote.value)?)?;
+
+    update_referred_shares(
+        &mut vault_state,
+        vault_user,
+        ctx.accounts.referrer_account.as_mut(),
+        false,
+        0,
+        shares,
+    )?;
+
+    // Update vault state
+    let mut withdrawals = Vec::new();
+    for (slot, amount) in quote.collateral_amounts.iter().enumerate() {
+        if *amount == 0 {
+            continue;
+        }
+        let collateral = &mut vault_state.collaterals[slot];
+        collateral.amount = collateral.amount.safe_sub(*amount)?;
+        collateral.value = collateral.value.safe_sub(quote.collateral_values[slot])?;
+        withdrawals.push((collateral.mint, collateral.spot_market_index, *amount));
+    }
+    vault_state.total_assets = vault_state.total_assets.safe_sub(quote.base_amount)?;
+    vault_state.total_shares = vault_state.total_shares.safe_sub(shares)?;
+    vault_state.net_deposits = vault_state.net_deposits.safe_sub(to_i64(quote.value)?)?;
+
+    // Update user state
+    let realized_pnl = vault_user.record_withdrawal(shares, quote.value, 0, vault_state.fees_per_share)?;
+
+    // Calculate share price
+    let share_price = vault_state.calculate_share_price()?;
+
+    // The vault signs the transfers, so release its data borrow first
+    let sequence = vault_state.next_event_sequence();
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+
+    if quote.base_amount > 0 {
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault_token_account.to_account_info(),
+                mint: ctx.accounts.token_mint.to_account_info(),
+                to: ctx.accounts.receiver_token_account.to_account_info(),
+                authority: ctx.accounts.vault_state.to_account_info(),
+            },
+            signer,
+        );
+        transfer_checked(transfer_ctx, quote.base_amount, ctx.accounts.token_mint.decimals)?;
+    }
+
+    // Remaining accounts: a (spot market vault, receiver token account) pair
+    // per collateral paid out, in slot order, then the oracles and spot
+    // markets Drift needs for the withdrawals
+    let pairs_len = withdrawals.len() * 2;
+    require!(
+        ctx.remaining_accounts.len() >= pairs_len,
+        VaultError::InvalidTokenAccount
+    );
+    let (pairs, drift_accounts) = ctx.remaining_accounts.split_at(pairs_len);
+    for ((mint, spot_market_index, amount), accounts) in withdrawals.iter().zip(pairs.chunks(2)) {
+        let receiver_account =
+            InterfaceAccount::<token_interface::TokenAccount>::try_from(&accounts[1])?;
+        require_keys_eq!(receiver_account.mint, *mint, VaultError::InvalidTokenAccount);
+
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.drift_program.to_account_info(),
+            DriftWithdraw {
+                state: ctx.accounts.drift_state.to_account_info(),
+                user: ctx.accounts.drift_user.to_account_info(),
+                user_stats: ctx.accounts.drift_user_stats.to_account_info(),
+                authority: ctx.accounts.vault_state.to_account_info(),
+                spot_market_vault: accounts[0].clone(),
+                drift_signer: ctx.accounts.drift_signer.to_account_info(),
+                user_token_account: accounts[1].clone(),
+                token_program: ctx.accounts.token_program.to_account_info(),
+            },
+            signer,
+        )
+        .with_remaining_accounts(drift_accounts.to_vec());
+        drift::cpi::withdraw(cpi_ctx, *spot_market_index, *amount, true)?;
+    }
+
+    // Emit event
+    emit!(CollateralWithdrawEvent {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence,
+        owner: ctx.accounts.owner.key(),
+        receiver: ctx.accounts.receiver_token_account.owner,
+        shares_burned: shares,
+        base_amount: quote.base_amount,
+        collateral_amounts: quote.collateral_amounts,
+        value: quote.value,
+        share_price,
+        realized_pnl,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("In-kind withdrawal successful: {} shares worth {}", shares, quote.value);
+    Ok(())
+}
+
+/// Accounts shared by `withdraw` and `redeem`
+struct WithdrawAccounts<'a, 'info> {
+    vault_state: &'a AccountLoader<'info, VaultState>,
+    vault_token_account: &'a InterfaceAccount<'info, token_interface::TokenAccount>,
+    token_mint: &'a InterfaceAccount<'info, token_interface::Mint>,
+    receiver_token_account: &'a InterfaceAccount<'info, token_interface::TokenAccount>,
+    vault_user: &'a mut Account<'info, VaultUser>,
+    owner: Pubkey,
+    referrer_account: Option<&'a mut Account<'info, ReferrerAccount>>,
+    token_program: &'a Interface<'info, TokenInterface>,
+}
+
+/// Burn the owner's shares and pay out the base asset as priced by `quote`,
+/// which is given whether the owner is inside the lockup
+fn process_withdrawal(
+    accounts: WithdrawAccounts,
+    quote: impl FnOnce(&VaultState, bool) -> Result<WithdrawalQuote>,
+) -> Result<WithdrawalQuote> {
+    let vault_key = accounts.vault_state.key();
+    let mut vault_state = accounts.vault_state.load_mut()?;
+    let vault_user = accounts.vault_user;
+    let clock = Clock::get()?;
+
+    // Check if vault is in emergency stop
+    require!(!vault_state.is_emergency_stopped(), VaultError::EmergencyStopActive);
+
+    require!(
+        vault_state.is_collateral_fresh(clock.slot),
+        VaultError::CollateralValuationStale
+    );
+
+    // The early-exit fee stays in the vault and accrues to the remaining holders
+    let locked = vault_user.is_locked(&vault_state, clock.unix_timestamp);
+    let withdrawal = quote(&vault_state, locked)?;
+    let WithdrawalQuote {
+        shares_burned: shares_to_burn,
+        amount,
+        early_exit_fee,
+        amount_out,
+    } = withdrawal;
+
+    require_at_most!(
+        shares_to_burn,
+        vault_user.shares,
+        VaultError::InsufficientFunds
     );
-    transfer(transfer_ctx, amount)?;
+
+    // Check the epoch outflow limit
+    vault_state.record_flow(clock.unix_timestamp, -to_i64(amount_out)?)?;
+
+    update_referred_shares(
+        &mut vault_state,
+        vault_user,
+        accounts.referrer_account,
+        false,
+        0,
+        shares_to_burn,
+    )?;
 
     // Update vault state
-    vault_state.total_assets -= amount;
-    vault_state.total_shares -= shares_to_burn;
-    vault_state.net_deposits -= amount as i64;
+    vault_state.total_assets = vault_state.total_assets.safe_sub(amount_out)?;
+    vault_state.total_shares = vault_state.total_shares.safe_sub(shares_to_burn)?;
+    vault_state.net_deposits = vault_state.net_deposits.safe_sub(to_i64(amount_out)?)?;
+
+    // Update user state
+    let realized_pnl = vault_user.record_withdrawal(
+        shares_to_burn,
+        amount_out,
+        early_exit_fee,
+        vault_state.fees_per_share,
+    )?;
 
     // Calculate share price
     let share_price = vault_state.calculate_share_price()?;
 
+    // The vault signs the transfer, so release its data borrow first
+    let sequence = vault_state.next_event_sequence();
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+
+    // Transfer tokens from vault to the receiver. Any Token-2022 transfer fee
+    // is withheld from what the receiver gets.
+    let transfer_ctx = CpiContext::new_with_signer(
+        accounts.token_program.to_account_info(),
+        TransferChecked {
+            from: accounts.vault_token_account.to_account_info(),
+            mint: accounts.token_mint.to_account_info(),
+            to: accounts.receiver_token_account.to_account_info(),
+            authority: accounts.vault_state.to_account_info(),
+        },
+        signer,
+    );
+    transfer_checked(transfer_ctx, amount_out, accounts.token_mint.decimals)?;
+
     // Emit event
     emit!(WithdrawEvent {
-        vault: vault_state.key(),
-        user: ctx.accounts.withdrawer.key(),
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence,
+        owner: accounts.owner,
+        receiver: accounts.receiver_token_account.owner,
         amount,
         shares_burned: shares_to_burn,
+        early_exit_fee,
         share_price,
+        realized_pnl,
+        average_entry_price: vault_user.average_entry_price,
+        cumulative_realized_pnl: vault_user.realized_pnl,
+        fees_paid: vault_user.fees_paid,
         timestamp: clock.unix_timestamp,
     });
 
     msg!("Withdrawal successful: {} tokens, {} shares", amount, shares_to_burn);
-    Ok(())
+    Ok(withdrawal)
 }
 
-pub fn rebalance(ctx: Context<Rebalance>) -> Result<()> {
-    let vault_state = &mut ctx.accounts.vault_state;
+pub fn rebalance<'info>(ctx: Context<'_, '_, '_, 'info, Rebalance<'info>>) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
     let clock = Clock::get()?;
 
     // Check if vault is in emergency stop
-    require!(!vault_state.emergency_stop, DeltaNeutralVaultError::EmergencyStopActive);
+    require!(!vault_state.is_emergency_stopped(), VaultError::EmergencyStopActive);
 
     // Check rebalance cooldown
     require!(
         vault_state.can_rebalance(clock.unix_timestamp),
-        DeltaNeutralVaultError::RebalanceCooldown
+        VaultError::RebalanceCooldown
+    );
+
+    // Refuse to hedge off a book that has drifted from Drift's view
+    require!(
+        vault_state.is_position_book_fresh(clock.slot),
+        VaultError::PositionBookStale
     );
 
+    // Book any fills on outstanding orders before measuring delta
+    refresh_open_orders(&mut vault_state, vault_key, &ctx.accounts.drift_user)?;
+
     // Check if rebalancing is needed
     require!(
         vault_state.needs_rebalance()?,
-        DeltaNeutralVaultError::RebalanceNotNeeded
+        VaultError::RebalanceNotNeeded
     );
 
     let delta_before = vault_state.calculate_delta()?;
+    let long_before = vault_state.long_position;
+    let short_before = vault_state.short_position;
     
     // Calculate required hedge
     let hedge_calc = vault_state.calculate_required_hedge()?;
+    let market_index = SOL_MARKET_INDEX;
+    let sub_account = vault_state
+        .drift_sub_account_slot(&ctx.accounts.drift_user.key())
+        .ok_or(VaultError::UnknownSubAccount)?;
+    vault_state.route_market(market_index, sub_account)?;
+    let max_ts = clock.unix_timestamp.safe_add(ORDER_TIME_TO_LIVE)?;
+    let oracle_price = oracle_price_for_market(&ctx.accounts.perp_market, market_index)?;
+    let position_before = perp_position_snapshot(&ctx.accounts.drift_user, market_index)?;
     
-    match hedge_calc.action {
-        HedgeAction::IncreaseLong => {
-            // Place long order on Drift
-            place_drift_order(
-                &ctx.accounts.drift_program,
-                &ctx.accounts.drift_user,
-                &ctx.accounts.drift_user_stats,
-                &ctx.accounts.drift_state,
-                hedge_calc.amount,
-                PositionDirection::Long,
-                vault_state.max_slippage,
-            )?;
-            vault_state.long_position += hedge_calc.amount as i64;
-        },
-        HedgeAction::IncreaseShort => {
-            // Place short order on Drift
-            place_drift_order(
-                &ctx.accounts.drift_program,
-                &ctx.accounts.drift_user,
-                &ctx.accounts.drift_user_stats,
-                &ctx.accounts.drift_state,
-                hedge_calc.amount,
-                PositionDirection::Short,
-                vault_state.max_slippage,
-            )?;
-            vault_state.short_position += hedge_calc.amount as i64;
-        },
-        HedgeAction::ReduceLong => {
-            // Close long position on Drift
-            close_drift_position(
-                &ctx.accounts.drift_program,
-                &ctx.accounts.drift_user,
-                &ctx.accounts.drift_user_stats,
-                &ctx.accounts.drift_state,
-                hedge_calc.amount,
-                PositionDirection::Long,
-                vault_state.max_slippage,
-            )?;
-            vault_state.long_position -= hedge_calc.amount as i64;
-        },
-        HedgeAction::ReduceShort => {
-            // Close short position on Drift
-            close_drift_position(
-                &ctx.accounts.drift_program,
-                &ctx.accounts.drift_user,
-                &ctx.accounts.drift_user_stats,
-                &ctx.accounts.drift_state,
-                hedge_calc.amount,
-                PositionDirection::Short,
-                vault_state.max_slippage,
-            )?;
-            vault_state.short_position -= hedge_calc.amount as i64;
-        },
+    let (direction, reduce_only) = match hedge_calc.action {
+        HedgeAction::IncreaseLong => (PositionDirection::Long, false),
+        HedgeAction::IncreaseShort => (PositionDirection::Short, false),
+        // Reducing a leg trades against it with a reduce-only order
+        HedgeAction::ReduceLong => (PositionDirection::Short, true),
+        HedgeAction::ReduceShort => (PositionDirection::Long, true),
         HedgeAction::None => {
             msg!("No rebalancing needed");
             return Ok(());
         }
-    }
+    };
+    let mut order = OpenOrder {
+        order_id: 0,
+        market_index,
+        direction: direction as u8,
+        reduce_only: u8::from(reduce_only),
+        base_asset_amount: hedge_calc.amount,
+        filled_amount: 0,
+        quote_filled: 0,
+        oracle_price,
+        max_ts,
+    };
+
+    // The vault signs the order, so its data borrow ends before the CPI
+    let max_slippage = vault_state.max_slippage;
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+    order.order_id = place_drift_order(
+        &ctx.accounts.drift_program,
+        &ctx.accounts.drift_state,
+        &ctx.accounts.drift_user,
+        &ctx.accounts.drift_user_stats,
+        &ctx.accounts.vault_state.to_account_info(),
+        &ctx.accounts.perp_market,
+        ctx.remaining_accounts,
+        signer,
+        &order,
+        max_slippage,
+    )?;
+
+    // Positions are only updated once Drift reports fills
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    vault_state.track_order(order)?;
+    let realized_pnl = settle_immediate_fill(&mut vault_state, vault_key, &ctx.accounts.drift_user, order.order_id, market_index, position_before)?;
+    let fees_paid = record_trading_fees(&mut vault_state, &ctx.accounts.drift_user_stats, market_index)?;
 
     let delta_after = vault_state.calculate_delta()?;
     vault_state.last_rebalance_time = clock.unix_timestamp;
+    record_history(&ctx.accounts.history, &vault_state, clock.unix_timestamp)?;
+    let book = *vault_state.market_book_mut(market_index)?;
 
     // Emit event
     emit!(RebalanceEvent {
-        vault: vault_state.key(),
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
         delta_before,
         delta_after,
-        long_position_change: vault_state.long_position,
-        short_position_change: vault_state.short_position,
+        long_position_change: vault_state.long_position.safe_sub(long_before)?,
+        short_position_change: vault_state.short_position.safe_sub(short_before)?,
+        market_index,
+        average_entry_price: book.average_entry_price,
+        realized_pnl,
+        fees_paid,
+        cumulative_realized_pnl: book.realized_pnl,
+        cumulative_funding: book.cumulative_funding,
+        cumulative_fees_paid: book.fees_paid,
         timestamp: clock.unix_timestamp,
     });
 
@@ -279,68 +992,140 @@ pub fn rebalance(ctx: Context<Rebalance>) -> Result<()> {
     Ok(())
 }
 
-pub fn emergency_stop(ctx: Context<EmergencyStop>) -> Result<()> {
-    let vault_state = &mut ctx.accounts.vault_state;
+pub fn emergency_stop(ctx: Context<EmergencyStop>, reason: EmergencyStopReason) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
     let clock = Clock::get()?;
 
-    vault_state.emergency_stop = true;
+    vault_state.emergency_stop = 1;
 
     // Emit event
     emit!(EmergencyStopEvent {
-        vault: vault_state.key(),
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
         admin: ctx.accounts.admin.key(),
-        reason: "Emergency stop triggered by admin".to_string(),
+        reason,
         timestamp: clock.unix_timestamp,
     });
 
-    msg!("Emergency stop activated");
+    msg!("Emergency stop activated: {:?}", reason);
     Ok(())
 }
 
 pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
-    let vault_state = &mut ctx.accounts.vault_state;
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
     let clock = Clock::get()?;
 
-    // Calculate management fees
-    let time_elapsed = clock.unix_timestamp - vault_state.last_rebalance_time;
+    require!(
+        vault_state.is_collateral_fresh(clock.slot),
+        VaultError::CollateralValuationStale
+    );
+    let total_value = vault_state.calculate_total_value()?;
+
+    // Calculate management fees since the last collection
+    let time_elapsed = clock.unix_timestamp.safe_sub(vault_state.last_fee_collection_time)?;
     let management_fees = calculate_management_fees(
-        vault_state.total_assets,
+        total_value,
         vault_state.management_fee,
         time_elapsed,
     )?;
 
-    // Calculate performance fees
+    // Calculate performance fees on gains above the high-water mark
     let performance_fees = calculate_performance_fees(
-        vault_state.total_assets,
+        vault_state.calculate_share_price()?,
+        vault_state.high_water_mark,
+        vault_state.total_shares,
         vault_state.performance_fee,
-        vault_state.net_deposits,
     )?;
 
-    let total_fees = management_fees + performance_fees;
+    // Fees are never more than the base asset they are paid from
+    let total_fees = management_fees
+        .safe_add(performance_fees)?
+        .min(vault_state.total_assets);
+
+    // Advance the checkpoint on every call, so neither fee is charged twice.
+    // The high-water mark is the post-fee share price and never moves down.
+    vault_state.total_assets = vault_state.total_assets.safe_sub(total_fees)?;
+    let high_water_mark = vault_state.high_water_mark.max(vault_state.calculate_share_price()?);
+    vault_state.last_fee_collection_time = clock.unix_timestamp;
+    vault_state.high_water_mark = high_water_mark;
 
     if total_fees > 0 {
+        // The protocol takes its share of the vault's fees
+        let protocol_fees = bps_of(
+            total_fees,
+            ctx.accounts.protocol_config.protocol_fee_bps,
+            Rounding::Down,
+        )?;
+        // Referrers' rebates stay in the vault token account until claimed
+        let referral_fees = vault_state.referral_fees_for(total_fees)?;
+        let vault_fees = total_fees.safe_sub(protocol_fees)?.safe_sub(referral_fees)?;
+        let decimals = ctx.accounts.token_mint.decimals;
+
+        if referral_fees > 0 {
+            vault_state.accrue_referral_fees(referral_fees)?;
+        }
+        vault_state.accrue_fees_per_share(total_fees)?;
+
+        // Update vault state
+        vault_state.total_fees_collected = vault_state.total_fees_collected.safe_add(total_fees)?;
+        record_history(&ctx.accounts.history, &vault_state, clock.unix_timestamp)?;
+
+        // The vault signs the transfers, so release its data borrow first
+        let sequence = vault_state.next_event_sequence();
+        let admin_key = vault_state.admin;
+        let vault_id = vault_state.vault_id_seed();
+        let bump = vault_state.bump;
+        drop(vault_state);
+        let signer_seeds = &[
+            VAULT_SEED,
+            admin_key.as_ref(),
+            vault_id.as_slice(),
+            &[bump],
+        ];
+        let signer = &[&signer_seeds[..]];
+
         // Transfer fees to collector
-        let transfer_ctx = CpiContext::new(
+        let transfer_ctx = CpiContext::new_with_signer(
             ctx.accounts.token_program.to_account_info(),
-            Transfer {
+            TransferChecked {
                 from: ctx.accounts.vault_token_account.to_account_info(),
+                mint: ctx.accounts.token_mint.to_account_info(),
                 to: ctx.accounts.fee_collector_account.to_account_info(),
-                authority: vault_state.to_account_info(),
+                authority: ctx.accounts.vault_state.to_account_info(),
             },
+            signer,
         );
-        transfer(transfer_ctx, total_fees)?;
-
-        // Update vault state
-        vault_state.total_assets -= total_fees;
-        vault_state.total_fees_collected += total_fees;
+        transfer_checked(transfer_ctx, vault_fees, decimals)?;
+
+        if protocol_fees > 0 {
+            let transfer_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                TransferChecked {
+                    from: ctx.accounts.vault_token_account.to_account_info(),
+                    mint: ctx.accounts.token_mint.to_account_info(),
+                    to: ctx.accounts.protocol_fee_account.to_account_info(),
+                    authority: ctx.accounts.vault_state.to_account_info(),
+                },
+                signer,
+            );
+            transfer_checked(transfer_ctx, protocol_fees, decimals)?;
+        }
 
         // Emit event
         emit!(FeeCollectionEvent {
-            vault: vault_state.key(),
+            vault: vault_key,
+            schema_version: EVENT_SCHEMA_VERSION,
+            sequence,
             fee_collector: ctx.accounts.fee_collector.key(),
             management_fees,
             performance_fees,
             total_fees,
+            protocol_fees,
+            referral_fees,
+            high_water_mark,
             timestamp: clock.unix_timestamp,
         });
 
@@ -351,222 +1136,1636 @@ pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
     Ok(())
 }
 
-pub fn update_vault_params(
-    ctx: Context<UpdateVaultParams>,
-    target_leverage: Option<u8>,
-    rebalance_threshold: Option<u16>,
-    max_slippage: Option<u16>,
-) -> Result<()> {
-    let vault_state = &mut ctx.accounts.vault_state;
+pub fn register_referrer(ctx: Context<RegisterReferrer>) -> Result<()> {
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let referrer_account = &mut ctx.accounts.referrer_account;
     let clock = Clock::get()?;
 
-    // Update parameters if provided
-    if let Some(leverage) = target_leverage {
-        require!(
-            leverage > 0 && leverage <= MAX_LEVERAGE,
-            DeltaNeutralVaultError::InvalidLeverage
-        );
-        vault_state.target_leverage = leverage;
-    }
+    referrer_account.referrer = ctx.accounts.referrer.key();
+    referrer_account.vault = ctx.accounts.vault_state.key();
+    referrer_account.referred_shares = 0;
+    referrer_account.fee_debt = 0;
+    referrer_account.accrued_fees = 0;
+    referrer_account.total_claimed = 0;
+    referrer_account.bump = ctx.bumps.referrer_account;
+
+    emit!(ReferrerRegistered {
+        vault: referrer_account.vault,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        referrer: referrer_account.referrer,
+        timestamp: clock.unix_timestamp,
+    });
 
-    if let Some(threshold) = rebalance_threshold {
-        require!(
-            threshold >= MIN_REBALANCE_THRESHOLD_BPS && 
-            threshold <= MAX_REBALANCE_THRESHOLD_BPS,
-            DeltaNeutralVaultError::InvalidRebalanceThreshold
-        );
-        vault_state.rebalance_threshold = threshold;
-        vault_state.delta_threshold = threshold;
-    }
+    msg!("Referrer registered: {}", referrer_account.referrer);
+    Ok(())
+}
 
-    if let Some(slippage) = max_slippage {
-        require!(
-            slippage <= MAX_SLIPPAGE_BPS,
-            DeltaNeutralVaultError::InvalidSlippage
-        );
-        vault_state.max_slippage = slippage;
-    }
+pub fn claim_referral_fees(ctx: Context<ClaimReferralFees>) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let referrer_account = &mut ctx.accounts.referrer_account;
+    let clock = Clock::get()?;
 
-    // Emit event
-    emit!(VaultParamsUpdated {
-        vault: vault_state.key(),
-        admin: ctx.accounts.admin.key(),
-        target_leverage,
-        rebalance_threshold,
-        max_slippage,
+    referrer_account.settle(vault_state.referral_fees_per_share)?;
+    let amount = referrer_account.accrued_fees;
+    require!(amount > 0, VaultError::InvalidAmount);
+
+    referrer_account.accrued_fees = 0;
+    referrer_account.total_claimed = referrer_account.total_claimed.safe_add(amount)?;
+    vault_state.referral_fees_owed = vault_state.referral_fees_owed.safe_sub(amount)?;
+
+    // The vault signs the transfer, so release its data borrow first
+    let sequence = vault_state.next_event_sequence();
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+
+    let transfer_ctx = CpiContext::new_with_signer(
+        ctx.accounts.token_program.to_account_info(),
+        TransferChecked {
+            from: ctx.accounts.vault_token_account.to_account_info(),
+            mint: ctx.accounts.token_mint.to_account_info(),
+            to: ctx.accounts.referrer_token_account.to_account_info(),
+            authority: ctx.accounts.vault_state.to_account_info(),
+        },
+        signer,
+    );
+    transfer_checked(transfer_ctx, amount, ctx.accounts.token_mint.decimals)?;
+
+    emit!(ReferralFeesClaimed {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence,
+        referrer: referrer_account.referrer,
+        amount,
         timestamp: clock.unix_timestamp,
     });
 
-    msg!("Vault parameters updated successfully");
+    msg!("Referral fees claimed: {}", amount);
     Ok(())
 }
 
-pub fn open_position(
-    ctx: Context<OpenPosition>,
-    market_index: u16,
-    size: u64,
-    direction: u8,
-) -> Result<()> {
-    let vault_state = &mut ctx.accounts.vault_state;
+pub fn initialize_history(ctx: Context<InitializeHistory>) -> Result<()> {
+    let vault_state = ctx.accounts.vault_state.load()?;
+    let mut history = ctx.accounts.history.load_init()?;
+    let clock = Clock::get()?;
+
+    history.vault = ctx.accounts.vault_state.key();
+    history.bump = ctx.bumps.history;
+    history.record(HistorySnapshot::of(&vault_state, clock.unix_timestamp)?);
+
+    msg!("History initialized for vault {}", history.vault);
+    Ok(())
+}
+
+pub fn get_share_price(ctx: Context<ViewVault>) -> Result<u64> {
+    ctx.accounts.vault_state.load()?.calculate_share_price()
+}
+
+pub fn preview_deposit(
+    ctx: Context<PreviewDeposit>,
+    amount: u64,
+    allowlist_proof: Option<AllowlistProof>,
+) -> Result<u64> {
+    let clock = Clock::get()?;
+
+    // Run `deposit`'s checks without writing, so a quote is never issued for
+    // a deposit that cannot execute
+    let vault_state = ctx.accounts.vault_state.load()?;
+    let mut vault_user = ctx.accounts.vault_user.as_deref().cloned().unwrap_or_default();
+    let vault_user_bump = vault_user.bump;
+
+    check_deposit_open(&vault_state, clock.slot)?;
+    admit_depositor(
+        &vault_state,
+        ctx.accounts.vault_state.key(),
+        &mut vault_user,
+        ctx.accounts.owner.key(),
+        vault_user_bump,
+        allowlist_proof,
+    )?;
+
+    // `deposit` credits what arrives, net of any Token-2022 transfer fee
+    let fee = transfer_fee(&ctx.accounts.token_mint.to_account_info(), amount, clock.epoch)?;
+    let received = amount.safe_sub(fee)?;
+
+    price_deposit(&vault_state, &vault_user, received, clock.unix_timestamp, 0)
+}
+
+pub fn preview_redeem(ctx: Context<PreviewRedeem>, shares: u64) -> Result<WithdrawalQuote> {
+    let vault_state = ctx.accounts.vault_state.load()?;
+    let clock = Clock::get()?;
+
+    require!(!vault_state.is_emergency_stopped(), VaultError::EmergencyStopActive);
+    require!(
+        vault_state.is_collateral_fresh(clock.slot),
+        VaultError::CollateralValuationStale
+    );
+
+    let locked = match &ctx.accounts.vault_user {
+        Some(vault_user) => {
+            require_at_most!(shares, vault_user.shares, VaultError::InsufficientFunds);
+            vault_user.is_locked(&vault_state, clock.unix_timestamp)
+        }
+        None => false,
+    };
+
+    vault_state.quote_redeem(shares, locked)
+}
+
+pub fn get_nav_breakdown(ctx: Context<ViewVault>) -> Result<NavBreakdown> {
+    let clock = Clock::get()?;
+    ctx.accounts.vault_state.load()?.nav_breakdown(clock.slot)
+}
+
+pub fn get_delta(ctx: Context<ViewVault>) -> Result<DeltaExposure> {
+    ctx.accounts.vault_state.load()?.delta_exposure()
+}
+
+pub fn close_user(ctx: Context<CloseUser>) -> Result<()> {
+    let vault_user = &ctx.accounts.vault_user;
+    let clock = Clock::get()?;
+
+    require_eq!(vault_user.shares, 0, VaultError::SharesOutstanding);
+
+    // A closed vault is handed back to the system program, leaving nothing to sequence against
+    let vault_info = ctx.accounts.vault_state.to_account_info();
+    let sequence = if vault_info.owner == &crate::ID {
+        AccountLoader::<VaultState>::try_from(&vault_info)?
+            .load_mut()?
+            .next_event_sequence()
+    } else {
+        0
+    };
+
+    emit!(UserClosed {
+        vault: vault_user.vault,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence,
+        user: vault_user.user,
+        receiver: ctx.accounts.receiver.key(),
+        realized_pnl: vault_user.realized_pnl,
+        fees_paid: vault_user.fees_paid,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("User account closed: {}", vault_user.user);
+    Ok(())
+}
+
+pub fn close_vault<'info>(ctx: Context<'_, '_, '_, 'info, CloseVault<'info>>) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let clock = Clock::get()?;
+
+    // With no shares or referral fees left, whatever the vault token account
+    // still holds is rounding dust, swept to the admin
+    let dust = ctx.accounts.vault_token_account.amount;
+    vault_state.ensure_closable(dust)?;
+
+    let collateral_mints: Vec<Pubkey> = vault_state
+        .collaterals
+        .iter()
+        .filter(|c| !c.is_empty())
+        .map(|c| c.mint)
+        .collect();
+
+    // The vault signs the closes, so release its data borrow first
+    let sequence = vault_state.next_event_sequence();
+    let has_drift_user = vault_state.drift_user != Pubkey::default();
+    let extra_sub_accounts: Vec<Pubkey> = vault_state
+        .drift_sub_accounts
+        .iter()
+        .map(|s| s.user)
+        .filter(|user| *user != Pubkey::default() && *user != vault_state.drift_user)
+        .collect();
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+
+    if dust > 0 {
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault_token_account.to_account_info(),
+                mint: ctx.accounts.token_mint.to_account_info(),
+                to: ctx.accounts.admin_token_account.to_account_info(),
+                authority: ctx.accounts.vault_state.to_account_info(),
+            },
+            signer,
+        );
+        transfer_checked(transfer_ctx, dust, ctx.accounts.token_mint.decimals)?;
+    }
+
+    let close_ctx = CpiContext::new_with_signer(
+        ctx.accounts.token_program.to_account_info(),
+        CloseAccount {
+            account: ctx.accounts.vault_token_account.to_account_info(),
+            destination: ctx.accounts.receiver.to_account_info(),
+            authority: ctx.accounts.vault_state.to_account_info(),
+        },
+        signer,
+    );
+    close_account(close_ctx)?;
+
+    // Remaining accounts: the Drift sub-accounts besides `drift_user`, in slot
+    // order, then each configured collateral's token account, in slot order
+    let (sub_account_infos, collateral_accounts) = ctx
+        .remaining_accounts
+        .split_at(extra_sub_accounts.len().min(ctx.remaining_accounts.len()));
+    require_eq!(collateral_accounts.len(), collateral_mints.len(), VaultError::InvalidTokenAccount);
+    for (collateral_account, mint) in collateral_accounts.iter().zip(collateral_mints.iter()) {
+        let (expected, _) = Pubkey::find_program_address(
+            &[COLLATERAL_TOKEN_ACCOUNT_SEED, vault_key.as_ref(), mint.as_ref()],
+            &crate::ID,
+        );
+        require_keys_eq!(collateral_account.key(), expected, VaultError::InvalidTokenAccount);
+
+        // Only created by a collateral deposit, so it may not exist
+        if collateral_account.lamports() == 0 {
+            continue;
+        }
+        let close_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: collateral_account.clone(),
+                destination: ctx.accounts.receiver.to_account_info(),
+                authority: ctx.accounts.vault_state.to_account_info(),
+            },
+            signer,
+        );
+        close_account(close_ctx)?;
+    }
+
+    // Drift refunds the user's rent to the vault, which is swept to the receiver on close
+    if has_drift_user {
+        let accounts = &ctx.accounts;
+        let (Some(drift_user), Some(drift_user_stats), Some(drift_state), Some(drift_program)) = (
+            &accounts.drift_user,
+            &accounts.drift_user_stats,
+            &accounts.drift_state,
+            &accounts.drift_program,
+        ) else {
+            msg!("Drift user {} must be passed to be deleted", accounts.vault_state.load()?.drift_user);
+            return err!(VaultError::InvalidDriftProgram);
+        };
+        let delete_ctx = CpiContext::new_with_signer(
+            drift_program.to_account_info(),
+            DeleteUser {
+                user: drift_user.to_account_info(),
+                user_stats: drift_user_stats.to_account_info(),
+                state: drift_state.to_account_info(),
+                authority: accounts.vault_state.to_account_info(),
+            },
+            signer,
+        );
+        drift::cpi::delete_user(delete_ctx)?;
+
+        // Other sub-accounts are passed as remaining accounts, in slot order
+        require_eq!(sub_account_infos.len(), extra_sub_accounts.len(), VaultError::UnknownSubAccount);
+        for (sub_account, expected) in sub_account_infos.iter().zip(extra_sub_accounts.iter()) {
+            require_keys_eq!(sub_account.key(), *expected, VaultError::UnknownSubAccount);
+            let delete_ctx = CpiContext::new_with_signer(
+                drift_program.to_account_info(),
+                DeleteUser {
+                    user: sub_account.clone(),
+                    user_stats: drift_user_stats.to_account_info(),
+                    state: drift_state.to_account_info(),
+                    authority: accounts.vault_state.to_account_info(),
+                },
+                signer,
+            );
+            drift::cpi::delete_user(delete_ctx)?;
+        }
+    }
+
+    emit!(VaultClosed {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence,
+        admin: ctx.accounts.admin.key(),
+        receiver: ctx.accounts.receiver.key(),
+        dust_swept: dust,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Vault closed: {}", vault_key);
+    Ok(())
+}
+
+pub fn configure_collateral(
+    ctx: Context<ConfigureCollateral>,
+    spot_market_index: u16,
+    haircut_bps: u16,
+    enabled: bool,
+) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let clock = Clock::get()?;
+    let mint = ctx.accounts.collateral_mint.key();
+
+    require_at_most!(
+        haircut_bps,
+        MAX_COLLATERAL_HAIRCUT_BPS,
+        VaultError::InvalidRiskParameters
+    );
+
+    // The spot market must hold this mint
+    let (market_index, market_mint) = spot_market_info(&ctx.accounts.spot_market)?;
+    require_eq!(
+        market_index,
+        spot_market_index,
+        VaultError::InvalidMarketIndex
+    );
+    require_keys_eq!(market_mint, mint, VaultError::InvalidTokenMint);
+
+    let collateral = vault_state.collateral_entry(&mint)?;
+    require!(
+        collateral.amount == 0 || collateral.spot_market_index == spot_market_index,
+        VaultError::InvalidMarketIndex
+    );
+    collateral.spot_market_index = spot_market_index;
+    collateral.haircut_bps = haircut_bps;
+    collateral.decimals = ctx.accounts.collateral_mint.decimals;
+    collateral.enabled = u8::from(enabled);
+
+    // Emit event
+    emit!(CollateralConfigured {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        admin: ctx.accounts.admin.key(),
+        mint,
+        spot_market_index,
+        haircut_bps,
+        enabled,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Collateral {} configured on spot market {}", mint, spot_market_index);
+    Ok(())
+}
+
+pub fn update_capacity(
+    ctx: Context<UpdateCapacity>,
+    max_capacity: Option<u64>,
+    max_user_deposit: Option<u64>,
+    epoch_duration: Option<i64>,
+    max_epoch_inflow: Option<u64>,
+    max_epoch_outflow: Option<u64>,
+) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let clock = Clock::get()?;
+
+    // Update limits if provided
+    if let Some(capacity) = max_capacity {
+        require!(capacity > 0, VaultError::InvalidCapacityLimits);
+        vault_state.max_capacity = capacity;
+    }
+
+    if let Some(user_cap) = max_user_deposit {
+        vault_state.max_user_deposit = user_cap;
+    }
+
+    if let Some(duration) = epoch_duration {
+        require!(duration > 0, VaultError::InvalidCapacityLimits);
+        require_at_most!(duration, MAX_FLOW_EPOCH_DURATION, VaultError::InvalidCapacityLimits);
+        vault_state.epoch_duration = duration;
+    }
+
+    if let Some(inflow) = max_epoch_inflow {
+        require_at_most!(inflow, i64::MAX as u64, VaultError::InvalidCapacityLimits);
+        vault_state.max_epoch_inflow = inflow;
+    }
+
+    if let Some(outflow) = max_epoch_outflow {
+        require_at_most!(outflow, i64::MAX as u64, VaultError::InvalidCapacityLimits);
+        vault_state.max_epoch_outflow = outflow;
+    }
+
+    vault_state.validate_flow_limits()?;
+
+    // Emit event
+    emit!(CapacityUpdated {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        admin: ctx.accounts.admin.key(),
+        max_capacity,
+        max_user_deposit,
+        epoch_duration,
+        max_epoch_inflow,
+        max_epoch_outflow,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Vault capacity updated successfully");
+    Ok(())
+}
+
+pub fn set_allowlist(
+    ctx: Context<SetAllowlist>,
+    enabled: bool,
+    root: [u8; 32],
+) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let clock = Clock::get()?;
+
+    // An empty root would match every user account that was never verified
+    require!(
+        !enabled || root != [0; 32],
+        VaultError::InvalidParameterUpdate
+    );
+
+    vault_state.allowlist_enabled = u8::from(enabled);
+    vault_state.allowlist_root = root;
+
+    // Emit event
+    emit!(AllowlistUpdated {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        admin: ctx.accounts.admin.key(),
+        enabled,
+        root,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Allowlist {}", if enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+pub fn update_vault_params(
+    ctx: Context<UpdateVaultParams>,
+    target_leverage: Option<u8>,
+    rebalance_threshold: Option<u16>,
+    max_slippage: Option<u16>,
+    revert_on_slippage: Option<bool>,
+    lockup_period: Option<i64>,
+    early_exit_fee_bps: Option<u16>,
+    referral_fee_share_bps: Option<u16>,
+) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let clock = Clock::get()?;
+
+    // Update parameters if provided
+    if let Some(leverage) = target_leverage {
+        require!(leverage > 0, VaultError::InvalidLeverage);
+        require_at_most!(leverage, MAX_LEVERAGE, VaultError::InvalidLeverage);
+        vault_state.target_leverage = leverage;
+    }
+
+    if let Some(threshold) = rebalance_threshold {
+        require_at_least!(
+            threshold,
+            MIN_REBALANCE_THRESHOLD_BPS,
+            VaultError::InvalidRebalanceThreshold
+        );
+        require_at_most!(
+            threshold,
+            MAX_REBALANCE_THRESHOLD_BPS,
+            VaultError::InvalidRebalanceThreshold
+        );
+        vault_state.rebalance_threshold = threshold;
+        vault_state.delta_threshold = threshold;
+    }
+
+    if let Some(slippage) = max_slippage {
+        require_at_most!(
+            slippage,
+            MAX_SLIPPAGE_BPS,
+            VaultError::InvalidSlippage
+        );
+        vault_state.max_slippage = slippage;
+    }
+
+    if let Some(revert) = revert_on_slippage {
+        vault_state.revert_on_slippage = u8::from(revert);
+    }
+
+    if let Some(lockup) = lockup_period {
+        require_at_least!(lockup, 0, VaultError::InvalidParameterUpdate);
+        require_at_most!(lockup, MAX_LOCKUP_PERIOD, VaultError::InvalidParameterUpdate);
+        vault_state.lockup_period = lockup;
+    }
+
+    if let Some(fee_bps) = early_exit_fee_bps {
+        require_at_most!(
+            fee_bps,
+            MAX_EARLY_EXIT_FEE_BPS,
+            VaultError::InvalidFeeStructure
+        );
+        vault_state.early_exit_fee_bps = fee_bps;
+    }
+
+    if let Some(share_bps) = referral_fee_share_bps {
+        require_at_most!(
+            share_bps,
+            MAX_REFERRAL_FEE_SHARE_BPS,
+            VaultError::InvalidFeeStructure
+        );
+        vault_state.referral_fee_share_bps = share_bps;
+    }
+
+    // Emit event
+    emit!(VaultParamsUpdated {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        admin: ctx.accounts.admin.key(),
+        target_leverage,
+        rebalance_threshold,
+        max_slippage,
+        revert_on_slippage,
+        lockup_period,
+        early_exit_fee_bps,
+        referral_fee_share_bps,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Vault parameters updated successfully");
+    Ok(())
+}
+
+pub fn migrate_vault(ctx: Context<MigrateVault>) -> Result<()> {
+    let vault_info = ctx.accounts.vault_state.to_account_info();
+    let clock = Clock::get()?;
+
+    let from_version = vault_state_version(&vault_info.try_borrow_data()?)?;
+    require!(
+        from_version < CURRENT_VAULT_VERSION,
+        VaultError::VaultAlreadyMigrated
+    );
+    require_keys_eq!(
+        vault_state_admin(&vault_info.try_borrow_data()?, from_version)?,
+        ctx.accounts.admin.key(),
+        VaultError::UnauthorizedAccess
+    );
+
+    // The admin tops up rent for the larger layout
+    let rent_due = Rent::get()?
+        .minimum_balance(VaultState::LEN)
+        .saturating_sub(vault_info.lamports());
+    if rent_due > 0 {
+        let transfer_ctx = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            anchor_lang::system_program::Transfer {
+                from: ctx.accounts.admin.to_account_info(),
+                to: vault_info.clone(),
+            },
+        );
+        anchor_lang::system_program::transfer(transfer_ctx, rent_due)?;
+    }
+
+    vault_info.realloc(VaultState::LEN, true)?;
+    upgrade_vault_state(&mut vault_info.try_borrow_mut_data()?, from_version)?;
+    let vault_loader = AccountLoader::<VaultState>::try_from(&vault_info)?;
+    let mut vault_state = vault_loader.load_mut()?;
+    // Earlier layouts had no fee checkpoint, so fees accrue from the migration
+    vault_state.reset_fee_checkpoint(clock.unix_timestamp)?;
+    let sequence = vault_state.next_event_sequence();
+    let vault_id = vault_state.vault_id;
+    drop(vault_state);
+
+    // Baseline vaults become vault 0 of their admin
+    let registry_entry = &mut ctx.accounts.registry_entry;
+    if registry_entry.vault == Pubkey::default() {
+        registry_entry.vault = vault_info.key();
+        registry_entry.admin = ctx.accounts.admin.key();
+        registry_entry.vault_id = vault_id;
+        registry_entry.registered_at = clock.unix_timestamp;
+        registry_entry.bump = ctx.bumps.registry_entry;
+    }
+
+    emit!(VaultMigrated {
+        vault: vault_info.key(),
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence,
+        admin: ctx.accounts.admin.key(),
+        from_version,
+        to_version: CURRENT_VAULT_VERSION,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Vault migrated from v{} to v{}", from_version, CURRENT_VAULT_VERSION);
+    Ok(())
+}
+
+pub fn open_position<'info>(
+    ctx: Context<'_, '_, '_, 'info, OpenPosition<'info>>,
+    market_index: u16,
+    size: u64,
+    direction: u8,
+) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
     let clock = Clock::get()?;
 
     // Check if vault is in emergency stop
-    require!(!vault_state.emergency_stop, DeltaNeutralVaultError::EmergencyStopActive);
+    require!(!vault_state.is_emergency_stopped(), VaultError::EmergencyStopActive);
 
     // Validate direction
-    let position_direction = match direction {
-        0 => PositionDirection::Long,
-        1 => PositionDirection::Short,
-        _ => return err!(DeltaNeutralVaultError::InvalidPositionDirection),
+    let position_direction = PositionDirection::try_from(direction)?;
+
+    // Each market trades from a single sub-account
+    let sub_account = vault_state
+        .drift_sub_account_slot(&ctx.accounts.drift_user.key())
+        .ok_or(VaultError::UnknownSubAccount)?;
+    vault_state.route_market(market_index, sub_account)?;
+
+    let max_ts = clock.unix_timestamp.safe_add(ORDER_TIME_TO_LIVE)?;
+    let oracle_price = oracle_price_for_market(&ctx.accounts.perp_market, market_index)?;
+    let position_before = perp_position_snapshot(&ctx.accounts.drift_user, market_index)?;
+    let mut order = OpenOrder {
+        order_id: 0,
+        market_index,
+        direction: position_direction as u8,
+        reduce_only: 0,
+        base_asset_amount: size,
+        filled_amount: 0,
+        quote_filled: 0,
+        oracle_price,
+        max_ts,
     };
 
-    // Place order on Drift
-    place_drift_order(
+    // Place order on Drift; the vault signs, so its data borrow ends first
+    let max_slippage = vault_state.max_slippage;
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+    order.order_id = place_drift_order(
         &ctx.accounts.drift_program,
+        &ctx.accounts.drift_state,
         &ctx.accounts.drift_user,
         &ctx.accounts.drift_user_stats,
-        &ctx.accounts.drift_state,
-        size,
-        position_direction,
-        vault_state.max_slippage,
+        &ctx.accounts.vault_state.to_account_info(),
+        &ctx.accounts.perp_market,
+        ctx.remaining_accounts,
+        signer,
+        &order,
+        max_slippage,
     )?;
+    let order_id = order.order_id;
 
-    // Update vault state
-    match position_direction {
-        PositionDirection::Long => {
-            vault_state.long_position += size as i64;
-        },
-        PositionDirection::Short => {
-            vault_state.short_position += size as i64;
-        }
-    }
+    // Track the order; positions are updated as fills come in
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    vault_state.track_order(order)?;
+    settle_immediate_fill(&mut vault_state, vault_key, &ctx.accounts.drift_user, order_id, market_index, position_before)?;
+    record_trading_fees(&mut vault_state, &ctx.accounts.drift_user_stats, market_index)?;
 
     // Emit event
     emit!(PositionOpened {
-        vault: vault_state.key(),
-        market: Pubkey::default(), // Would need market address
-        direction: format!("{:?}", position_direction),
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        market: ctx.accounts.perp_market.key(),
+        market_index,
+        order_id,
+        direction: position_direction,
         size,
-        price: 0, // Would need to get from Drift
+        oracle_price,
         timestamp: clock.unix_timestamp,
     });
 
-    msg!("Position opened: {} {} on market {}", size, format!("{:?}", position_direction), market_index);
+    msg!("Position opened: {} {:?} on market {}", size, position_direction, market_index);
     Ok(())
 }
 
-pub fn close_position(
-    ctx: Context<ClosePosition>,
+pub fn close_position<'info>(
+    ctx: Context<'_, '_, '_, 'info, ClosePosition<'info>>,
     market_index: u16,
     size: u64,
 ) -> Result<()> {
-    let vault_state = &mut ctx.accounts.vault_state;
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
     let clock = Clock::get()?;
 
     // Check if vault is in emergency stop
-    require!(!vault_state.emergency_stop, DeltaNeutralVaultError::EmergencyStopActive);
-
-    // Determine position direction based on current positions
-    let position_direction = if vault_state.long_position > 0 {
-        PositionDirection::Long
-    } else if vault_state.short_position > 0 {
-        PositionDirection::Short
-    } else {
-        return err!(DeltaNeutralVaultError::NoPositionToClose);
+    require!(!vault_state.is_emergency_stopped(), VaultError::EmergencyStopActive);
+
+    // Reduce this market's booked position, never past flat
+    let (position_direction, size) = vault_state.position_to_close(market_index, size)?;
+
+    // Reduce from the sub-account holding the market
+    let sub_account = vault_state
+        .drift_sub_account_slot(&ctx.accounts.drift_user.key())
+        .ok_or(VaultError::UnknownSubAccount)?;
+    vault_state.route_market(market_index, sub_account)?;
+
+    let max_ts = clock.unix_timestamp.safe_add(ORDER_TIME_TO_LIVE)?;
+    let oracle_price = oracle_price_for_market(&ctx.accounts.perp_market, market_index)?;
+    let position_before = perp_position_snapshot(&ctx.accounts.drift_user, market_index)?;
+    let mut order = OpenOrder {
+        order_id: 0,
+        market_index,
+        direction: position_direction.opposite() as u8,
+        reduce_only: 1,
+        base_asset_amount: size,
+        filled_amount: 0,
+        quote_filled: 0,
+        oracle_price,
+        max_ts,
     };
 
-    // Close position on Drift
-    close_drift_position(
+    // Close position on Drift; the vault signs, so its data borrow ends first
+    let max_slippage = vault_state.max_slippage;
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+    order.order_id = place_drift_order(
         &ctx.accounts.drift_program,
+        &ctx.accounts.drift_state,
         &ctx.accounts.drift_user,
         &ctx.accounts.drift_user_stats,
-        &ctx.accounts.drift_state,
-        size,
-        position_direction,
-        vault_state.max_slippage,
+        &ctx.accounts.vault_state.to_account_info(),
+        &ctx.accounts.perp_market,
+        ctx.remaining_accounts,
+        signer,
+        &order,
+        max_slippage,
     )?;
+    let order_id = order.order_id;
 
-    // Update vault state
-    match position_direction {
-        PositionDirection::Long => {
-            vault_state.long_position -= size as i64;
-        },
-        PositionDirection::Short => {
-            vault_state.short_position -= size as i64;
-        }
-    }
+    // Track the reduce-only order; positions are updated as fills come in
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    vault_state.track_order(order)?;
+    let realized_pnl = settle_immediate_fill(&mut vault_state, vault_key, &ctx.accounts.drift_user, order_id, market_index, position_before)?;
+    let fees_paid = record_trading_fees(&mut vault_state, &ctx.accounts.drift_user_stats, market_index)?;
+    let book = *vault_state.market_book_mut(market_index)?;
 
     // Emit event
     emit!(PositionClosed {
-        vault: vault_state.key(),
-        market: Pubkey::default(), // Would need market address
-        direction: format!("{:?}", position_direction),
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        market: ctx.accounts.perp_market.key(),
+        market_index,
+        order_id,
+        direction: position_direction,
         size,
-        price: 0, // Would need to get from Drift
-        pnl: 0, // Would need to calculate from Drift
+        oracle_price,
+        pnl: realized_pnl,
+        fees_paid,
+        average_entry_price: book.average_entry_price,
+        cumulative_realized_pnl: book.realized_pnl,
+        cumulative_funding: book.cumulative_funding,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Position closed: {} {:?} on market {}", size, position_direction, market_index);
+    Ok(())
+}
+
+pub fn sync_positions(ctx: Context<SyncPositions>) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let clock = Clock::get()?;
+
+    let sub_account = vault_state
+        .drift_sub_account_slot(&ctx.accounts.drift_user.key())
+        .ok_or(VaultError::UnknownSubAccount)?;
+
+    // Record fills first so later refreshes don't book them a second time
+    refresh_open_orders(&mut vault_state, vault_key, &ctx.accounts.drift_user)?;
+
+    let old_long_position = vault_state.long_position;
+    let old_short_position = vault_state.short_position;
+
+    {
+        let user_loader = AccountLoader::<User>::try_from(&ctx.accounts.drift_user.to_account_info())?;
+        let drift_user = user_loader.load()?;
+
+        // Rebuild the book from every perp market the Drift user holds
+        let market_indices: Vec<u16> = drift_user
+            .perp_positions
+            .iter()
+            .filter(|p| p.base_asset_amount != 0)
+            .map(|p| p.market_index)
+            .collect();
+
+        sync_vault_positions(&mut vault_state, sub_account, &drift_user, &market_indices, clock.slot)?;
+
+        // Attribute funding settled since the last sync and align each book
+        // this sub-account trades
+        let market_sub_accounts = vault_state.market_sub_accounts;
+        for (slot, book) in vault_state.market_books.iter_mut().enumerate() {
+            if !book.is_initialized() || market_sub_accounts[slot] as usize != sub_account {
+                continue;
+            }
+            let position = drift_user
+                .perp_positions
+                .iter()
+                .find(|p| p.market_index == book.market_index && !p.is_available());
+
+            match position {
+                Some(position) => {
+                    if book.base_position != 0 && book.last_funding_rate != 0 {
+                        let funding = calculate_funding_payment(
+                            book.base_position,
+                            book.last_funding_rate,
+                            position.last_cumulative_funding_rate,
+                        )?;
+                        book.cumulative_funding = book.cumulative_funding.safe_add(funding)?;
+                    }
+                    book.base_position = position.base_asset_amount;
+                    book.last_funding_rate = position.last_cumulative_funding_rate;
+                },
+                None => {
+                    book.base_position = 0;
+                    book.last_funding_rate = 0;
+                }
+            }
+        }
+    }
+
+    // Report each leg so offsetting mismatches don't cancel out
+    let long_discrepancy = vault_state.long_position.safe_sub(old_long_position)?;
+    let short_discrepancy = vault_state.short_position.safe_sub(old_short_position)?;
+
+    // Emit event
+    emit!(PositionUpdatedEvent {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        drift_user: ctx.accounts.drift_user.key(),
+        old_long_position,
+        old_short_position,
+        new_long_position: vault_state.long_position,
+        new_short_position: vault_state.short_position,
+        long_discrepancy,
+        short_discrepancy,
+        slot: clock.slot,
         timestamp: clock.unix_timestamp,
     });
 
-    msg!("Position closed: {} {} on market {}", size, format!("{:?}", position_direction), market_index);
+    msg!("Positions synced: long {} -> {}, short {} -> {}",
+         old_long_position, { vault_state.long_position },
+         old_short_position, { vault_state.short_position });
+    Ok(())
+}
+
+pub fn add_drift_sub_account(ctx: Context<AddDriftSubAccount>, sub_account_id: u16) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let clock = Clock::get()?;
+
+    // The vault is the Drift authority and signs the CPI, so no data borrow is held over it
+    let (admin_key, vault_id, bump, drift_user_stats) = {
+        let vault_state = ctx.accounts.vault_state.load()?;
+        (
+            vault_state.admin,
+            vault_state.vault_id_seed(),
+            vault_state.bump,
+            vault_state.drift_user_stats,
+        )
+    };
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+
+    // Drift only creates users under an authority that already has user
+    // stats. The first user creates them, later ones must reuse them.
+    let creates_user_stats = drift_user_stats == Pubkey::default();
+    if creates_user_stats {
+        initialize_drift_user_stats(
+            &ctx.accounts.drift_program,
+            &ctx.accounts.drift_user_stats,
+            &ctx.accounts.drift_state,
+            &ctx.accounts.vault_state.to_account_info(),
+            &ctx.accounts.admin.to_account_info(),
+            &ctx.accounts.rent.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            signer,
+        )?;
+    } else {
+        require_keys_eq!(
+            ctx.accounts.drift_user_stats.key(),
+            drift_user_stats,
+            VaultError::DriftUserStatsMismatch
+        );
+    }
+
+    let cpi_ctx = CpiContext::new_with_signer(
+        ctx.accounts.drift_program.to_account_info(),
+        InitializeUser {
+            user: ctx.accounts.drift_user.to_account_info(),
+            user_stats: ctx.accounts.drift_user_stats.to_account_info(),
+            state: ctx.accounts.drift_state.to_account_info(),
+            authority: ctx.accounts.vault_state.to_account_info(),
+            payer: ctx.accounts.admin.to_account_info(),
+            rent: ctx.accounts.rent.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+        },
+        signer,
+    );
+    drift::cpi::initialize_user(cpi_ctx, sub_account_id, None)?;
+
+    // A new user holds no positions, so its book starts out in sync
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    if creates_user_stats {
+        vault_state.drift_user_authority = vault_key;
+        vault_state.drift_user_stats = ctx.accounts.drift_user_stats.key();
+    }
+    let slot = vault_state.add_drift_sub_account(ctx.accounts.drift_user.key())?;
+    vault_state.sync_sub_account(slot, 0, 0, clock.slot)?;
+
+    emit!(DriftSubAccountAdded {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        drift_user: ctx.accounts.drift_user.key(),
+        sub_account_id,
+        slot: slot as u8,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Drift sub-account {} added in slot {}", sub_account_id, slot);
+    Ok(())
+}
+
+pub fn transfer_sub_account_collateral<'info>(
+    ctx: Context<'_, '_, '_, 'info, TransferSubAccountCollateral<'info>>,
+    spot_market_index: u16,
+    amount: u64,
+) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let clock = Clock::get()?;
+
+    require!(amount > 0, VaultError::InvalidAmount);
+    require_keys_neq!(
+        ctx.accounts.from_user.key(),
+        ctx.accounts.to_user.key(),
+        VaultError::InvalidParameterUpdate
+    );
+
+    // Collateral stays with the vault, so NAV is unchanged. The vault signs
+    // the transfer, so its data borrow ends first.
+    let (admin_key, vault_id, bump, sequence) = {
+        let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+        (
+            vault_state.admin,
+            vault_state.vault_id_seed(),
+            vault_state.bump,
+            vault_state.next_event_sequence(),
+        )
+    };
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+
+    let cpi_ctx = CpiContext::new_with_signer(
+        ctx.accounts.drift_program.to_account_info(),
+        TransferDeposit {
+            from_user: ctx.accounts.from_user.to_account_info(),
+            to_user: ctx.accounts.to_user.to_account_info(),
+            user_stats: ctx.accounts.drift_user_stats.to_account_info(),
+            authority: ctx.accounts.vault_state.to_account_info(),
+            state: ctx.accounts.drift_state.to_account_info(),
+            spot_market_vault: ctx.accounts.spot_market_vault.to_account_info(),
+        },
+        signer,
+    )
+    .with_remaining_accounts(ctx.remaining_accounts.to_vec());
+    drift::cpi::transfer_deposit(cpi_ctx, spot_market_index, amount)?;
+
+    emit!(SubAccountCollateralTransferred {
+        vault: vault_key,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence,
+        from_user: ctx.accounts.from_user.key(),
+        to_user: ctx.accounts.to_user.key(),
+        spot_market_index,
+        amount,
+        timestamp: clock.unix_timestamp,
+    });
+
+    msg!("Moved {} of spot market {} between sub-accounts", amount, spot_market_index);
+    Ok(())
+}
+
+pub fn cancel_stale_orders(ctx: Context<CancelStaleOrders>) -> Result<()> {
+    let vault_key = ctx.accounts.vault_state.key();
+    let mut vault_state = ctx.accounts.vault_state.load_mut()?;
+    let clock = Clock::get()?;
+
+    // Book fills first so cancelled orders keep whatever size they did fill
+    refresh_open_orders(&mut vault_state, vault_key, &ctx.accounts.drift_user)?;
+
+    // Forget this sub-account's stale orders up front; the vault signs the
+    // cancels below, so its data borrow is released before any CPI
+    let sub_account = vault_state
+        .drift_sub_account_slot(&ctx.accounts.drift_user.key())
+        .ok_or(VaultError::UnknownSubAccount)?;
+    let stale_orders = vault_state.take_stale_orders(sub_account, clock.unix_timestamp);
+
+    let admin_key = vault_state.admin;
+    let vault_id = vault_state.vault_id_seed();
+    let bump = vault_state.bump;
+    drop(vault_state);
+    let signer_seeds = &[
+        VAULT_SEED,
+        admin_key.as_ref(),
+        vault_id.as_slice(),
+        &[bump],
+    ];
+    let signer = &[&signer_seeds[..]];
+
+    for (order, sequence) in stale_orders.iter() {
+        // Orders Drift has already closed out only need to be forgotten
+        if is_drift_order_open(&ctx.accounts.drift_user, order.order_id)? {
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.drift_program.to_account_info(),
+                CancelOrder {
+                    state: ctx.accounts.drift_state.to_account_info(),
+                    user: ctx.accounts.drift_user.to_account_info(),
+                    authority: ctx.accounts.vault_state.to_account_info(),
+                },
+                signer,
+            );
+            drift::cpi::cancel_order(cpi_ctx, Some(order.order_id))?;
+        }
+
+        emit!(StaleOrderCancelled {
+            vault: vault_key,
+            schema_version: EVENT_SCHEMA_VERSION,
+            sequence: *sequence,
+            order_id: order.order_id,
+            market_index: order.market_index,
+            base_asset_amount: order.base_asset_amount,
+            filled_amount: order.filled_amount,
+            max_ts: order.max_ts,
+            timestamp: clock.unix_timestamp,
+        });
+    }
+
+    msg!("Cancelled {} stale orders", stale_orders.len());
     Ok(())
 }
 
 // Helper functions for Drift integration
-fn place_drift_order(
-    drift_program: &Account<Drift>,
-    drift_user: &AccountInfo,
-    drift_user_stats: &AccountInfo,
-    drift_state: &AccountInfo,
-    size: u64,
-    direction: PositionDirection,
+
+/// Place a vault-signed perp limit order on Drift for `order`, priced off its
+/// oracle price with the vault's slippage allowance. The vault's data borrow
+/// must be released before calling. Returns the id Drift assigned.
+#[allow(clippy::too_many_arguments)]
+fn place_drift_order<'info>(
+    drift_program: &Program<'info, Drift>,
+    drift_state: &AccountInfo<'info>,
+    drift_user: &AccountInfo<'info>,
+    drift_user_stats: &AccountInfo<'info>,
+    vault_state: &AccountInfo<'info>,
+    perp_market: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+    signer: &[&[&[u8]]],
+    order: &OpenOrder,
     max_slippage: u16,
+) -> Result<u32> {
+    // Drift assigns the user's next order id to the order being placed
+    let order_id = next_drift_order_id(drift_user)?;
+
+    let slippage = bps_of(order.oracle_price, max_slippage, Rounding::Down)?;
+    let (direction, limit_price) = match order.direction()? {
+        PositionDirection::Long => (DriftPositionDirection::Long, order.oracle_price.safe_add(slippage)?),
+        PositionDirection::Short => (DriftPositionDirection::Short, order.oracle_price.safe_sub(slippage)?),
+    };
+    let order_params = OrderParams {
+        order_type: OrderType::Limit,
+        market_type: MarketType::Perp,
+        direction,
+        user_order_id: 0,
+        base_asset_amount: order.base_asset_amount,
+        price: Some(limit_price),
+        market_index: order.market_index,
+        reduce_only: order.is_reduce_only(),
+        post_only: PostOnlyParam::None,
+        immediate_or_cancel: false,
+        max_ts: Some(order.max_ts),
+        trigger_price: None,
+        trigger_condition: OrderTriggerCondition::Above,
+        oracle_price_offset: None,
+        auction_duration: None,
+        auction_start_price: None,
+        auction_end_price: None,
+    };
+
+    // Drift loads the market, its oracle and spot markets from remaining accounts
+    let mut market_accounts = vec![perp_market.clone()];
+    market_accounts.extend_from_slice(remaining_accounts);
+    let cpi_ctx = CpiContext::new_with_signer(
+        drift_program.to_account_info(),
+        PlaceOrder {
+            state: drift_state.clone(),
+            user: drift_user.clone(),
+            user_stats: drift_user_stats.clone(),
+            authority: vault_state.clone(),
+        },
+        signer,
+    )
+    .with_remaining_accounts(market_accounts);
+    drift::cpi::place_perp_order(cpi_ctx, order_params)?;
+
+    msg!(
+        "Placed Drift order {}: {} {:?} at limit {}, expires at {}",
+        order_id,
+        { order.base_asset_amount },
+        order.direction()?,
+        limit_price,
+        { order.max_ts }
+    );
+    Ok(order_id)
+}
+
+fn next_drift_order_id(drift_user: &AccountInfo) -> Result<u32> {
+    let user_loader = AccountLoader::<User>::try_from(drift_user)?;
+    let user = user_loader.load()?;
+    Ok(user.next_order_id)
+}
+
+fn is_drift_order_open(drift_user: &AccountInfo, order_id: u32) -> Result<bool> {
+    let user_loader = AccountLoader::<User>::try_from(drift_user)?;
+    let user = user_loader.load()?;
+    Ok(user
+        .orders
+        .iter()
+        .any(|o| o.order_id == order_id && o.status == OrderStatus::Open))
+}
+
+/// Pull filled sizes for every order tracked on a sub-account from its Drift
+/// user account. Orders Drift has closed out stop being tracked once their
+/// final fill is booked.
+pub(crate) fn refresh_open_orders(
+    vault_state: &mut VaultState,
+    vault: Pubkey,
+    drift_user: &AccountInfo,
 ) -> Result<()> {
-    // This would implement the actual Drift order placement
-    // For now, just log the action
-    msg!("Placing Drift order: {} {:?} with max slippage {}", size, direction, max_slippage);
+    let sub_account = vault_state
+        .drift_sub_account_slot(&drift_user.key())
+        .ok_or(VaultError::UnknownSubAccount)?;
+    let user_loader = AccountLoader::<User>::try_from(drift_user)?;
+    let user = user_loader.load()?;
+
+    // Order ids are per Drift user, so only this sub-account's orders are matched
+    for slot in 0..MAX_OPEN_ORDERS {
+        let tracked = vault_state.open_orders[slot];
+        if tracked.is_empty() || !vault_state.is_order_on_sub_account(&tracked, sub_account) {
+            continue;
+        }
+
+        match user
+            .orders
+            .iter()
+            .find(|o| o.order_id == tracked.order_id && o.status != OrderStatus::Init)
+        {
+            Some(order) => {
+                // Later fills can no longer be undone, so they are only flagged
+                book_order_fill(
+                    vault_state,
+                    vault,
+                    slot,
+                    order.base_asset_amount_filled,
+                    order.quote_asset_amount_filled,
+                    false,
+                )?;
+                if order.status != OrderStatus::Open {
+                    vault_state.open_orders[slot] = OpenOrder::default();
+                }
+            },
+            None => {
+                // Drift resets cancelled, expired and filled orders alike, so
+                // whatever wasn't observed before the order vanished is only
+                // reported; sync_positions reconciles the book from Drift
+                let unobserved = tracked.base_asset_amount.saturating_sub(tracked.filled_amount);
+                if unobserved > 0 {
+                    let clock = Clock::get()?;
+                    emit!(OrderFillUnobserved {
+                        vault,
+                        schema_version: EVENT_SCHEMA_VERSION,
+                        sequence: vault_state.next_event_sequence(),
+                        order_id: tracked.order_id,
+                        market_index: tracked.market_index,
+                        base_asset_amount: tracked.base_asset_amount,
+                        filled_amount: tracked.filled_amount,
+                        unobserved_amount: unobserved,
+                        timestamp: clock.unix_timestamp,
+                    });
+                }
+                vault_state.open_orders[slot] = OpenOrder::default();
+            }
+        }
+    }
+
     Ok(())
 }
 
-fn close_drift_position(
-    drift_program: &Account<Drift>,
+/// Book the part of a just-placed order that filled inside this transaction,
+/// measured from the change in the vault's Drift perp position. Returns the
+/// PnL realized by the fill.
+fn settle_immediate_fill(
+    vault_state: &mut VaultState,
+    vault: Pubkey,
     drift_user: &AccountInfo,
-    drift_user_stats: &AccountInfo,
-    drift_state: &AccountInfo,
-    size: u64,
-    direction: PositionDirection,
-    max_slippage: u16,
+    order_id: u32,
+    market_index: u16,
+    position_before: (i64, i64),
+) -> Result<i64> {
+    let (base_after, quote_after) = perp_position_snapshot(drift_user, market_index)?;
+    let base_filled = base_after.safe_sub(position_before.0)?.unsigned_abs();
+    let quote_filled = quote_after.safe_sub(position_before.1)?.unsigned_abs();
+
+    let slot = vault_state
+        .open_orders
+        .iter()
+        .position(|o| o.order_id == order_id)
+        .ok_or(VaultError::UnknownOrder)?;
+
+    let was_flat = vault_state
+        .market_book(market_index)
+        .map_or(true, |b| b.base_position == 0);
+    let realized_pnl = book_order_fill(vault_state, vault, slot, base_filled, quote_filled, true)?;
+
+    // Funding on a book the fill just opened accrues from the position's
+    // current cumulative rate, not from the first sync
+    if was_flat && base_filled > 0 {
+        let funding_rate = perp_funding_rate(drift_user, market_index)?;
+        vault_state.market_book_mut(market_index)?.last_funding_rate = funding_rate;
+    }
+    Ok(realized_pnl)
+}
+
+/// Book a fill into the position legs and the market's cost basis, then
+/// compare its price with the oracle price recorded when the order was
+/// placed. Slippage breaches are always reported; they only revert when
+/// `may_revert` is set and the vault is configured to revert. Returns the
+/// PnL realized by the fill.
+fn book_order_fill(
+    vault_state: &mut VaultState,
+    vault: Pubkey,
+    slot: usize,
+    base_filled: u64,
+    quote_filled: u64,
+    may_revert: bool,
+) -> Result<i64> {
+    let order = vault_state.open_orders[slot];
+    let fill_price = match vault_state.apply_fill(slot, base_filled, quote_filled)? {
+        Some(price) => price,
+        None => return Ok(0),
+    };
+
+    let new_fill = to_i64(base_filled.safe_sub(order.filled_amount)?)?;
+    let trade_base = match order.direction()? {
+        PositionDirection::Long => new_fill,
+        PositionDirection::Short => -new_fill,
+    };
+    let realized_pnl = vault_state.record_trade(order.market_index, trade_base, fill_price)?;
+
+    if is_within_slippage_tolerance(order.oracle_price, fill_price, vault_state.max_slippage) {
+        return Ok(realized_pnl);
+    }
+
+    let reverted = may_revert && vault_state.reverts_on_slippage();
+    emit!(SlippageExceeded {
+        vault,
+        schema_version: EVENT_SCHEMA_VERSION,
+        sequence: vault_state.next_event_sequence(),
+        order_id: order.order_id,
+        market_index: order.market_index,
+        expected_price: order.oracle_price,
+        actual_price: fill_price,
+        slippage_bps: calculate_slippage_bps(order.oracle_price, fill_price),
+        max_slippage_bps: vault_state.max_slippage,
+        reverted,
+        timestamp: Clock::get()?.unix_timestamp,
+    });
+
+    if reverted {
+        msg!(
+            "Order {} slippage {} bps exceeds max {} bps",
+            order.order_id,
+            calculate_slippage_bps(order.oracle_price, fill_price),
+            { vault_state.max_slippage }
+        );
+        return err!(VaultError::SlippageExceeded);
+    }
+
+    vault_state.slippage_breach_count = vault_state.slippage_breach_count.saturating_add(1);
+    msg!("Order {} filled at {} against oracle {}", order.order_id, fill_price, order.oracle_price);
+    Ok(realized_pnl)
+}
+
+/// Append a share-price snapshot to the vault's history
+fn record_history(
+    history: &AccountLoader<VaultHistory>,
+    vault_state: &VaultState,
+    timestamp: i64,
 ) -> Result<()> {
-    // This would implement the actual Drift position closing
-    // For now, just log the action
-    msg!("Closing Drift position: {} {:?} with max slippage {}", size, direction, max_slippage);
+    history.load_mut()?.record(HistorySnapshot::of(vault_state, timestamp)?);
     Ok(())
 }
 
-fn calculate_management_fees(
-    total_assets: u64,
-    management_fee_bps: u16,
-    time_elapsed: i64,
+/// Attribute Drift trading fees paid since the last observation to a market.
+/// Fees on resting fills are picked up by the next trade that observes them.
+fn record_trading_fees(
+    vault_state: &mut VaultState,
+    drift_user_stats: &AccountInfo,
+    market_index: u16,
 ) -> Result<u64> {
-    let annual_fee_rate = management_fee_bps as u128 * 1_000_000 / BASIS_POINTS_DIVISOR as u128;
-    let time_fraction = time_elapsed as u128 * 1_000_000 / SECONDS_PER_YEAR as u128;
-    let fee = (total_assets as u128 * annual_fee_rate * time_fraction) / 1_000_000_000_000;
-    Ok(fee as u64)
+    let total_fee_paid = {
+        let stats_loader = AccountLoader::<UserStats>::try_from(drift_user_stats)?;
+        let stats = stats_loader.load()?;
+        stats.fees.total_fee_paid
+    };
+
+    let fees_paid = total_fee_paid.saturating_sub(vault_state.last_total_fee_paid);
+    vault_state.last_total_fee_paid = total_fee_paid;
+    let book = vault_state.market_book_mut(market_index)?;
+    book.fees_paid = book.fees_paid.safe_add(fees_paid)?;
+    Ok(fees_paid)
+}
+
+/// Current base and quote amounts of the vault's Drift perp position
+fn perp_position_snapshot(drift_user: &AccountInfo, market_index: u16) -> Result<(i64, i64)> {
+    let user_loader = AccountLoader::<User>::try_from(drift_user)?;
+    let user = user_loader.load()?;
+    Ok(user
+        .perp_positions
+        .iter()
+        .find(|p| p.market_index == market_index && !p.is_available())
+        .map(|p| (p.base_asset_amount, p.quote_asset_amount))
+        .unwrap_or((0, 0)))
+}
+
+/// Cumulative funding rate last settled on the vault's Drift perp position
+fn perp_funding_rate(drift_user: &AccountInfo, market_index: u16) -> Result<i64> {
+    let user_loader = AccountLoader::<User>::try_from(drift_user)?;
+    let user = user_loader.load()?;
+    Ok(user
+        .perp_positions
+        .iter()
+        .find(|p| p.market_index == market_index && !p.is_available())
+        .map(|p| p.last_cumulative_funding_rate)
+        .unwrap_or(0))
+}
+
+pub(crate) fn oracle_price_for_market(perp_market: &AccountInfo, market_index: u16) -> Result<u64> {
+    let market_loader = AccountLoader::<PerpMarket>::try_from(perp_market)?;
+    let market = market_loader.load()?;
+    require_eq!(
+        market.market_index,
+        market_index,
+        VaultError::InvalidMarketIndex
+    );
+
+    let oracle_price = market.amm.historical_oracle_data.last_oracle_price;
+    if oracle_price <= 0 {
+        msg!("Perp market {} oracle price {}", market_index, oracle_price);
+        return err!(VaultError::InvalidOraclePrice);
+    }
+    Ok(oracle_price as u64)
+}
+
+/// Read a Drift spot market's index and mint
+pub(crate) fn spot_market_info(spot_market: &AccountInfo) -> Result<(u16, Pubkey)> {
+    let market_loader = AccountLoader::<SpotMarket>::try_from(spot_market)?;
+    let market = market_loader.load()?;
+    Ok((market.market_index, market.mint))
+}
+
+/// Read a Drift spot market's index and mint, and the live price from its
+/// oracle, rejecting stale or low-confidence readings
+pub(crate) fn spot_market_oracle_price(
+    spot_market: &AccountInfo,
+    oracle: &AccountInfo,
+    slot: u64,
+) -> Result<(u16, Pubkey, u64)> {
+    let market_loader = AccountLoader::<SpotMarket>::try_from(spot_market)?;
+    let market = market_loader.load()?;
+    require_keys_eq!(oracle.key(), market.oracle, VaultError::InvalidOracleAccount);
+
+    let oracle_source = market.oracle_source;
+    let price_data = drift::state::oracle::get_oracle_price(&oracle_source, oracle, slot)
+        .map_err(|_| VaultError::InvalidOraclePrice)?;
+    let oracle_price = validate_oracle_price(price_data.price, price_data.confidence, price_data.delay)
+        .map_err(|e| {
+            msg!(
+                "Spot market {} oracle price {} confidence {} delay {}",
+                { market.market_index },
+                price_data.price,
+                price_data.confidence,
+                price_data.delay
+            );
+            e
+        })?;
+    Ok((market.market_index, market.mint, oracle_price))
+}
+
+/// Create the owner's position account on first deposit and enforce the allowlist
+fn admit_depositor(
+    vault_state: &VaultState,
+    vault: Pubkey,
+    vault_user: &mut VaultUser,
+    owner: Pubkey,
+    vault_user_bump: u8,
+    allowlist_proof: Option<AllowlistProof>,
+) -> Result<()> {
+    if vault_user.user == Pubkey::default() {
+        vault_user.user = owner;
+        vault_user.vault = vault;
+        vault_user.bump = vault_user_bump;
+    }
+
+    // Permissioned vaults only credit shares to allowlisted owners
+    if vault_state.is_allowlist_enabled() && !vault_user.is_allowlisted(vault_state) {
+        let proof = allowlist_proof.ok_or(VaultError::NotAllowlisted)?;
+        let leaf = allowlist_leaf(&owner, proof.deposit_cap);
+        require!(
+            verify_merkle_proof(&proof.proof, vault_state.allowlist_root, leaf),
+            VaultError::NotAllowlisted
+        );
+
+        // Later deposits skip the proof until the admin rotates the root
+        vault_user.allowlist_root = vault_state.allowlist_root;
+        vault_user.deposit_cap = proof.deposit_cap;
+    }
+
+    Ok(())
+}
+
+/// Attach a referrer on the user's first deposit and keep the referred share
+/// totals in step. Users with a referrer must pass its `ReferrerAccount`.
+fn update_referred_shares(
+    vault_state: &mut VaultState,
+    vault_user: &mut VaultUser,
+    referrer_account: Option<&mut Account<ReferrerAccount>>,
+    owner_consents: bool,
+    shares_added: u64,
+    shares_removed: u64,
+) -> Result<()> {
+    // The referrer is fixed by the first deposit, later ones cannot change it.
+    // Only the owner, depositing or co-signing, may bind it.
+    if !vault_user.has_referrer() && vault_user.total_deposits == 0 {
+        if let Some(referrer_account) = referrer_account.as_ref() {
+            require!(owner_consents, VaultError::InvalidReferrer);
+            require!(
+                referrer_account.referrer != vault_user.user,
+                VaultError::InvalidReferrer
+            );
+            vault_user.referrer = referrer_account.referrer;
+        }
+    }
+
+    if !vault_user.has_referrer() {
+        return Ok(());
+    }
+
+    let referrer_account = referrer_account.ok_or(VaultError::InvalidReferrer)?;
+    require!(
+        referrer_account.referrer == vault_user.referrer,
+        VaultError::InvalidReferrer
+    );
+
+    referrer_account.update_shares(
+        vault_state.referral_fees_per_share,
+        shares_added,
+        shares_removed,
+    )?;
+    vault_state.referred_shares = vault_state
+        .referred_shares
+        .safe_add(shares_added)?
+        .safe_sub(shares_removed)?;
+    Ok(())
+}
+
+/// Deposits are refused during an emergency stop, and while collateral
+/// values are too old to price shares off NAV
+fn check_deposit_open(vault_state: &VaultState, slot: u64) -> Result<()> {
+    require!(!vault_state.is_emergency_stopped(), VaultError::EmergencyStopActive);
+    require!(
+        vault_state.is_collateral_fresh(slot),
+        VaultError::CollateralValuationStale
+    );
+    Ok(())
 }
 
-fn calculate_performance_fees(
-    total_assets: u64,
-    performance_fee_bps: u16,
-    net_deposits: i64,
+/// Shares minted for `value` once the deposit limits pass, refused below
+/// `min_shares_out`. The caller records the inflow against the epoch limit.
+fn price_deposit(
+    vault_state: &VaultState,
+    vault_user: &VaultUser,
+    value: u64,
+    current_time: i64,
+    min_shares_out: u64,
 ) -> Result<u64> {
-    if net_deposits <= 0 {
+    check_deposit_limits(vault_state, vault_user, value, current_time)?;
+    // Callers cannot see the share price they will get, so they bound it
+    vault_state.shares_for_deposit_at_least(value, min_shares_out)
+}
+
+/// Token-2022 transfer fee withheld when `amount` of `mint` moves in
+/// `epoch`, zero for mints without the extension
+fn transfer_fee(mint: &AccountInfo, amount: u64, epoch: u64) -> Result<u64> {
+    if *mint.owner != spl_token_2022::ID {
         return Ok(0);
     }
-    
-    let performance = total_assets as i64 - net_deposits;
-    if performance <= 0 {
-        return Ok(0);
+
+    let data = mint.try_borrow_data()?;
+    let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+    match mint.get_extension::<TransferFeeConfig>() {
+        Ok(config) => config
+            .calculate_epoch_fee(epoch, amount)
+            .ok_or_else(|| error!(VaultError::MathOverflow)),
+        Err(_) => Ok(0),
     }
-    
-    let fee = (performance as u128 * performance_fee_bps as u128) / BASIS_POINTS_DIVISOR as u128;
-    Ok(fee as u64)
-}
\ No newline at end of file
+}
+
+/// Enforce capacity, per-user caps and the epoch inflow limit for a deposit worth `value`
+fn check_deposit_limits(
+    vault_state: &VaultState,
+    vault_user: &VaultUser,
+    value: u64,
+    current_time: i64,
+) -> Result<()> {
+    let user_net_deposits = vault_user.net_deposits().safe_add(value)?;
+
+    // Allowlisted depositors are held to the cap in their leaf
+    if vault_state.is_allowlist_enabled() {
+        require_at_most!(
+            user_net_deposits,
+            vault_user.deposit_cap,
+            VaultError::DepositCapExceeded
+        );
+    }
+
+    // Check vault capacity
+    let total_value_after = vault_state.calculate_total_value()?.safe_add(value)?;
+    require_at_most!(
+        total_value_after,
+        vault_state.max_capacity,
+        VaultError::VaultAtCapacity
+    );
+
+    // Check the per-user cap
+    if vault_state.max_user_deposit > 0 {
+        require_at_most!(
+            user_net_deposits,
+            vault_state.max_user_deposit,
+            VaultError::DepositCapExceeded
+        );
+    }
+
+    // Check the epoch inflow limit
+    vault_state.check_flow(current_time, to_i64(value)?)?;
+
+    // The first deposit must be large enough to anchor the share price
+    if vault_state.total_shares == 0 {
+        require_at_least!(
+            value,
+            MIN_INITIAL_DEPOSIT,
+            VaultError::DepositBelowMinimum
+        );
+    }
+
+    Ok(())
+}
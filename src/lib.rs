@@ -1,5 +1,5 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Token, TokenAccount, Mint};
+use anchor_spl::token_interface::{self, TokenInterface};
 use drift::cpi::accounts::{PlaceOrder, CancelOrder};
 use drift::program::Drift;
 use drift::state::{UserStats, User, Order, OrderType, OrderStatus, MarketType, PositionDirection};
@@ -12,6 +12,9 @@ pub mod instructions;
 pub mod utils;
 pub mod events;
 pub mod constants;
+pub mod drift_integration;
+pub mod math;
+pub mod migrations;
 
 use errors::*;
 use state::*;
@@ -23,30 +26,82 @@ use constants::*;
 pub mod delta_neutral_vault {
     use super::*;
 
+    /// Initialize the protocol-wide config (once per program, upgrade authority only)
+    /// 
+    /// # Arguments
+    /// * `protocol_fee_bps` - Share of each vault's fees taken by the protocol (basis points)
+    pub fn initialize_protocol(ctx: Context<InitializeProtocol>, protocol_fee_bps: u16) -> Result<()> {
+        instructions::initialize_protocol(ctx, protocol_fee_bps)
+    }
+
+    /// Update the protocol-wide config (protocol owner only)
+    /// 
+    /// # Arguments
+    /// * `new_owner` - New protocol owner
+    /// * `protocol_fee_bps` - New protocol fee share (basis points)
+    /// * `paused` - Pause or resume every vault
+    pub fn update_protocol_config(
+        ctx: Context<UpdateProtocolConfig>,
+        new_owner: Option<Pubkey>,
+        protocol_fee_bps: Option<u16>,
+        paused: Option<bool>,
+    ) -> Result<()> {
+        instructions::update_protocol_config(ctx, new_owner, protocol_fee_bps, paused)
+    }
+
     /// Initialize a new delta-neutral vault
     /// 
     /// # Arguments
+    /// * `vault_id` - Index of the vault under this admin, part of the vault PDA
+    ///   seeds. Vault 0 keeps the pre-`vault_id` address `[VAULT_SEED, admin]`.
     /// * `target_leverage` - Target leverage ratio (1-10x)
     /// * `rebalance_threshold` - Threshold to trigger rebalancing (basis points)
     /// * `max_slippage` - Maximum allowed slippage (basis points)
     pub fn initialize_vault(
         ctx: Context<InitializeVault>,
+        vault_id: u64,
         target_leverage: u8,
         rebalance_threshold: u16,
         max_slippage: u16,
     ) -> Result<()> {
-        instructions::initialize_vault(ctx, target_leverage, rebalance_threshold, max_slippage)
+        instructions::initialize_vault(ctx, vault_id, target_leverage, rebalance_threshold, max_slippage)
     }
 
-    /// Deposit funds into the vault
+    /// Deposit funds into the vault, crediting shares to `owner`.
+    /// A `referrer_account` passed on the owner's first deposit attributes them to that referrer.
     /// 
     /// # Arguments
     /// * `amount` - Amount to deposit (in token units)
-    pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
-        instructions::deposit(ctx, amount)
+    /// * `allowlist_proof` - Merkle proof for permissioned vaults, needed until first verified
+    pub fn deposit(
+        ctx: Context<Deposit>,
+        amount: u64,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Result<()> {
+        instructions::deposit(ctx, amount, allowlist_proof)
+    }
+
+    /// Deposit a non-base collateral asset, valued by oracle less its haircut,
+    /// crediting shares to `owner`
+    /// 
+    /// # Arguments
+    /// * `amount` - Amount of collateral to deposit (in collateral token units)
+    /// * `allowlist_proof` - Merkle proof for permissioned vaults, needed until first verified
+    pub fn deposit_collateral(
+        ctx: Context<DepositCollateral>,
+        amount: u64,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Result<()> {
+        instructions::deposit_collateral(ctx, amount, allowlist_proof)
+    }
+
+    /// Refresh collateral values at live oracle prices.
+    /// Remaining accounts: a (Drift spot market, oracle) pair per collateral asset.
+    pub fn revalue_collateral(ctx: Context<RevalueCollateral>) -> Result<()> {
+        instructions::revalue_collateral(ctx)
     }
 
-    /// Withdraw funds from the vault
+    /// Redeem the owner's shares, paying out to `receiver_token_account`
     /// 
     /// # Arguments
     /// * `amount` - Amount to withdraw (in token units)
@@ -54,44 +109,257 @@ pub mod delta_neutral_vault {
         instructions::withdraw(ctx, amount)
     }
 
-    /// Rebalance the vault's delta-neutral positions
-    pub fn rebalance(ctx: Context<Rebalance>) -> Result<()> {
+    /// Deposit on behalf of `owner` with a separate rent payer, for programs
+    /// whose PDA signs as depositor. Returns the shares minted.
+    /// 
+    /// # Arguments
+    /// * `amount` - Amount to deposit (in token units)
+    /// * `allowlist_proof` - Proof for the owner's allowlist leaf, if required
+    /// * `min_shares_out` - Fewest shares to accept, the deposit fails below it
+    pub fn deposit_for(
+        ctx: Context<DepositFor>,
+        amount: u64,
+        allowlist_proof: Option<AllowlistProof>,
+        min_shares_out: u64,
+    ) -> Result<u64> {
+        instructions::deposit_for(ctx, amount, allowlist_proof, min_shares_out)
+    }
+
+    /// Redeem exactly `shares`, paying out to `receiver_token_account`.
+    /// Returns the base tokens sent.
+    /// 
+    /// # Arguments
+    /// * `shares` - Shares to burn
+    /// * `min_amount_out` - Fewest base tokens to accept, the redemption fails below it
+    pub fn redeem(ctx: Context<Redeem>, shares: u64, min_amount_out: u64) -> Result<u64> {
+        instructions::redeem(ctx, shares, min_amount_out)
+    }
+
+    /// Redeem `shares` in kind for their pro-rata slice of the base asset and
+    /// of every held collateral, withdrawn from Drift to the receiver.
+    /// Remaining accounts: a (spot market vault, receiver token account) pair
+    /// per collateral paid out, in slot order, then the oracles and spot
+    /// markets Drift needs for the withdrawals.
+    /// 
+    /// # Arguments
+    /// * `shares` - Shares to burn
+    pub fn withdraw_collateral<'info>(
+        ctx: Context<'_, '_, '_, 'info, WithdrawCollateral<'info>>,
+        shares: u64,
+    ) -> Result<()> {
+        instructions::withdraw_collateral(ctx, shares)
+    }
+
+    /// Rebalance the vault's delta-neutral positions.
+    /// Remaining accounts: the oracle and spot markets Drift needs to place the order.
+    pub fn rebalance<'info>(ctx: Context<'_, '_, '_, 'info, Rebalance<'info>>) -> Result<()> {
         instructions::rebalance(ctx)
     }
 
     /// Emergency stop the vault (admin only)
-    pub fn emergency_stop(ctx: Context<EmergencyStop>) -> Result<()> {
-        instructions::emergency_stop(ctx)
+    pub fn emergency_stop(ctx: Context<EmergencyStop>, reason: EmergencyStopReason) -> Result<()> {
+        instructions::emergency_stop(ctx, reason)
     }
 
-    /// Collect fees from the vault
+    /// Collect management fees accrued since the last collection and
+    /// performance fees above the high-water mark. Only the admin may collect.
     pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
         instructions::collect_fees(ctx)
     }
 
+    /// Create the caller's referrer account for a vault, approved by the admin
+    pub fn register_referrer(ctx: Context<RegisterReferrer>) -> Result<()> {
+        instructions::register_referrer(ctx)
+    }
+
+    /// Claim accrued referral rebates (referrer only)
+    pub fn claim_referral_fees(ctx: Context<ClaimReferralFees>) -> Result<()> {
+        instructions::claim_referral_fees(ctx)
+    }
+
+    /// Create the share-price history account of a vault that predates it.
+    /// `initialize_vault` creates it for new vaults.
+    pub fn initialize_history(ctx: Context<InitializeHistory>) -> Result<()> {
+        instructions::initialize_history(ctx)
+    }
+
+    /// Close the owner's emptied user account, returning its rent to `receiver`
+    pub fn close_user(ctx: Context<CloseUser>) -> Result<()> {
+        instructions::close_user(ctx)
+    }
+
+    /// Close a drained vault and its accounts, returning their rent to `receiver` (admin only).
+    /// Leftover base-asset dust, at most MAX_CLOSE_DUST, goes to `admin_token_account`.
+    /// Remaining accounts: Drift sub-accounts other than `drift_user`, then the
+    /// token account of each configured collateral, both in slot order.
+    pub fn close_vault<'info>(ctx: Context<'_, '_, '_, 'info, CloseVault<'info>>) -> Result<()> {
+        instructions::close_vault(ctx)
+    }
+
+    /// Current share price (SHARE_PRICE_PRECISION), returned as return data
+    pub fn get_share_price(ctx: Context<ViewVault>) -> Result<u64> {
+        instructions::get_share_price(ctx)
+    }
+
+    /// Shares `deposit` would mint for `amount` credited to `owner`, returned
+    /// as return data. Applies the same allowlist, limits and Token-2022
+    /// transfer fee as `deposit`.
+    pub fn preview_deposit(
+        ctx: Context<PreviewDeposit>,
+        amount: u64,
+        allowlist_proof: Option<AllowlistProof>,
+    ) -> Result<u64> {
+        instructions::preview_deposit(ctx, amount, allowlist_proof)
+    }
+
+    /// Base tokens paid out for redeeming `shares`, returned as return data
+    pub fn preview_redeem(ctx: Context<PreviewRedeem>, shares: u64) -> Result<WithdrawalQuote> {
+        instructions::preview_redeem(ctx, shares)
+    }
+
+    /// NAV split into base assets and collateral, returned as return data
+    pub fn get_nav_breakdown(ctx: Context<ViewVault>) -> Result<NavBreakdown> {
+        instructions::get_nav_breakdown(ctx)
+    }
+
+    /// Positions, delta and rebalance trigger, returned as return data
+    pub fn get_delta(ctx: Context<ViewVault>) -> Result<DeltaExposure> {
+        instructions::get_delta(ctx)
+    }
+
+    /// Create the vault's Drift user stats and first Drift user (admin only).
+    /// The user becomes `drift_user`, which `sync_positions` and `rebalance` trade through.
+    /// 
+    /// # Arguments
+    /// * `sub_account_id` - Drift sub-account id under the vault's authority
+    pub fn initialize_drift_user(ctx: Context<InitializeDriftUser>, sub_account_id: u16) -> Result<()> {
+        drift_integration::initialize_drift_user(ctx, sub_account_id)
+    }
+
+    /// Create another Drift sub-account for the vault (admin only). The first
+    /// one created becomes `drift_user` and creates the vault's Drift user
+    /// stats, later ones must pass the same `drift_user_stats`.
+    /// 
+    /// # Arguments
+    /// * `sub_account_id` - Drift sub-account id under the vault's authority
+    pub fn add_drift_sub_account(ctx: Context<AddDriftSubAccount>, sub_account_id: u16) -> Result<()> {
+        instructions::add_drift_sub_account(ctx, sub_account_id)
+    }
+
+    /// Move collateral between two of the vault's Drift sub-accounts (admin only).
+    /// The spot market and oracle accounts Drift needs are passed as remaining accounts.
+    /// 
+    /// # Arguments
+    /// * `spot_market_index` - Drift spot market of the collateral
+    /// * `amount` - Amount to move (in token units)
+    pub fn transfer_sub_account_collateral<'info>(
+        ctx: Context<'_, '_, '_, 'info, TransferSubAccountCollateral<'info>>,
+        spot_market_index: u16,
+        amount: u64,
+    ) -> Result<()> {
+        instructions::transfer_sub_account_collateral(ctx, spot_market_index, amount)
+    }
+
+    /// Add or update an accepted collateral asset (admin only)
+    /// 
+    /// # Arguments
+    /// * `spot_market_index` - Drift spot market holding the collateral
+    /// * `haircut_bps` - Discount applied to the collateral's oracle value
+    /// * `enabled` - Whether new deposits of the collateral are accepted
+    pub fn configure_collateral(
+        ctx: Context<ConfigureCollateral>,
+        spot_market_index: u16,
+        haircut_bps: u16,
+        enabled: bool,
+    ) -> Result<()> {
+        instructions::configure_collateral(ctx, spot_market_index, haircut_bps, enabled)
+    }
+
+    /// Update vault capacity and flow limits (admin only)
+    /// 
+    /// # Arguments
+    /// * `max_capacity` - New maximum total assets
+    /// * `max_user_deposit` - New per-user net deposit cap (0 for no cap)
+    /// * `epoch_duration` - New flow-limit epoch length in seconds
+    /// * `max_epoch_inflow` - New net inflow limit per epoch (0 for no limit)
+    /// * `max_epoch_outflow` - New net outflow limit per epoch (0 for no limit)
+    pub fn update_capacity(
+        ctx: Context<UpdateCapacity>,
+        max_capacity: Option<u64>,
+        max_user_deposit: Option<u64>,
+        epoch_duration: Option<i64>,
+        max_epoch_inflow: Option<u64>,
+        max_epoch_outflow: Option<u64>,
+    ) -> Result<()> {
+        instructions::update_capacity(
+            ctx,
+            max_capacity,
+            max_user_deposit,
+            epoch_duration,
+            max_epoch_inflow,
+            max_epoch_outflow,
+        )
+    }
+
+    /// Configure the depositor allowlist (admin only)
+    /// 
+    /// # Arguments
+    /// * `enabled` - Whether deposits require an allowlist proof
+    /// * `root` - Merkle root of (depositor, deposit cap) leaves
+    pub fn set_allowlist(
+        ctx: Context<SetAllowlist>,
+        enabled: bool,
+        root: [u8; 32],
+    ) -> Result<()> {
+        instructions::set_allowlist(ctx, enabled, root)
+    }
+
     /// Update vault parameters (admin only)
     /// 
     /// # Arguments
     /// * `target_leverage` - New target leverage ratio
     /// * `rebalance_threshold` - New rebalance threshold
     /// * `max_slippage` - New maximum slippage
+    /// * `revert_on_slippage` - Revert hedges that fill outside `max_slippage` instead of flagging them
+    /// * `lockup_period` - Minimum seconds between a user's last deposit and a fee-free withdrawal
+    /// * `early_exit_fee_bps` - Fee charged on withdrawals inside the lockup (0 rejects them)
+    /// * `referral_fee_share_bps` - Share of fees on referred shares rebated to referrers
     pub fn update_vault_params(
         ctx: Context<UpdateVaultParams>,
         target_leverage: Option<u8>,
         rebalance_threshold: Option<u16>,
         max_slippage: Option<u16>,
+        revert_on_slippage: Option<bool>,
+        lockup_period: Option<i64>,
+        early_exit_fee_bps: Option<u16>,
+        referral_fee_share_bps: Option<u16>,
     ) -> Result<()> {
-        instructions::update_vault_params(ctx, target_leverage, rebalance_threshold, max_slippage)
+        instructions::update_vault_params(
+            ctx,
+            target_leverage,
+            rebalance_threshold,
+            max_slippage,
+            revert_on_slippage,
+            lockup_period,
+            early_exit_fee_bps,
+            referral_fee_share_bps,
+        )
+    }
+
+    /// Upgrade a vault account written by an older program version to the
+    /// current layout in place (admin only)
+    pub fn migrate_vault(ctx: Context<MigrateVault>) -> Result<()> {
+        instructions::migrate_vault(ctx)
     }
 
-    /// Open a new position on Drift Protocol
+    /// Open a new position on Drift Protocol (admin only)
     /// 
     /// # Arguments
     /// * `market_index` - Market index for the position
     /// * `size` - Position size
     /// * `direction` - Position direction (0 = long, 1 = short)
-    pub fn open_position(
-        ctx: Context<OpenPosition>,
+    pub fn open_position<'info>(
+        ctx: Context<'_, '_, '_, 'info, OpenPosition<'info>>,
         market_index: u16,
         size: u64,
         direction: u8,
@@ -99,30 +367,104 @@ pub mod delta_neutral_vault {
         instructions::open_position(ctx, market_index, size, direction)
     }
 
-    /// Close an existing position on Drift Protocol
+    /// Close an existing position on Drift Protocol (admin only)
     /// 
     /// # Arguments
     /// * `market_index` - Market index for the position
-    /// * `size` - Position size to close
-    pub fn close_position(
-        ctx: Context<ClosePosition>,
+    /// * `size` - Position size to close, capped at the market's booked position
+    pub fn close_position<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClosePosition<'info>>,
         market_index: u16,
         size: u64,
     ) -> Result<()> {
         instructions::close_position(ctx, market_index, size)
     }
+
+    /// Reconcile the vault's position book against Drift (permissionless)
+    pub fn sync_positions(ctx: Context<SyncPositions>) -> Result<()> {
+        instructions::sync_positions(ctx)
+    }
+
+    /// Cancel tracked Drift orders past their `max_ts` (permissionless)
+    pub fn cancel_stale_orders(ctx: Context<CancelStaleOrders>) -> Result<()> {
+        instructions::cancel_stale_orders(ctx)
+    }
+}
+
+#[derive(Accounts)]
+pub struct InitializeProtocol<'info> {
+    #[account(
+        init,
+        payer = owner,
+        space = ProtocolConfig::LEN,
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::DeltaNeutralVault>,
+
+    // Only the program's upgrade authority may become the protocol owner
+    #[account(constraint = program_data.upgrade_authority_address == Some(owner.key()) @ VaultError::UnauthorizedAccess)]
+    pub program_data: Account<'info, ProgramData>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateProtocolConfig<'info> {
+    #[account(
+        mut,
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        has_one = owner
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    pub owner: Signer<'info>,
 }
 
 #[derive(Accounts)]
+#[instruction(vault_id: u64)]
 pub struct InitializeVault<'info> {
     #[account(
         init,
         payer = admin,
         space = VaultState::LEN,
-        seeds = [VAULT_SEED, admin.key().as_ref()],
+        seeds = [VAULT_SEED, admin.key().as_ref(), &vault_id_seed(vault_id)],
+        bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = VaultRegistryEntry::LEN,
+        seeds = [REGISTRY_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub registry_entry: Account<'info, VaultRegistryEntry>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = VaultHistory::LEN,
+        seeds = [HISTORY_SEED, vault_state.key().as_ref()],
         bump
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub history: AccountLoader<'info, VaultHistory>,
+
+    #[account(
+        mut,
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
 
     #[account(
         init,
@@ -132,14 +474,14 @@ pub struct InitializeVault<'info> {
         seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
         bump
     )]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
 
     #[account(mut)]
     pub admin: Signer<'info>,
 
-    pub token_mint: Account<'info, Mint>,
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
     pub drift_program: Program<'info, Drift>,
-    pub token_program: Program<'info, Token>,
+    pub token_program: Interface<'info, TokenInterface>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
 }
@@ -148,194 +490,1089 @@ pub struct InitializeVault<'info> {
 pub struct Deposit<'info> {
     #[account(
         mut,
-        seeds = [VAULT_SEED, vault_state.admin.as_ref()],
-        bump = vault_state.bump
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub protocol_config: Account<'info, ProtocolConfig>,
 
     #[account(
         mut,
         seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
         bump
     )]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
 
     #[account(
         mut,
         token::mint = vault_token_account.mint,
         token::authority = depositor
     )]
-    pub depositor_token_account: Account<'info, TokenAccount>,
+    pub depositor_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        space = VaultUser::LEN,
+        seeds = [USER_SEED, vault_state.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub vault_user: Account<'info, VaultUser>,
 
     #[account(mut)]
     pub depositor: Signer<'info>,
 
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Beneficiary credited with the shares, may be the depositor
+    pub owner: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [REFERRER_SEED, vault_state.key().as_ref(), referrer_account.referrer.as_ref()],
+        bump = referrer_account.bump
+    )]
+    pub referrer_account: Option<Account<'info, ReferrerAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Withdraw<'info> {
+pub struct DepositCollateral<'info> {
     #[account(
         mut,
-        seeds = [VAULT_SEED, vault_state.admin.as_ref()],
-        bump = vault_state.bump
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub vault_state: AccountLoader<'info, VaultState>,
 
     #[account(
-        mut,
-        seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    pub collateral_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        token::mint = collateral_mint,
+        token::authority = vault_state,
+        seeds = [COLLATERAL_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref(), collateral_mint.key().as_ref()],
         bump
     )]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    pub vault_collateral_account: InterfaceAccount<'info, token_interface::TokenAccount>,
 
     #[account(
         mut,
-        token::mint = vault_token_account.mint,
-        token::authority = withdrawer
+        token::mint = collateral_mint,
+        token::authority = depositor
+    )]
+    pub depositor_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = depositor,
+        space = VaultUser::LEN,
+        seeds = [USER_SEED, vault_state.key().as_ref(), owner.key().as_ref()],
+        bump
     )]
-    pub withdrawer_token_account: Account<'info, TokenAccount>,
+    pub vault_user: Account<'info, VaultUser>,
 
     #[account(mut)]
-    pub withdrawer: Signer<'info>,
+    pub depositor: Signer<'info>,
 
-    pub token_program: Program<'info, Token>,
-}
+    /// CHECK: Beneficiary credited with the shares, may be the depositor
+    pub owner: UncheckedAccount<'info>,
 
-#[derive(Accounts)]
-pub struct Rebalance<'info> {
     #[account(
         mut,
-        seeds = [VAULT_SEED, vault_state.admin.as_ref()],
-        bump = vault_state.bump
+        seeds = [REFERRER_SEED, vault_state.key().as_ref(), referrer_account.referrer.as_ref()],
+        bump = referrer_account.bump
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub referrer_account: Option<Account<'info, ReferrerAccount>>,
+
+    /// CHECK: Drift state
+    pub drift_state: UncheckedAccount<'info>,
 
     /// CHECK: Drift user account
-    #[account(mut)]
+    #[account(mut, address = vault_state.load()?.drift_user)]
     pub drift_user: UncheckedAccount<'info>,
 
     /// CHECK: Drift user stats
-    #[account(mut)]
+    #[account(mut, address = vault_state.load()?.drift_user_stats)]
     pub drift_user_stats: UncheckedAccount<'info>,
 
-    /// CHECK: Drift state
+    /// CHECK: Drift spot market, validated against the collateral's market index
     #[account(mut)]
-    pub drift_state: UncheckedAccount<'info>,
-
-    pub drift_program: Program<'info, Drift>,
+    pub spot_market: UncheckedAccount<'info>,
 
+    /// CHECK: Drift spot market vault, validated by Drift
     #[account(mut)]
-    pub authority: Signer<'info>,
+    pub spot_market_vault: UncheckedAccount<'info>,
+
+    /// CHECK: Spot market oracle, validated against the spot market
+    pub oracle: UncheckedAccount<'info>,
+
+    pub drift_program: Program<'info, Drift>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct EmergencyStop<'info> {
+pub struct RevalueCollateral<'info> {
     #[account(
         mut,
-        seeds = [VAULT_SEED, vault_state.admin.as_ref()],
-        bump = vault_state.bump,
-        has_one = admin
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub vault_state: AccountLoader<'info, VaultState>,
 
-    #[account(mut)]
-    pub admin: Signer<'info>,
+    pub payer: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CollectFees<'info> {
+pub struct Withdraw<'info> {
     #[account(
         mut,
-        seeds = [VAULT_SEED, vault_state.admin.as_ref()],
-        bump = vault_state.bump
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub protocol_config: Account<'info, ProtocolConfig>,
 
     #[account(
         mut,
         seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
         bump
     )]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
 
     #[account(
         mut,
-        token::mint = vault_token_account.mint,
-        token::authority = fee_collector
+        token::mint = vault_token_account.mint
+    )]
+    pub receiver_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [USER_SEED, vault_state.key().as_ref(), owner.key().as_ref()],
+        bump = vault_user.bump
     )]
-    pub fee_collector_account: Account<'info, TokenAccount>,
+    pub vault_user: Account<'info, VaultUser>,
 
     #[account(mut)]
-    pub fee_collector: Signer<'info>,
+    pub owner: Signer<'info>,
 
-    pub token_program: Program<'info, Token>,
+    #[account(
+        mut,
+        seeds = [REFERRER_SEED, vault_state.key().as_ref(), referrer_account.referrer.as_ref()],
+        bump = referrer_account.bump
+    )]
+    pub referrer_account: Option<Account<'info, ReferrerAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateVaultParams<'info> {
+pub struct WithdrawCollateral<'info> {
     #[account(
         mut,
-        seeds = [VAULT_SEED, vault_state.admin.as_ref()],
-        bump = vault_state.bump,
-        has_one = admin
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub vault_state: AccountLoader<'info, VaultState>,
 
-    #[account(mut)]
-    pub admin: Signer<'info>,
-}
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
 
-#[derive(Accounts)]
-pub struct OpenPosition<'info> {
     #[account(
         mut,
-        seeds = [VAULT_SEED, vault_state.admin.as_ref()],
-        bump = vault_state.bump
+        seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
+        bump
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = vault_token_account.mint
+    )]
+    pub receiver_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [USER_SEED, vault_state.key().as_ref(), owner.key().as_ref()],
+        bump = vault_user.bump
+    )]
+    pub vault_user: Account<'info, VaultUser>,
 
-    /// CHECK: Drift user account
     #[account(mut)]
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [REFERRER_SEED, vault_state.key().as_ref(), referrer_account.referrer.as_ref()],
+        bump = referrer_account.bump
+    )]
+    pub referrer_account: Option<Account<'info, ReferrerAccount>>,
+
+    /// CHECK: Drift state
+    pub drift_state: UncheckedAccount<'info>,
+
+    /// CHECK: Drift user account holding the collateral
+    #[account(mut, address = vault_state.load()?.drift_user)]
     pub drift_user: UncheckedAccount<'info>,
 
     /// CHECK: Drift user stats
-    #[account(mut)]
+    #[account(mut, address = vault_state.load()?.drift_user_stats)]
     pub drift_user_stats: UncheckedAccount<'info>,
 
-    /// CHECK: Drift state
-    #[account(mut)]
-    pub drift_state: UncheckedAccount<'info>,
+    /// CHECK: Drift signer, validated by Drift
+    pub drift_signer: UncheckedAccount<'info>,
 
     pub drift_program: Program<'info, Drift>,
-
-    #[account(mut)]
-    pub authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
+/// `Deposit` for CPI callers. The depositor only signs for the transfer, so
+/// it may be a PDA holding data, and `payer` funds the user account.
 #[derive(Accounts)]
-pub struct ClosePosition<'info> {
+pub struct DepositFor<'info> {
     #[account(
         mut,
-        seeds = [VAULT_SEED, vault_state.admin.as_ref()],
-        bump = vault_state.bump
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
     )]
-    pub vault_state: Account<'info, VaultState>,
+    pub vault_state: AccountLoader<'info, VaultState>,
 
-    /// CHECK: Drift user account
-    #[account(mut)]
-    pub drift_user: UncheckedAccount<'info>,
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
 
-    /// CHECK: Drift user stats
-    #[account(mut)]
-    pub drift_user_stats: UncheckedAccount<'info>,
+    #[account(
+        mut,
+        seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
 
-    /// CHECK: Drift state
-    #[account(mut)]
-    pub drift_state: UncheckedAccount<'info>,
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
 
-    pub drift_program: Program<'info, Drift>,
+    #[account(
+        mut,
+        token::mint = vault_token_account.mint,
+        token::authority = depositor
+    )]
+    pub depositor_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
 
-    #[account(mut)]
-    pub authority: Signer<'info>,
-}
\ No newline at end of file
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = VaultUser::LEN,
+        seeds = [USER_SEED, vault_state.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub vault_user: Account<'info, VaultUser>,
+
+    pub depositor: Signer<'info>,
+
+    /// CHECK: Beneficiary credited with the shares, may be the depositor
+    pub owner: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [REFERRER_SEED, vault_state.key().as_ref(), referrer_account.referrer.as_ref()],
+        bump = referrer_account.bump
+    )]
+    pub referrer_account: Option<Account<'info, ReferrerAccount>>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+/// `Withdraw` priced in shares for CPI callers. The owner only signs, so it
+/// may be a PDA holding data.
+#[derive(Accounts)]
+pub struct Redeem<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = vault_token_account.mint
+    )]
+    pub receiver_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [USER_SEED, vault_state.key().as_ref(), owner.key().as_ref()],
+        bump = vault_user.bump
+    )]
+    pub vault_user: Account<'info, VaultUser>,
+
+    pub owner: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [REFERRER_SEED, vault_state.key().as_ref(), referrer_account.referrer.as_ref()],
+        bump = referrer_account.bump
+    )]
+    pub referrer_account: Option<Account<'info, ReferrerAccount>>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Rebalance<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    /// CHECK: Drift user account
+    #[account(mut)]
+    pub drift_user: UncheckedAccount<'info>,
+
+    /// CHECK: Drift user stats
+    #[account(mut, address = vault_state.load()?.drift_user_stats)]
+    pub drift_user_stats: UncheckedAccount<'info>,
+
+    /// CHECK: Drift state
+    #[account(mut)]
+    pub drift_state: UncheckedAccount<'info>,
+
+    /// CHECK: Drift perp market, validated against the hedged market index
+    pub perp_market: UncheckedAccount<'info>,
+
+    pub drift_program: Program<'info, Drift>,
+
+    #[account(
+        mut,
+        seeds = [HISTORY_SEED, vault_state.key().as_ref()],
+        bump = history.load()?.bump
+    )]
+    pub history: AccountLoader<'info, VaultHistory>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyStop<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = vault_token_account.mint,
+        token::authority = fee_collector
+    )]
+    pub fee_collector_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = vault_token_account.mint,
+        constraint = protocol_fee_account.owner == protocol_config.owner @ VaultError::InvalidTokenAccount
+    )]
+    pub protocol_fee_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [HISTORY_SEED, vault_state.key().as_ref()],
+        bump = history.load()?.bump
+    )]
+    pub history: AccountLoader<'info, VaultHistory>,
+
+    #[account(
+        mut,
+        constraint = fee_collector.key() == vault_state.load()?.admin @ VaultError::UnauthorizedAccess
+    )]
+    pub fee_collector: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterReferrer<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        init,
+        payer = referrer,
+        space = ReferrerAccount::LEN,
+        seeds = [REFERRER_SEED, vault_state.key().as_ref(), referrer.key().as_ref()],
+        bump
+    )]
+    pub referrer_account: Account<'info, ReferrerAccount>,
+
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+
+    /// Approves the referrer, so a depositor cannot rebate fees to a second key of their own
+    #[account(address = vault_state.load()?.admin @ VaultError::UnauthorizedAccess)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeHistory<'info> {
+    #[account(
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = VaultHistory::LEN,
+        seeds = [HISTORY_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub history: AccountLoader<'info, VaultHistory>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ViewVault<'info> {
+    #[account(
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+}
+
+#[derive(Accounts)]
+pub struct PreviewDeposit<'info> {
+    #[account(
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    #[account(
+        seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    /// Decides the Token-2022 transfer fee deducted from the deposit
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// CHECK: Beneficiary the shares would be credited to
+    pub owner: UncheckedAccount<'info>,
+
+    /// The owner's position, whose allowlist admission and net deposits the
+    /// limits apply to. Omit for an owner's first deposit.
+    #[account(
+        seeds = [USER_SEED, vault_state.key().as_ref(), owner.key().as_ref()],
+        bump = vault_user.bump
+    )]
+    pub vault_user: Option<Account<'info, VaultUser>>,
+}
+
+#[derive(Accounts)]
+pub struct PreviewRedeem<'info> {
+    #[account(
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    /// The redeeming user, whose lockup decides the early-exit fee. Omit to
+    /// quote an exit outside the lockup.
+    #[account(
+        seeds = [USER_SEED, vault_state.key().as_ref(), vault_user.user.as_ref()],
+        bump = vault_user.bump
+    )]
+    pub vault_user: Option<Account<'info, VaultUser>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseUser<'info> {
+    /// CHECK: The user's vault. It may already have been closed, in which case
+    /// the close is not sequenced
+    #[account(mut, address = vault_user.vault)]
+    pub vault_state: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [USER_SEED, vault_user.vault.as_ref(), owner.key().as_ref()],
+        bump = vault_user.bump,
+        close = receiver
+    )]
+    pub vault_user: Account<'info, VaultUser>,
+
+    pub owner: Signer<'info>,
+
+    /// CHECK: Receives the reclaimed rent
+    #[account(mut)]
+    pub receiver: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseVault<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin,
+        close = receiver
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(seeds = [PROTOCOL_CONFIG_SEED], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    #[account(
+        mut,
+        seeds = [REGISTRY_SEED, vault_state.key().as_ref()],
+        bump = registry_entry.bump,
+        close = receiver
+    )]
+    pub registry_entry: Account<'info, VaultRegistryEntry>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// Receives leftover base-asset dust
+    #[account(
+        mut,
+        token::mint = vault_token_account.mint,
+        token::authority = admin
+    )]
+    pub admin_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [HISTORY_SEED, vault_state.key().as_ref()],
+        bump = history.load()?.bump,
+        close = receiver
+    )]
+    pub history: AccountLoader<'info, VaultHistory>,
+
+    /// CHECK: Drift user, deleted by Drift. Required once the vault has one
+    #[account(mut, address = vault_state.load()?.drift_user)]
+    pub drift_user: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: Drift user stats, deleted by Drift. Required once the vault has a Drift user
+    #[account(mut, address = vault_state.load()?.drift_user_stats)]
+    pub drift_user_stats: Option<UncheckedAccount<'info>>,
+
+    /// CHECK: Drift state
+    #[account(mut)]
+    pub drift_state: Option<UncheckedAccount<'info>>,
+
+    pub drift_program: Option<Program<'info, Drift>>,
+
+    pub admin: Signer<'info>,
+
+    /// CHECK: Receives the reclaimed rent
+    #[account(mut)]
+    pub receiver: UncheckedAccount<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralFees<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    #[account(
+        mut,
+        seeds = [REFERRER_SEED, vault_state.key().as_ref(), referrer.key().as_ref()],
+        bump = referrer_account.bump,
+        has_one = referrer
+    )]
+    pub referrer_account: Account<'info, ReferrerAccount>,
+
+    #[account(
+        mut,
+        seeds = [VAULT_TOKEN_ACCOUNT_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub vault_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(address = vault_token_account.mint)]
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = vault_token_account.mint
+    )]
+    pub referrer_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub referrer: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ConfigureCollateral<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    pub collateral_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// CHECK: Drift spot market, validated against the market index and mint
+    pub spot_market: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateCapacity<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAllowlist<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateVaultParams<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateVault<'info> {
+    /// CHECK: Older layouts do not deserialize as `VaultState`; the
+    /// discriminator, version and admin are checked in the handler
+    #[account(mut, owner = crate::ID)]
+    pub vault_state: UncheckedAccount<'info>,
+
+    /// Created for baseline vaults, which predate the registry
+    #[account(
+        init_if_needed,
+        payer = admin,
+        space = VaultRegistryEntry::LEN,
+        seeds = [REGISTRY_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub registry_entry: Account<'info, VaultRegistryEntry>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenPosition<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    /// CHECK: Drift user account
+    #[account(mut)]
+    pub drift_user: UncheckedAccount<'info>,
+
+    /// CHECK: Drift user stats
+    #[account(mut, address = vault_state.load()?.drift_user_stats)]
+    pub drift_user_stats: UncheckedAccount<'info>,
+
+    /// CHECK: Drift state
+    #[account(mut)]
+    pub drift_state: UncheckedAccount<'info>,
+
+    /// CHECK: Drift perp market, validated against the hedged market index
+    pub perp_market: UncheckedAccount<'info>,
+
+    pub drift_program: Program<'info, Drift>,
+
+    /// Trades with depositors' collateral, so only the admin may sign
+    #[account(
+        mut,
+        constraint = vault_state.load()?.is_admin(&authority.key()) @ VaultError::UnauthorizedAccess
+    )]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    /// CHECK: Drift user account
+    #[account(mut)]
+    pub drift_user: UncheckedAccount<'info>,
+
+    /// CHECK: Drift user stats
+    #[account(mut, address = vault_state.load()?.drift_user_stats)]
+    pub drift_user_stats: UncheckedAccount<'info>,
+
+    /// CHECK: Drift state
+    #[account(mut)]
+    pub drift_state: UncheckedAccount<'info>,
+
+    /// CHECK: Drift perp market, validated against the hedged market index
+    pub perp_market: UncheckedAccount<'info>,
+
+    pub drift_program: Program<'info, Drift>,
+
+    /// Trades with depositors' collateral, so only the admin may sign
+    #[account(
+        mut,
+        constraint = vault_state.load()?.is_admin(&authority.key()) @ VaultError::UnauthorizedAccess
+    )]
+    pub authority: Signer<'info>,
+}
+#[derive(Accounts)]
+pub struct SyncPositions<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    /// CHECK: Drift user of one of the vault's sub-accounts
+    #[account(
+        constraint = vault_state.load()?.drift_sub_account_slot(&drift_user.key()).is_some() @ VaultError::UnknownSubAccount
+    )]
+    pub drift_user: UncheckedAccount<'info>,
+
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelStaleOrders<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(seeds = [PROTOCOL_CONFIG_SEED], bump = protocol_config.bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    /// CHECK: Drift user of one of the vault's sub-accounts
+    #[account(
+        mut,
+        constraint = vault_state.load()?.drift_sub_account_slot(&drift_user.key()).is_some() @ VaultError::UnknownSubAccount
+    )]
+    pub drift_user: UncheckedAccount<'info>,
+
+    /// CHECK: Drift state
+    #[account(mut)]
+    pub drift_state: UncheckedAccount<'info>,
+
+    pub drift_program: Program<'info, Drift>,
+
+    pub payer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeDriftUser<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin,
+        constraint = vault_state.load()?.drift_user == Pubkey::default() @ VaultError::DriftUserAlreadyInitialized
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    /// CHECK: Drift user to create, validated by Drift
+    #[account(mut)]
+    pub drift_user: UncheckedAccount<'info>,
+
+    /// CHECK: Drift user stats to create, validated by Drift
+    #[account(mut)]
+    pub drift_user_stats: UncheckedAccount<'info>,
+
+    /// CHECK: Drift state
+    #[account(mut)]
+    pub drift_state: UncheckedAccount<'info>,
+
+    pub drift_program: Program<'info, Drift>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct AddDriftSubAccount<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    /// CHECK: Drift user to create, validated by Drift
+    #[account(mut)]
+    pub drift_user: UncheckedAccount<'info>,
+
+    /// CHECK: Drift user stats of the vault, created on the first call and
+    /// checked against `vault_state.drift_user_stats` after that
+    #[account(mut)]
+    pub drift_user_stats: UncheckedAccount<'info>,
+
+    /// CHECK: Drift state
+    #[account(mut)]
+    pub drift_state: UncheckedAccount<'info>,
+
+    pub drift_program: Program<'info, Drift>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct TransferSubAccountCollateral<'info> {
+    #[account(
+        mut,
+        seeds = [VAULT_SEED, vault_state.load()?.admin.as_ref(), &vault_state.load()?.vault_id_seed()],
+        bump = vault_state.load()?.bump,
+        has_one = admin
+    )]
+    pub vault_state: AccountLoader<'info, VaultState>,
+
+    #[account(
+        seeds = [PROTOCOL_CONFIG_SEED],
+        bump = protocol_config.bump,
+        constraint = !protocol_config.paused @ VaultError::ProtocolPaused
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    /// CHECK: Drift user of the sub-account sending the collateral
+    #[account(
+        mut,
+        constraint = vault_state.load()?.drift_sub_account_slot(&from_user.key()).is_some() @ VaultError::UnknownSubAccount
+    )]
+    pub from_user: UncheckedAccount<'info>,
+
+    /// CHECK: Drift user of the sub-account receiving the collateral
+    #[account(
+        mut,
+        constraint = vault_state.load()?.drift_sub_account_slot(&to_user.key()).is_some() @ VaultError::UnknownSubAccount
+    )]
+    pub to_user: UncheckedAccount<'info>,
+
+    /// CHECK: Drift user stats
+    #[account(mut, address = vault_state.load()?.drift_user_stats)]
+    pub drift_user_stats: UncheckedAccount<'info>,
+
+    /// CHECK: Drift state
+    pub drift_state: UncheckedAccount<'info>,
+
+    /// CHECK: Drift spot market vault, validated by Drift
+    pub spot_market_vault: UncheckedAccount<'info>,
+
+    pub drift_program: Program<'info, Drift>,
+
+    pub admin: Signer<'info>,
+}
@@ -0,0 +1,125 @@
+use anchor_lang::prelude::*;
+
+use crate::constants::BASIS_POINTS_DIVISOR;
+use crate::errors::VaultError;
+
+/// Direction to round the result of a fixed-point division
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+/// Checked arithmetic that surfaces vault errors instead of panicking or wrapping
+pub trait SafeMath: Sized {
+    fn safe_add(self, rhs: Self) -> Result<Self>;
+    fn safe_sub(self, rhs: Self) -> Result<Self>;
+    fn safe_mul(self, rhs: Self) -> Result<Self>;
+    fn safe_div(self, rhs: Self) -> Result<Self>;
+}
+
+macro_rules! impl_safe_math {
+    ($($t:ty),*) => {
+        $(
+            impl SafeMath for $t {
+                fn safe_add(self, rhs: Self) -> Result<Self> {
+                    self.checked_add(rhs)
+                        .ok_or_else(|| error!(VaultError::MathOverflow))
+                }
+
+                fn safe_sub(self, rhs: Self) -> Result<Self> {
+                    self.checked_sub(rhs)
+                        .ok_or_else(|| error!(VaultError::MathUnderflow))
+                }
+
+                fn safe_mul(self, rhs: Self) -> Result<Self> {
+                    self.checked_mul(rhs)
+                        .ok_or_else(|| error!(VaultError::MathOverflow))
+                }
+
+                fn safe_div(self, rhs: Self) -> Result<Self> {
+                    if rhs == 0 {
+                        return err!(VaultError::DivisionByZero);
+                    }
+                    self.checked_div(rhs)
+                        .ok_or_else(|| error!(VaultError::MathOverflow))
+                }
+            }
+        )*
+    };
+}
+
+impl_safe_math!(u64, i64, u128, i128);
+
+/// Divide with an explicit rounding direction
+pub fn div_round(numerator: u128, denominator: u128, rounding: Rounding) -> Result<u128> {
+    let quotient = numerator.safe_div(denominator)?;
+    if rounding == Rounding::Up && numerator % denominator != 0 {
+        return quotient.safe_add(1);
+    }
+    Ok(quotient)
+}
+
+/// Compute `value * numerator / denominator` through a u128 intermediate
+pub fn mul_div(value: u64, numerator: u64, denominator: u64, rounding: Rounding) -> Result<u64> {
+    let product = (value as u128).safe_mul(numerator as u128)?;
+    let quotient = div_round(product, denominator as u128, rounding)?;
+    u64::try_from(quotient).map_err(|_| error!(VaultError::MathOverflow))
+}
+
+/// Signed `value * numerator / denominator` through an i128 intermediate,
+/// truncating toward zero
+pub fn mul_div_signed(value: i64, numerator: i64, denominator: i64) -> Result<i64> {
+    let product = (value as i128).safe_mul(numerator as i128)?;
+    let quotient = product.safe_div(denominator as i128)?;
+    i64::try_from(quotient).map_err(|_| error!(VaultError::MathOverflow))
+}
+
+/// Take `bps` basis points of `value`
+pub fn bps_of(value: u64, bps: u16, rounding: Rounding) -> Result<u64> {
+    mul_div(value, bps as u64, BASIS_POINTS_DIVISOR, rounding)
+}
+
+pub fn to_u64<T: TryInto<u64>>(value: T) -> Result<u64> {
+    value
+        .try_into()
+        .map_err(|_| error!(VaultError::ConversionFailed))
+}
+
+pub fn to_i64<T: TryInto<i64>>(value: T) -> Result<i64> {
+    value
+        .try_into()
+        .map_err(|_| error!(VaultError::ConversionFailed))
+}
+
+pub fn to_u16<T: TryInto<u16>>(value: T) -> Result<u16> {
+    value
+        .try_into()
+        .map_err(|_| error!(VaultError::ConversionFailed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_math_errors() {
+        assert!(u64::MAX.safe_add(1).is_err());
+        assert!(0u64.safe_sub(1).is_err());
+        assert!(i64::MIN.safe_sub(1).is_err());
+        assert!(1u64.safe_div(0).is_err());
+        assert_eq!(7u64.safe_mul(6).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_mul_div_rounding() {
+        assert_eq!(mul_div(10, 1, 3, Rounding::Down).unwrap(), 3);
+        assert_eq!(mul_div(10, 1, 3, Rounding::Up).unwrap(), 4);
+        assert_eq!(mul_div(9, 1, 3, Rounding::Up).unwrap(), 3);
+
+        // Intermediate exceeds u64 but the result fits
+        assert_eq!(mul_div(u64::MAX, 2, 4, Rounding::Down).unwrap(), u64::MAX / 2);
+        assert!(mul_div(u64::MAX, 2, 1, Rounding::Down).is_err());
+        assert!(mul_div(1, 1, 0, Rounding::Down).is_err());
+    }
+}
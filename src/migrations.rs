@@ -0,0 +1,270 @@
+use anchor_lang::prelude::*;
+use anchor_lang::Discriminator;
+
+use crate::constants::*;
+use crate::errors::*;
+use crate::state::VaultState;
+
+/// Byte offset of the version field, right after the discriminator
+const VERSION_OFFSET: usize = 8;
+
+/// Baseline `admin` and `bump`, which v2 moves behind the version byte
+const BASELINE_HEADER: std::ops::Range<usize> = 8..41;
+/// Baseline `target_leverage` through `delta_threshold`, which v2 moves
+/// behind the new `vault_id`
+const BASELINE_FIELDS: std::ops::Range<usize> = 41..221;
+/// v2 offset of `vault_id`, which is 0 for baseline vaults, see `vault_id_seed`
+const V2_VAULT_ID_OFFSET: usize = 42;
+
+/// v5 offset of the trailing reserved space, where v6 inserts the fee checkpoint
+const V5_RESERVED_OFFSET: usize = VAULT_STATE_V2_SIZE - 4;
+/// `last_fee_collection_time` and `high_water_mark`
+const FEE_CHECKPOINT_LEN: usize = 16;
+
+/// Layout version of raw `VaultState` account data.
+/// v1 is the baseline Borsh layout, which predates the version byte and is
+/// recognised by its size:
+/// - v2 adds the version byte and `vault_id`, and appends order tracking,
+///   per-market books, the allowlist, lockup, flow limits, collateral and
+///   referrals
+/// - v3 takes `event_sequence` from reserved space
+/// - v4 takes `fees_per_share` from reserved space
+/// - v5 takes the Drift sub-accounts from reserved space
+/// - v6 adds the fee checkpoint
+pub fn vault_state_version(data: &[u8]) -> Result<u8> {
+    require!(
+        data.len() > VERSION_OFFSET && data[..8] == VaultState::discriminator(),
+        VaultError::InvalidVaultState
+    );
+
+    if data.len() == BASELINE_VAULT_STATE_SIZE {
+        return Ok(1);
+    }
+    Ok(data[VERSION_OFFSET])
+}
+
+/// Admin recorded in raw `VaultState` data of the given layout version
+pub fn vault_state_admin(data: &[u8], version: u8) -> Result<Pubkey> {
+    let offset = match version {
+        1 => VERSION_OFFSET,
+        2..=CURRENT_VAULT_VERSION => VERSION_OFFSET + 1,
+        _ => return err!(VaultError::UnsupportedVaultVersion),
+    };
+    Pubkey::try_from(&data[offset..offset + 32])
+        .map_err(|_| error!(VaultError::InvalidVaultState))
+}
+
+/// Upgrade raw `VaultState` data in place, one version at a time.
+/// `data` must already be resized to `VaultState::LEN`, with the bytes past
+/// the old layout zeroed.
+pub fn upgrade_vault_state(data: &mut [u8], from_version: u8) -> Result<()> {
+    require!(
+        data.len() == VaultState::LEN,
+        VaultError::InvalidVaultState
+    );
+
+    let mut version = from_version;
+    while version < CURRENT_VAULT_VERSION {
+        match version {
+            1 => upgrade_baseline(data),
+            // Fields taken from reserved space start out zeroed, which is
+            // their initial value
+            2..=4 => {}
+            5 => {
+                data.copy_within(V5_RESERVED_OFFSET..VAULT_STATE_V2_SIZE, V5_RESERVED_OFFSET + FEE_CHECKPOINT_LEN);
+                data[V5_RESERVED_OFFSET..V5_RESERVED_OFFSET + FEE_CHECKPOINT_LEN].fill(0);
+            }
+            _ => return err!(VaultError::UnsupportedVaultVersion),
+        }
+        version += 1;
+    }
+
+    data[VERSION_OFFSET] = CURRENT_VAULT_VERSION;
+    Ok(())
+}
+
+/// Rewrite baseline data as v2. Every field v2 adds starts zeroed, which
+/// leaves orders, books, limits and collateral empty and features off.
+fn upgrade_baseline(data: &mut [u8]) {
+    let fields_start = V2_VAULT_ID_OFFSET + 8;
+    let fields_end = fields_start + BASELINE_FIELDS.len();
+
+    // Move the later fields first, so the header shift cannot overwrite them
+    data.copy_within(BASELINE_FIELDS, fields_start);
+    data.copy_within(BASELINE_HEADER, VERSION_OFFSET + 1);
+    data[V2_VAULT_ID_OFFSET..fields_start].fill(0);
+    // The baseline reserved space, now past the moved fields
+    data[fields_end..].fill(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::vault_id_seed;
+
+    /// A vault account as the baseline program wrote it
+    const BASELINE_FIXTURE: &[u8] = include_bytes!("../tests/fixtures/vault_state_baseline.bin");
+
+    /// The baseline `VaultState`, field for field
+    #[derive(AnchorSerialize)]
+    struct BaselineVaultState {
+        admin: Pubkey,
+        bump: u8,
+        target_leverage: u8,
+        rebalance_threshold: u16,
+        max_slippage: u16,
+        total_assets: u64,
+        total_shares: u64,
+        long_position: i64,
+        short_position: i64,
+        total_fees_collected: u64,
+        last_rebalance_time: i64,
+        net_deposits: i64,
+        emergency_stop: bool,
+        max_capacity: u64,
+        drift_user_authority: Pubkey,
+        drift_user: Pubkey,
+        drift_user_stats: Pubkey,
+        management_fee: u16,
+        performance_fee: u16,
+        min_rebalance_interval: i64,
+        delta_threshold: u16,
+        reserved: [u64; 32],
+    }
+
+    fn upgraded(data: &[u8], from_version: u8) -> Vec<u8> {
+        let mut data = data.to_vec();
+        data.resize(VaultState::LEN, 0);
+        upgrade_vault_state(&mut data, from_version).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_baseline_fixture_matches_layout() {
+        let baseline = BaselineVaultState {
+            admin: Pubkey::new_from_array([1; 32]),
+            bump: 254,
+            target_leverage: 2,
+            rebalance_threshold: 100,
+            max_slippage: 50,
+            total_assets: 5_000_000,
+            total_shares: 4_000_000,
+            long_position: 1_000,
+            short_position: -1_000,
+            total_fees_collected: 12,
+            last_rebalance_time: 1_700_000_000,
+            net_deposits: 4_500_000,
+            emergency_stop: true,
+            max_capacity: 1_000_000_000_000,
+            drift_user_authority: Pubkey::new_from_array([2; 32]),
+            drift_user: Pubkey::new_from_array([3; 32]),
+            drift_user_stats: Pubkey::new_from_array([4; 32]),
+            management_fee: 200,
+            performance_fee: 2000,
+            min_rebalance_interval: 300,
+            delta_threshold: 100,
+            reserved: [0; 32],
+        };
+        let mut data = VaultState::discriminator().to_vec();
+        baseline.serialize(&mut data).unwrap();
+
+        assert_eq!(data.len(), BASELINE_VAULT_STATE_SIZE);
+        assert_eq!(data, BASELINE_FIXTURE);
+    }
+
+    #[test]
+    fn test_detect_baseline_layout() {
+        assert_eq!(vault_state_version(BASELINE_FIXTURE).unwrap(), 1);
+        assert_eq!(vault_state_admin(BASELINE_FIXTURE, 1).unwrap(), Pubkey::new_from_array([1; 32]));
+    }
+
+    #[test]
+    fn test_upgrade_baseline_fixture() {
+        let data = upgraded(BASELINE_FIXTURE, 1);
+        assert_eq!(vault_state_version(&data).unwrap(), CURRENT_VAULT_VERSION);
+        assert_eq!(
+            vault_state_admin(&data, CURRENT_VAULT_VERSION).unwrap(),
+            Pubkey::new_from_array([1; 32])
+        );
+
+        let vault: &VaultState = bytemuck::from_bytes(&data[8..]);
+        assert_eq!({ vault.version }, CURRENT_VAULT_VERSION);
+        assert_eq!({ vault.admin }, Pubkey::new_from_array([1; 32]));
+        assert_eq!({ vault.bump }, 254);
+        // Baseline vaults live at the legacy `[VAULT_SEED, admin]` address
+        assert_eq!({ vault.vault_id }, 0);
+        assert!(vault_id_seed(vault.vault_id).is_empty());
+        assert_eq!({ vault.target_leverage }, 2);
+        assert_eq!({ vault.rebalance_threshold }, 100);
+        assert_eq!({ vault.max_slippage }, 50);
+        assert_eq!({ vault.total_assets }, 5_000_000);
+        assert_eq!({ vault.total_shares }, 4_000_000);
+        assert_eq!({ vault.long_position }, 1_000);
+        assert_eq!({ vault.short_position }, -1_000);
+        assert_eq!({ vault.total_fees_collected }, 12);
+        assert_eq!({ vault.last_rebalance_time }, 1_700_000_000);
+        assert_eq!({ vault.net_deposits }, 4_500_000);
+        assert!(vault.is_emergency_stopped());
+        assert_eq!({ vault.max_capacity }, 1_000_000_000_000);
+        assert_eq!({ vault.drift_user_authority }, Pubkey::new_from_array([2; 32]));
+        assert_eq!({ vault.drift_user }, Pubkey::new_from_array([3; 32]));
+        assert_eq!({ vault.drift_user_stats }, Pubkey::new_from_array([4; 32]));
+        assert_eq!({ vault.management_fee }, 200);
+        assert_eq!({ vault.performance_fee }, 2000);
+        assert_eq!({ vault.min_rebalance_interval }, 300);
+        assert_eq!({ vault.delta_threshold }, 100);
+
+        // Everything added since starts empty or off
+        assert!(vault.open_orders.iter().all(|o| o.is_empty()));
+        assert!(vault.market_books.iter().all(|b| !b.is_initialized()));
+        assert!(!vault.reverts_on_slippage());
+        assert!(!vault.is_allowlist_enabled());
+        assert_eq!({ vault.lockup_period }, 0);
+        assert_eq!({ vault.max_epoch_inflow }, 0);
+        assert!(vault.collaterals.iter().all(|c| c.is_empty()));
+        assert_eq!({ vault.referral_fees_owed }, 0);
+        assert_eq!({ vault.event_sequence }, 0);
+        assert_eq!({ vault.fees_per_share }, 0);
+        assert!(vault.drift_sub_accounts.iter().all(|s| s.is_empty()));
+        assert_eq!(vault.drift_sub_account_slot(&Pubkey::new_from_array([3; 32])), Some(0));
+        // `migrate_vault` seeds the fee checkpoint after the raw upgrade
+        assert_eq!({ vault.last_fee_collection_time }, 0);
+        assert_eq!({ vault.high_water_mark }, 0);
+        assert_eq!({ vault.reserved }, [0; 1]);
+    }
+
+    #[test]
+    fn test_upgrade_inserts_fee_checkpoint() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.admin = Pubkey::new_from_array([1; 32]);
+        vault.vault_id = 3;
+        vault.event_sequence = 9;
+        vault.fees_per_share = 11;
+        vault.market_sub_accounts = [1, 0, 2, 0];
+        let mut current = VaultState::discriminator().to_vec();
+        current.extend_from_slice(bytemuck::bytes_of(&vault));
+        assert_eq!(current.len(), VAULT_STATE_V2_SIZE + FEE_CHECKPOINT_LEN);
+
+        // The same vault without the checkpoint, as v2 through v5 wrote it
+        let mut old = current.clone();
+        old.drain(V5_RESERVED_OFFSET..V5_RESERVED_OFFSET + FEE_CHECKPOINT_LEN);
+        assert_eq!(old.len(), VAULT_STATE_V2_SIZE);
+
+        for from_version in 2..CURRENT_VAULT_VERSION {
+            old[VERSION_OFFSET] = from_version;
+            assert_eq!(vault_state_version(&old).unwrap(), from_version);
+            assert_eq!(vault_state_admin(&old, from_version).unwrap(), Pubkey::new_from_array([1; 32]));
+
+            current[VERSION_OFFSET] = CURRENT_VAULT_VERSION;
+            assert_eq!(upgraded(&old, from_version), current);
+        }
+    }
+
+    #[test]
+    fn test_upgrade_rejects_unknown_version() {
+        let mut data = upgraded(BASELINE_FIXTURE, 1);
+        assert!(upgrade_vault_state(&mut data, 0).is_err());
+        assert!(upgrade_vault_state(&mut data[..VAULT_STATE_V2_SIZE], 2).is_err());
+        assert!(vault_state_admin(&data, CURRENT_VAULT_VERSION + 1).is_err());
+    }
+}
@@ -1,9 +1,22 @@
 use anchor_lang::prelude::*;
 
-#[account]
+use crate::constants::*;
+use crate::errors::*;
+use crate::math::*;
+use crate::utils::{
+    calculate_annualized_return_bps, calculate_fill_price, calculate_position_after_trade,
+    calculate_share_price, calculate_shares_to_burn, calculate_shares_to_mint, calculate_withdrawal_amount,
+};
+
+/// Zero-copy so instructions read the fields they need without
+/// deserializing the whole account. The packed layout is byte-for-byte the
+/// Borsh layout of earlier versions, see `migrations`.
+#[account(zero_copy(unsafe))]
 pub struct VaultState {
+    pub version: u8,                 // Layout version, see `migrations`
     pub admin: Pubkey,
     pub bump: u8,
+    pub vault_id: u64,               // Index of this vault under its admin, part of the PDA seeds
     
     // Vault parameters
     pub target_leverage: u8,          // Target leverage (e.g., 2x = 2)
@@ -22,7 +35,7 @@ pub struct VaultState {
     pub net_deposits: i64,           // Net deposits (deposits - withdrawals)
     
     // Risk management
-    pub emergency_stop: bool,        // Emergency stop flag
+    pub emergency_stop: u8,          // Emergency stop flag, see `is_emergency_stopped`
     pub max_capacity: u64,          // Maximum vault capacity
     
     // Drift integration
@@ -38,84 +51,413 @@ pub struct VaultState {
     pub min_rebalance_interval: i64, // Minimum time between rebalances
     pub delta_threshold: u16,        // Delta threshold for rebalancing (basis points)
     
+    // Order tracking
+    pub open_orders: [OpenOrder; MAX_OPEN_ORDERS], // Outstanding Drift orders
+    pub last_position_sync_slot: u64, // Slot of last reconciliation against Drift
+    
+    // Execution quality
+    pub revert_on_slippage: u8,      // Revert hedges that fill outside max_slippage, see `reverts_on_slippage`
+    pub slippage_breach_count: u32,  // Fills flagged for exceeding max_slippage
+    
+    // Per-market accounting
+    pub market_books: [MarketBook; MAX_MARKETS], // Cost basis, PnL, funding and fees per market
+    pub last_total_fee_paid: u64,    // Drift user stats fee total at last observation
+    
+    // Access control
+    pub allowlist_enabled: u8,       // Only allowlisted depositors may deposit, see `is_allowlist_enabled`
+    pub allowlist_root: [u8; 32],    // Merkle root of (depositor, deposit cap) leaves
+    
+    // Withdrawal policy
+    pub lockup_period: i64,          // Seconds a deposit must stay in the vault
+    pub early_exit_fee_bps: u16,     // Fee on exits inside the lockup, 0 rejects them
+    
+    // Capacity and flow limits
+    pub max_user_deposit: u64,       // Net deposit cap per user, 0 for no cap
+    pub epoch_duration: i64,         // Length of a flow-limit epoch in seconds
+    pub epoch_start: i64,            // Start of the current flow-limit epoch
+    pub epoch_net_flow: i64,         // Deposits minus withdrawals this epoch
+    pub max_epoch_inflow: u64,       // Net inflow limit per epoch, 0 for no limit
+    pub max_epoch_outflow: u64,      // Net outflow limit per epoch, 0 for no limit
+    
+    // Collateral
+    pub collaterals: [CollateralConfig; MAX_COLLATERALS], // Non-base collateral held on Drift
+    
+    // Referrals
+    pub referral_fee_share_bps: u16, // Share of fees on referred shares rebated to referrers
+    pub referred_shares: u64,        // Shares held by users with a referrer
+    pub referral_fees_per_share: u128, // Cumulative referral fees per referred share, scaled by REFERRAL_FEE_PRECISION
+    pub referral_fees_owed: u64,     // Accrued referral fees held in the vault token account
+    
+    // Events
+    pub event_sequence: u64,         // Sequence number of the last event emitted for this vault
+    
+    // Per-user fee attribution
+    pub fees_per_share: u128,        // Cumulative collected fees per share, scaled by FEES_PER_SHARE_PRECISION
+    
+    // Drift sub-accounts
+    pub drift_sub_accounts: [DriftSubAccount; MAX_DRIFT_SUB_ACCOUNTS], // Every Drift user of the vault, slot 0 is `drift_user`
+    pub market_sub_accounts: [u8; MAX_MARKETS], // Sub-account slot trading each entry of `market_books`
+    
+    // Fee checkpoint
+    pub last_fee_collection_time: i64, // Management fees accrue from here
+    pub high_water_mark: u64,        // Highest share price performance fees were charged up to
+    
     // Reserved for future use
-    pub reserved: [u64; 32],
+    pub reserved: [u32; 1],
+}
+
+/// Vault id bytes in the vault PDA seeds `[VAULT_SEED, admin, vault_id]`.
+/// Vault 0 seeds nothing, so its address is the `[VAULT_SEED, admin]` PDA
+/// that vaults created before vault ids already live at.
+pub fn vault_id_seed(vault_id: u64) -> Vec<u8> {
+    if vault_id == 0 {
+        Vec::new()
+    } else {
+        vault_id.to_le_bytes().to_vec()
+    }
 }
 
 impl VaultState {
-    pub const LEN: usize = 8 +        // discriminator
-        32 +                          // admin
-        1 +                           // bump
-        1 +                           // target_leverage
-        2 +                           // rebalance_threshold
-        2 +                           // max_slippage
-        8 +                           // total_assets
-        8 +                           // total_shares
-        8 +                           // long_position
-        8 +                           // short_position
-        8 +                           // total_fees_collected
-        8 +                           // last_rebalance_time
-        8 +                           // net_deposits
-        1 +                           // emergency_stop
-        8 +                           // max_capacity
-        32 +                          // drift_user_authority
-        32 +                          // drift_user
-        32 +                          // drift_user_stats
-        2 +                           // management_fee
-        2 +                           // performance_fee
-        8 +                           // min_rebalance_interval
-        2 +                           // delta_threshold
-        (32 * 8);                     // reserved
+    pub const LEN: usize = 8 + std::mem::size_of::<VaultState>();
+
+    pub fn vault_id_seed(&self) -> Vec<u8> {
+        vault_id_seed(self.vault_id)
+    }
 
     pub fn calculate_delta(&self) -> Result<i64> {
         // Calculate current delta of the vault
         // Delta = long_position + short_position
         // For delta neutral, this should be close to 0
-        Ok(self.long_position + self.short_position)
+        self.long_position.safe_add(self.short_position)
     }
 
+    /// NAV: base asset cash plus the haircut value of other collateral.
+    /// Collateral moved into any Drift sub-account counts at its cached
+    /// value, but the sub-accounts' unrealized perp PnL, unsettled funding
+    /// and margin beyond that collateral are not included.
     pub fn calculate_total_value(&self) -> Result<u64> {
-        // Calculate total value including positions and cash
-        // This would need to fetch current market prices
-        // For now, return total_assets as placeholder
-        Ok(self.total_assets)
+        self.total_assets.safe_add(self.collateral_value()?)
+    }
+
+    /// Haircut value of all non-base collateral as of its last valuation
+    pub fn collateral_value(&self) -> Result<u64> {
+        self.collaterals
+            .iter()
+            .filter(|c| !c.is_empty())
+            .try_fold(0u64, |total, c| total.safe_add(c.value))
+    }
+
+    /// Whether every held collateral was valued recently enough to price shares
+    pub fn is_collateral_fresh(&self, current_slot: u64) -> bool {
+        self.collaterals
+            .iter()
+            .filter(|c| !c.is_empty() && c.amount > 0)
+            .all(|c| current_slot.saturating_sub(c.last_valued_slot) <= MAX_COLLATERAL_VALUATION_AGE_SLOTS)
+    }
+
+    pub fn collateral_mut(&mut self, mint: &Pubkey) -> Option<&mut CollateralConfig> {
+        self.collaterals
+            .iter_mut()
+            .find(|c| !c.is_empty() && c.mint == *mint)
+    }
+
+    /// Add a collateral asset, or return the existing entry for the mint
+    pub fn collateral_entry(&mut self, mint: &Pubkey) -> Result<&mut CollateralConfig> {
+        let slot = match self
+            .collaterals
+            .iter()
+            .position(|c| !c.is_empty() && c.mint == *mint)
+        {
+            Some(slot) => slot,
+            None => {
+                let slot = self
+                    .collaterals
+                    .iter()
+                    .position(|c| c.is_empty())
+                    .ok_or(VaultError::TooManyCollaterals)?;
+                self.collaterals[slot] = CollateralConfig {
+                    mint: *mint,
+                    ..CollateralConfig::default()
+                };
+                slot
+            }
+        };
+
+        Ok(&mut self.collaterals[slot])
     }
 
+    /// Price of one share in SHARE_PRICE_PRECISION, on the same virtual
+    /// offsets `shares_for_deposit` and `quote_redeem` use
     pub fn calculate_share_price(&self) -> Result<u64> {
+        calculate_share_price(self.calculate_total_value()?, self.total_shares)
+    }
+
+    /// Start fee accrual from `current_time` at the current share price, so
+    /// nothing earned before the checkpoint is charged
+    pub fn reset_fee_checkpoint(&mut self, current_time: i64) -> Result<()> {
+        self.last_fee_collection_time = current_time;
+        self.high_water_mark = self.calculate_share_price()?;
+        Ok(())
+    }
+
+    /// Referrers' cut of `total_fees`: the fees attributable to referred
+    /// shares times the referral fee share
+    pub fn referral_fees_for(&self, total_fees: u64) -> Result<u64> {
+        if self.referred_shares == 0 || self.total_shares == 0 {
+            return Ok(0);
+        }
+        let attributable = mul_div(total_fees, self.referred_shares, self.total_shares, Rounding::Down)?;
+        bps_of(attributable, self.referral_fee_share_bps, Rounding::Down)
+    }
+
+    /// Spread referral fees over the referred shares
+    pub fn accrue_referral_fees(&mut self, referral_fees: u64) -> Result<()> {
+        let per_share = (referral_fees as u128)
+            .safe_mul(REFERRAL_FEE_PRECISION)?
+            .safe_div(self.referred_shares as u128)?;
+        self.referral_fees_per_share = self.referral_fees_per_share.safe_add(per_share)?;
+        self.referral_fees_owed = self.referral_fees_owed.safe_add(referral_fees)?;
+        Ok(())
+    }
+
+    /// Spread collected vault fees over all shares so each user's share
+    /// can be attributed to them
+    pub fn accrue_fees_per_share(&mut self, total_fees: u64) -> Result<()> {
         if self.total_shares == 0 {
-            return Ok(1_000_000); // Initial share price (6 decimals)
+            return Ok(());
         }
-        
-        let total_value = self.calculate_total_value()?;
-        Ok((total_value as u128 * 1_000_000 / self.total_shares as u128) as u64)
+        let per_share = (total_fees as u128)
+            .safe_mul(FEES_PER_SHARE_PRECISION)?
+            .safe_div(self.total_shares as u128)?;
+        self.fees_per_share = self.fees_per_share.safe_add(per_share)?;
+        Ok(())
     }
 
-    pub fn needs_rebalance(&self) -> Result<bool> {
+    /// Absolute delta as a share of total value in basis points, 0 for an empty vault
+    pub fn delta_bps(&self) -> Result<u64> {
         let delta = self.calculate_delta()?;
         let total_value = self.calculate_total_value()?;
         
         if total_value == 0 {
-            return Ok(false);
+            return Ok(0);
         }
         
-        // Calculate delta as percentage of total value
-        let delta_percentage = (delta.abs() as u128 * 10_000 / total_value as u128) as u16;
-        
-        Ok(delta_percentage > self.delta_threshold)
+        mul_div(delta.unsigned_abs(), BASIS_POINTS_DIVISOR, total_value, Rounding::Down)
+    }
+
+    pub fn needs_rebalance(&self) -> Result<bool> {
+        Ok(self.delta_bps()? > self.delta_threshold as u64)
+    }
+
+    /// Shares minted for `amount` base tokens, as `deposit` mints them
+    pub fn shares_for_deposit(&self, amount: u64) -> Result<u64> {
+        let shares = calculate_shares_to_mint(amount, self.calculate_total_value()?, self.total_shares)?;
+        require!(shares > 0, VaultError::InvalidShareCalculation);
+        Ok(shares)
+    }
+
+    /// Shares minted for `amount`, refused below the caller's `min_shares_out`
+    pub fn shares_for_deposit_at_least(&self, amount: u64, min_shares_out: u64) -> Result<u64> {
+        let shares = self.shares_for_deposit(amount)?;
+        require_at_least!(shares, min_shares_out, VaultError::SlippageExceeded);
+        Ok(shares)
+    }
+
+    /// Shares burned and fee charged to withdraw `amount` base tokens, as
+    /// `withdraw` executes it. `locked` is whether the owner is inside the lockup.
+    pub fn quote_withdraw(&self, amount: u64, locked: bool) -> Result<WithdrawalQuote> {
+        // Withdrawals are paid in the base asset only, see `withdraw_collateral`
+        require_at_most!(amount, self.total_assets, VaultError::InsufficientFunds);
+
+        // Round up so the withdrawer pays for any dust
+        let shares_burned = calculate_shares_to_burn(amount, self.calculate_total_value()?, self.total_shares)?;
+        self.withdrawal_quote(shares_burned, amount, locked)
+    }
+
+    /// Base tokens paid out for redeeming `shares`, net of any early-exit fee
+    pub fn quote_redeem(&self, shares: u64, locked: bool) -> Result<WithdrawalQuote> {
+        let amount = calculate_withdrawal_amount(shares, self.calculate_total_value()?, self.total_shares)?;
+        require_at_most!(amount, self.total_assets, VaultError::InsufficientFunds);
+        self.withdrawal_quote(shares, amount, locked)
+    }
+
+    fn withdrawal_quote(&self, shares_burned: u64, amount: u64, locked: bool) -> Result<WithdrawalQuote> {
+        // Base-asset exits are capped at the shares' slice of the base asset,
+        // so early exits cannot leave later ones holding only collateral
+        require_at_most!(amount, self.liquid_share(shares_burned)?, VaultError::InsufficientFunds);
+
+        // Exits inside the lockup pay the early-exit fee, or are refused if none is set
+        let early_exit_fee = if locked {
+            require!(self.early_exit_fee_bps > 0, VaultError::WithdrawalLocked);
+            bps_of(amount, self.early_exit_fee_bps, Rounding::Up)?
+        } else {
+            0
+        };
+
+        Ok(WithdrawalQuote {
+            shares_burned,
+            amount,
+            early_exit_fee,
+            amount_out: amount.safe_sub(early_exit_fee)?,
+        })
+    }
+
+    /// Pro-rata slice of the base asset and of every held collateral for
+    /// `shares`, as `withdraw_collateral` pays it out. Rounds down so the
+    /// remaining holders keep the dust.
+    pub fn quote_in_kind(&self, shares: u64) -> Result<InKindQuote> {
+        require!(shares > 0, VaultError::InvalidAmount);
+        require_at_most!(shares, self.total_shares, VaultError::InsufficientShares);
+
+        let base_amount = mul_div(shares, self.total_assets, self.total_shares, Rounding::Down)?;
+        let mut quote = InKindQuote {
+            shares_burned: shares,
+            base_amount,
+            value: base_amount,
+            ..InKindQuote::default()
+        };
+        for (slot, collateral) in self.collaterals.iter().enumerate() {
+            if collateral.is_empty() || collateral.amount == 0 {
+                continue;
+            }
+            quote.collateral_amounts[slot] = mul_div(shares, collateral.amount, self.total_shares, Rounding::Down)?;
+            quote.collateral_values[slot] = mul_div(shares, collateral.value, self.total_shares, Rounding::Down)?;
+            quote.value = quote.value.safe_add(quote.collateral_values[slot])?;
+        }
+        Ok(quote)
+    }
+
+    /// Base assets backing `shares`, priced like a redemption but off the
+    /// base asset alone. Equal to the redemption amount while the vault
+    /// holds no collateral.
+    pub fn liquid_share(&self, shares: u64) -> Result<u64> {
+        calculate_withdrawal_amount(shares, self.total_assets, self.total_shares)
+    }
+
+    pub fn nav_breakdown(&self, current_slot: u64) -> Result<NavBreakdown> {
+        Ok(NavBreakdown {
+            base_assets: self.total_assets,
+            collateral_value: self.collateral_value()?,
+            total_value: self.calculate_total_value()?,
+            total_shares: self.total_shares,
+            share_price: self.calculate_share_price()?,
+            collateral_fresh: self.is_collateral_fresh(current_slot),
+        })
+    }
+
+    /// Only a fully unwound vault may be closed: no shares, positions, open
+    /// orders, collateral or unclaimed referral fees. Of `token_balance`, only
+    /// rounding dust up to MAX_CLOSE_DUST may remain for `close_vault` to sweep.
+    pub fn ensure_closable(&self, token_balance: u64) -> Result<()> {
+        require_eq!({ self.total_shares }, 0, VaultError::VaultNotEmpty);
+        require_eq!({ self.referral_fees_owed }, 0, VaultError::VaultNotEmpty);
+        require_at_most!(token_balance, MAX_CLOSE_DUST, VaultError::VaultNotEmpty);
+        require_eq!({ self.long_position }, 0, VaultError::PositionsOpen);
+        require_eq!({ self.short_position }, 0, VaultError::PositionsOpen);
+        if let Some(book) = self.market_books.iter().find(|b| b.is_initialized() && b.base_position != 0) {
+            msg!("Market {} position: {}", { book.market_index }, { book.base_position });
+            return err!(VaultError::PositionsOpen);
+        }
+        if let Some(order) = self.open_orders.iter().find(|o| !o.is_empty()) {
+            msg!("Market {} order {} is open", { order.market_index }, { order.order_id });
+            return err!(VaultError::OrdersOpen);
+        }
+        for collateral in self.collaterals.iter().filter(|c| !c.is_empty()) {
+            require_eq!({ collateral.amount }, 0, VaultError::VaultNotEmpty);
+        }
+        Ok(())
+    }
+
+    pub fn delta_exposure(&self) -> Result<DeltaExposure> {
+        Ok(DeltaExposure {
+            long_position: self.long_position,
+            short_position: self.short_position,
+            delta: self.calculate_delta()?,
+            delta_bps: self.delta_bps()?,
+            delta_threshold: self.delta_threshold,
+            needs_rebalance: self.needs_rebalance()?,
+        })
+    }
+
+    // Flags are stored as `u8` so any byte in the account is a valid value;
+    // a zero-copy `bool` holding anything but 0 or 1 is undefined behaviour
+
+    pub fn is_emergency_stopped(&self) -> bool {
+        self.emergency_stop != 0
+    }
+
+    pub fn reverts_on_slippage(&self) -> bool {
+        self.revert_on_slippage != 0
+    }
+
+    pub fn is_allowlist_enabled(&self) -> bool {
+        self.allowlist_enabled != 0
+    }
+
+    pub fn is_admin(&self, key: &Pubkey) -> bool {
+        self.admin == *key
     }
 
     pub fn can_rebalance(&self, current_time: i64) -> bool {
-        if self.emergency_stop {
+        if self.is_emergency_stopped() {
             return false;
         }
         
-        current_time - self.last_rebalance_time >= self.min_rebalance_interval
+        current_time.saturating_sub(self.last_rebalance_time) >= self.min_rebalance_interval
+    }
+
+    /// Add a signed deposit (+) or withdrawal (-) to the current epoch's net flow.
+    pub fn record_flow(&mut self, current_time: i64, flow: i64) -> Result<()> {
+        let net_flow = self.check_flow(current_time, flow)?;
+        if self.is_flow_epoch_over(current_time)? {
+            self.epoch_start = current_time;
+        }
+        self.epoch_net_flow = net_flow;
+        Ok(())
+    }
+
+    /// The epoch's net flow once `flow` is added, without recording it.
+    /// Only the limit in the direction of the flow is enforced, so flows that
+    /// move the net back toward zero always pass.
+    pub fn check_flow(&self, current_time: i64, flow: i64) -> Result<i64> {
+        let net_flow = if self.is_flow_epoch_over(current_time)? {
+            flow
+        } else {
+            self.epoch_net_flow.safe_add(flow)?
+        };
+        if flow > 0 && self.max_epoch_inflow > 0 {
+            let max_inflow = to_i64(self.max_epoch_inflow)?;
+            require_at_most!(net_flow, max_inflow, VaultError::FlowLimitExceeded);
+        }
+        if flow < 0 && self.max_epoch_outflow > 0 {
+            let max_outflow = -to_i64(self.max_epoch_outflow)?;
+            require_at_least!(net_flow, max_outflow, VaultError::FlowLimitExceeded);
+        }
+
+        Ok(net_flow)
+    }
+
+    fn is_flow_epoch_over(&self, current_time: i64) -> Result<bool> {
+        Ok(current_time >= self.epoch_start.safe_add(self.epoch_duration)?)
+    }
+
+    /// Flow limits need a non-zero epoch, otherwise every flow starts a new
+    /// epoch and the limits only bound single transactions.
+    pub fn validate_flow_limits(&self) -> Result<()> {
+        if self.max_epoch_inflow > 0 || self.max_epoch_outflow > 0 {
+            require!(self.epoch_duration > 0, VaultError::InvalidCapacityLimits);
+        }
+        Ok(())
+    }
+
+    pub fn is_position_book_fresh(&self, current_slot: u64) -> bool {
+        current_slot.saturating_sub(self.last_position_sync_slot) <= MAX_POSITION_BOOK_AGE_SLOTS
     }
 
     pub fn calculate_required_hedge(&self) -> Result<HedgeCalculation> {
         let delta = self.calculate_delta()?;
         
-        if delta.abs() < 1000 { // Small delta threshold
+        if delta.unsigned_abs() < 1000 { // Small delta threshold
             return Ok(HedgeCalculation {
                 action: HedgeAction::None,
                 amount: 0,
@@ -125,10 +467,10 @@ impl VaultState {
         
         let (action, amount, direction) = if delta > 0 {
             // Too much long exposure, need to short
-            (HedgeAction::IncreaseShort, delta.abs() as u64, PositionDirection::Short)
+            (HedgeAction::IncreaseShort, delta.unsigned_abs(), PositionDirection::Short)
         } else {
             // Too much short exposure, need to long
-            (HedgeAction::IncreaseLong, delta.abs() as u64, PositionDirection::Long)
+            (HedgeAction::IncreaseLong, delta.unsigned_abs(), PositionDirection::Long)
         };
         
         Ok(HedgeCalculation {
@@ -137,6 +479,351 @@ impl VaultState {
             direction,
         })
     }
+
+    /// Sequence number for the next event emitted for this vault. Indexers
+    /// use the gapless sequence to detect events they missed.
+    pub fn next_event_sequence(&mut self) -> u64 {
+        self.event_sequence = self.event_sequence.wrapping_add(1);
+        self.event_sequence
+    }
+
+    /// Stop tracking a sub-account's orders that outlived their `max_ts`,
+    /// pairing each with the sequence of the event that reports it
+    pub fn take_stale_orders(&mut self, sub_account: usize, current_time: i64) -> Vec<(OpenOrder, u64)> {
+        let mut stale_orders = Vec::new();
+        for slot in 0..MAX_OPEN_ORDERS {
+            let order = self.open_orders[slot];
+            if order.is_stale(current_time) && self.is_order_on_sub_account(&order, sub_account) {
+                self.open_orders[slot] = OpenOrder::default();
+                stale_orders.push((order, self.next_event_sequence()));
+            }
+        }
+        stale_orders
+    }
+
+    /// Record a newly placed Drift order in the first free slot
+    pub fn track_order(&mut self, order: OpenOrder) -> Result<()> {
+        let slot = self
+            .open_orders
+            .iter()
+            .position(|o| o.is_empty())
+            .ok_or(VaultError::TooManyOpenOrders)?;
+        
+        self.open_orders[slot] = order;
+        Ok(())
+    }
+
+    /// Book the newly filled size of a tracked order into the position legs.
+    /// Only size that Drift reports as filled ever reaches the book. Returns
+    /// the average price of the new fill, if there was one.
+    pub fn apply_fill(&mut self, slot: usize, filled_amount: u64, quote_filled: u64) -> Result<Option<u64>> {
+        let order = self.open_orders[slot];
+        if filled_amount <= order.filled_amount {
+            return Ok(None);
+        }
+        
+        let base_delta = filled_amount.safe_sub(order.filled_amount)?;
+        let quote_delta = quote_filled.saturating_sub(order.quote_filled);
+        let new_fill = to_i64(base_delta)?;
+        let (long_change, short_change) = match (order.direction()?, order.is_reduce_only()) {
+            (PositionDirection::Long, false) => (new_fill, 0),
+            (PositionDirection::Long, true) => (0, new_fill),
+            (PositionDirection::Short, false) => (0, -new_fill),
+            (PositionDirection::Short, true) => (-new_fill, 0),
+        };
+        self.backfill_primary_sub_account();
+        self.long_position = self.long_position.safe_add(long_change)?;
+        self.short_position = self.short_position.safe_add(short_change)?;
+
+        // Keep the placing sub-account's legs in step until its next sync
+        let sub_account_slot = self.market_sub_account(order.market_index).unwrap_or(0);
+        let sub_account = &mut self.drift_sub_accounts[sub_account_slot];
+        if !sub_account.is_empty() {
+            sub_account.long_position = sub_account.long_position.safe_add(long_change)?;
+            sub_account.short_position = sub_account.short_position.safe_add(short_change)?;
+        }
+        
+        self.open_orders[slot].filled_amount = filled_amount;
+        self.open_orders[slot].quote_filled = quote_filled.max(order.quote_filled);
+        Ok(Some(calculate_fill_price(base_delta, quote_delta)?))
+    }
+
+    /// Find the accounting book for a market, opening one if needed
+    pub fn market_book_mut(&mut self, market_index: u16) -> Result<&mut MarketBook> {
+        let slot = self.market_book_slot(market_index)?;
+        Ok(&mut self.market_books[slot])
+    }
+
+    fn market_book_slot(&mut self, market_index: u16) -> Result<usize> {
+        let slot = match self
+            .market_books
+            .iter()
+            .position(|b| b.is_initialized() && b.market_index == market_index)
+        {
+            Some(slot) => slot,
+            None => {
+                let slot = self
+                    .market_books
+                    .iter()
+                    .position(|b| !b.is_initialized())
+                    .ok_or(VaultError::TooManyMarkets)?;
+                self.market_books[slot] = MarketBook {
+                    market_index,
+                    initialized: 1,
+                    ..MarketBook::default()
+                };
+                self.market_sub_accounts[slot] = 0;
+                slot
+            }
+        };
+        
+        Ok(slot)
+    }
+
+    /// Slot of a Drift user in `drift_sub_accounts`. Vaults created before
+    /// sub-accounts only recorded `drift_user`, which is always slot 0.
+    pub fn drift_sub_account_slot(&self, user: &Pubkey) -> Option<usize> {
+        if *user == Pubkey::default() {
+            return None;
+        }
+        self.drift_sub_accounts
+            .iter()
+            .position(|s| s.user == *user)
+            .or_else(|| (*user == self.drift_user).then_some(0))
+    }
+
+    /// Record a new Drift user, which becomes `drift_user` if it is the first
+    pub fn add_drift_sub_account(&mut self, user: Pubkey) -> Result<usize> {
+        if self.drift_user == Pubkey::default() {
+            self.drift_user = user;
+        }
+        self.backfill_primary_sub_account();
+        if let Some(slot) = self.drift_sub_account_slot(&user) {
+            return Ok(slot);
+        }
+
+        let slot = self
+            .drift_sub_accounts
+            .iter()
+            .position(|s| s.is_empty())
+            .ok_or(VaultError::TooManySubAccounts)?;
+        self.drift_sub_accounts[slot] = DriftSubAccount {
+            user,
+            ..DriftSubAccount::default()
+        };
+        Ok(slot)
+    }
+
+    /// Give a vault created before sub-accounts its `drift_user` entry, which
+    /// holds all of the vault's positions
+    fn backfill_primary_sub_account(&mut self) {
+        if self.drift_sub_accounts[0].is_empty() && self.drift_user != Pubkey::default() {
+            self.drift_sub_accounts[0] = DriftSubAccount {
+                user: self.drift_user,
+                long_position: self.long_position,
+                short_position: self.short_position,
+                last_sync_slot: self.last_position_sync_slot,
+            };
+        }
+    }
+
+    /// Sub-account slot that trades a market, if the market has a book
+    pub fn market_sub_account(&self, market_index: u16) -> Option<usize> {
+        self.market_books
+            .iter()
+            .position(|b| b.is_initialized() && b.market_index == market_index)
+            .map(|slot| self.market_sub_accounts[slot] as usize)
+    }
+
+    /// Route a market to a sub-account. Each market is traded from a single
+    /// sub-account, and only moves to another once it is flat with no open orders.
+    pub fn route_market(&mut self, market_index: u16, sub_account: usize) -> Result<()> {
+        let slot = self.market_book_slot(market_index)?;
+        let current = self.market_sub_accounts[slot] as usize;
+        if current == sub_account {
+            return Ok(());
+        }
+
+        let has_open_orders = self
+            .open_orders
+            .iter()
+            .any(|o| !o.is_empty() && o.market_index == market_index);
+        if self.market_books[slot].base_position != 0 || has_open_orders {
+            msg!("Market {} is traded by sub-account slot {}", market_index, current);
+            return err!(VaultError::MarketOnOtherSubAccount);
+        }
+        self.market_sub_accounts[slot] = sub_account as u8;
+        Ok(())
+    }
+
+    /// Whether a tracked order was placed from the given sub-account slot
+    pub fn is_order_on_sub_account(&self, order: &OpenOrder, sub_account: usize) -> bool {
+        self.market_sub_account(order.market_index).unwrap_or(0) == sub_account
+    }
+
+    /// Store a sub-account's positions as reported by Drift and re-aggregate
+    /// the vault's legs across all sub-accounts
+    pub fn sync_sub_account(&mut self, slot: usize, long_position: i64, short_position: i64, current_slot: u64) -> Result<()> {
+        self.backfill_primary_sub_account();
+        let sub_account = &mut self.drift_sub_accounts[slot];
+        sub_account.long_position = long_position;
+        sub_account.short_position = short_position;
+        sub_account.last_sync_slot = current_slot;
+
+        let mut total_long = 0i64;
+        let mut total_short = 0i64;
+        let mut oldest_sync_slot = current_slot;
+        for sub_account in self.drift_sub_accounts.iter().filter(|s| !s.is_empty()) {
+            total_long = total_long.safe_add(sub_account.long_position)?;
+            total_short = total_short.safe_add(sub_account.short_position)?;
+            oldest_sync_slot = oldest_sync_slot.min(sub_account.last_sync_slot);
+        }
+
+        self.long_position = total_long;
+        self.short_position = total_short;
+        // The book is only as fresh as its least recently synced sub-account
+        self.last_position_sync_slot = oldest_sync_slot;
+        Ok(())
+    }
+
+    pub fn market_book(&self, market_index: u16) -> Option<&MarketBook> {
+        self.market_books
+            .iter()
+            .find(|b| b.is_initialized() && b.market_index == market_index)
+    }
+
+    /// Side of the market's booked position and how much of it a close of
+    /// `size` reduces. Shorts are booked as negative `base_position`.
+    pub fn position_to_close(&self, market_index: u16, size: u64) -> Result<(PositionDirection, u64)> {
+        let base_position = self
+            .market_book(market_index)
+            .map(|book| book.base_position)
+            .unwrap_or(0);
+        let direction = match base_position {
+            0 => return err!(VaultError::NoPositionToClose),
+            p if p > 0 => PositionDirection::Long,
+            _ => PositionDirection::Short,
+        };
+        require!(size > 0, VaultError::InvalidAmount);
+        Ok((direction, size.min(base_position.unsigned_abs())))
+    }
+
+    /// Update cost basis for a fill and return the PnL it realized
+    pub fn record_trade(&mut self, market_index: u16, trade_base: i64, trade_price: u64) -> Result<i64> {
+        let book = self.market_book_mut(market_index)?;
+        let (position, average_entry_price, realized_pnl) = calculate_position_after_trade(
+            book.base_position,
+            book.average_entry_price,
+            trade_base,
+            trade_price,
+        )?;
+        
+        book.base_position = position;
+        book.average_entry_price = average_entry_price;
+        book.realized_pnl = book.realized_pnl.safe_add(realized_pnl)?;
+        Ok(realized_pnl)
+    }
+
+    pub fn has_open_orders(&self) -> bool {
+        self.open_orders.iter().any(|o| !o.is_empty())
+    }
+}
+
+#[zero_copy(unsafe)]
+#[derive(Debug, Default, PartialEq)]
+pub struct OpenOrder {
+    pub order_id: u32,                // Drift order id (0 = empty slot)
+    pub market_index: u16,            // Drift perp market index
+    pub direction: u8,                // Order side, see `direction()`
+    pub reduce_only: u8,              // Order reduces an existing leg, see `is_reduce_only`
+    pub base_asset_amount: u64,       // Requested order size
+    pub filled_amount: u64,           // Size filled as last observed on Drift
+    pub quote_filled: u64,            // Quote amount filled as last observed on Drift
+    pub oracle_price: u64,            // Oracle price when the order was placed
+    pub max_ts: i64,                  // Expiry timestamp of the order
+}
+
+impl OpenOrder {
+    pub const LEN: usize = std::mem::size_of::<OpenOrder>();
+
+    pub fn is_empty(&self) -> bool {
+        self.order_id == 0
+    }
+
+    pub fn is_stale(&self, current_time: i64) -> bool {
+        !self.is_empty() && current_time > self.max_ts
+    }
+
+    pub fn direction(&self) -> Result<PositionDirection> {
+        PositionDirection::try_from(self.direction)
+    }
+
+    pub fn is_reduce_only(&self) -> bool {
+        self.reduce_only != 0
+    }
+}
+
+#[zero_copy(unsafe)]
+#[derive(Debug, Default, PartialEq)]
+pub struct MarketBook {
+    pub market_index: u16,            // Drift perp market index
+    pub initialized: u8,              // Slot is in use, see `is_initialized`
+    pub base_position: i64,           // Net filled position (base precision)
+    pub average_entry_price: u64,     // Cost basis of the open position
+    pub realized_pnl: i64,            // Cumulative PnL realized on reductions
+    pub cumulative_funding: i64,      // Funding received (+) or paid (-)
+    pub fees_paid: u64,               // Trading fees paid on Drift
+    pub last_funding_rate: i64,       // Cumulative funding rate at last settlement
+}
+
+impl MarketBook {
+    pub const LEN: usize = std::mem::size_of::<MarketBook>();
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized != 0
+    }
+}
+
+#[zero_copy(unsafe)]
+#[derive(Debug, Default, PartialEq)]
+pub struct DriftSubAccount {
+    pub user: Pubkey,                 // Drift user account, default when the slot is free
+    pub long_position: i64,           // Long leg as of the last sync
+    pub short_position: i64,          // Short leg as of the last sync
+    pub last_sync_slot: u64,          // Slot of last reconciliation against Drift
+}
+
+impl DriftSubAccount {
+    pub const LEN: usize = std::mem::size_of::<DriftSubAccount>();
+
+    pub fn is_empty(&self) -> bool {
+        self.user == Pubkey::default()
+    }
+}
+
+#[zero_copy(unsafe)]
+#[derive(Debug, Default, PartialEq)]
+pub struct CollateralConfig {
+    pub mint: Pubkey,                 // Collateral mint, default when the slot is free
+    pub spot_market_index: u16,       // Drift spot market holding the collateral
+    pub haircut_bps: u16,             // Discount applied to the oracle value in NAV
+    pub decimals: u8,                 // Mint decimals
+    pub enabled: u8,                  // Accepting new deposits, see `is_enabled`
+    pub amount: u64,                  // Collateral deposited to Drift (native units)
+    pub value: u64,                   // Haircut value of `amount` at last valuation
+    pub last_valued_slot: u64,        // Slot of last valuation
+}
+
+impl CollateralConfig {
+    pub const LEN: usize = std::mem::size_of::<CollateralConfig>();
+
+    pub fn is_empty(&self) -> bool {
+        self.mint == Pubkey::default()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled != 0
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -155,13 +842,48 @@ pub enum HedgeAction {
     ReduceShort,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[repr(u8)]
 pub enum PositionDirection {
+    #[default]
     Long,
     Short,
 }
 
+impl TryFrom<u8> for PositionDirection {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(PositionDirection::Long),
+            1 => Ok(PositionDirection::Short),
+            _ => err!(VaultError::InvalidPositionDirection),
+        }
+    }
+}
+
+impl PositionDirection {
+    pub fn opposite(&self) -> Self {
+        match self {
+            PositionDirection::Long => PositionDirection::Short,
+            PositionDirection::Short => PositionDirection::Long,
+        }
+    }
+}
+
+/// Why an emergency stop was triggered
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmergencyStopReason {
+    AdminAction,
+    OracleFailure,
+    MarketDisruption,
+    SecurityIncident,
+}
+
+/// Borsh account, so new fields are carved out of `reserved` rather than
+/// appended, keeping existing accounts loadable without a realloc
 #[account]
+#[derive(Default)]
 pub struct VaultUser {
     pub user: Pubkey,
     pub vault: Pubkey,
@@ -170,6 +892,14 @@ pub struct VaultUser {
     pub total_deposits: u64,
     pub total_withdrawals: u64,
     pub bump: u8,
+    pub allowlist_root: [u8; 32],     // Root the user was last verified against
+    pub deposit_cap: u64,             // Net deposit cap from the allowlist leaf
+    pub referrer: Pubkey,             // Referrer attached on first deposit, default if none
+    pub average_entry_price: u64,     // Share-weighted average price paid per share (SHARE_PRICE_PRECISION)
+    pub realized_pnl: i64,            // Proceeds less cost basis of redeemed shares
+    pub fees_paid: u64,               // Early-exit fees plus this user's share of collected vault fees
+    pub fee_debt: u128,               // shares * fees_per_share at last settlement
+    pub reserved: [u64; 16],          // Reserved for future fields, zero until used
 }
 
 impl VaultUser {
@@ -180,5 +910,791 @@ impl VaultUser {
         8 +                           // last_deposit_time
         8 +                           // total_deposits
         8 +                           // total_withdrawals
+        1 +                           // bump
+        32 +                          // allowlist_root
+        8 +                           // deposit_cap
+        32 +                          // referrer
+        8 +                           // average_entry_price
+        8 +                           // realized_pnl
+        8 +                           // fees_paid
+        16 +                          // fee_debt
+        (16 * 8);                     // reserved
+
+    pub fn is_allowlisted(&self, vault_state: &VaultState) -> bool {
+        self.allowlist_root == vault_state.allowlist_root
+    }
+
+    /// Lockup runs from the share-weighted average deposit time
+    pub fn is_locked(&self, vault_state: &VaultState, current_time: i64) -> bool {
+        current_time < self.last_deposit_time.saturating_add(vault_state.lockup_period)
+    }
+
+    /// Credit newly minted shares bought for `amount`. The lockup clock and
+    /// the entry price move to their share-weighted averages, so a small
+    /// deposit made on the user's behalf cannot restart the lockup on their
+    /// whole position.
+    pub fn record_deposit(
+        &mut self,
+        shares: u64,
+        amount: u64,
+        current_time: i64,
+        fees_per_share: u128,
+    ) -> Result<()> {
+        self.settle_fees(fees_per_share)?;
+
+        let total_shares = self.shares.safe_add(shares)?;
+        let weighted_time = (self.shares as i128)
+            .safe_mul(self.last_deposit_time as i128)?
+            .safe_add((shares as i128).safe_mul(current_time as i128)?)?
+            .safe_div(total_shares as i128)?;
+        let cost_basis = (self.shares as u128)
+            .safe_mul(self.average_entry_price as u128)?
+            .safe_add((amount as u128).safe_mul(SHARE_PRICE_PRECISION as u128)?)?;
+
+        self.shares = total_shares;
+        self.total_deposits = self.total_deposits.safe_add(amount)?;
+        self.last_deposit_time = to_i64(weighted_time)?;
+        self.average_entry_price = to_u64(cost_basis.safe_div(total_shares as u128)?)?;
+        self.fee_debt = (self.shares as u128).safe_mul(fees_per_share)?;
+        Ok(())
+    }
+
+    /// Burn redeemed shares paid out with `amount_out` after `early_exit_fee`.
+    /// Returns the PnL realized against the average entry price.
+    pub fn record_withdrawal(
+        &mut self,
+        shares: u64,
+        amount_out: u64,
+        early_exit_fee: u64,
+        fees_per_share: u128,
+    ) -> Result<i64> {
+        self.settle_fees(fees_per_share)?;
+
+        let cost = mul_div(shares, self.average_entry_price, SHARE_PRICE_PRECISION, Rounding::Up)?;
+        let realized_pnl = to_i64(amount_out)?.safe_sub(to_i64(cost)?)?;
+
+        self.shares = self.shares.safe_sub(shares)?;
+        self.total_withdrawals = self.total_withdrawals.safe_add(amount_out)?;
+        self.realized_pnl = self.realized_pnl.safe_add(realized_pnl)?;
+        self.fees_paid = self.fees_paid.safe_add(early_exit_fee)?;
+        self.fee_debt = (self.shares as u128).safe_mul(fees_per_share)?;
+        Ok(realized_pnl)
+    }
+
+    /// Attribute vault fees collected since the last settlement to this user.
+    /// Must run before `shares` changes.
+    pub fn settle_fees(&mut self, fees_per_share: u128) -> Result<()> {
+        let accrued = (self.shares as u128).safe_mul(fees_per_share)?;
+        let pending = accrued.safe_sub(self.fee_debt)?.safe_div(FEES_PER_SHARE_PRECISION)?;
+        self.fees_paid = self.fees_paid.safe_add(to_u64(pending)?)?;
+        self.fee_debt = self.fee_debt.safe_add(pending.safe_mul(FEES_PER_SHARE_PRECISION)?)?;
+        Ok(())
+    }
+
+    pub fn net_deposits(&self) -> u64 {
+        self.total_deposits.saturating_sub(self.total_withdrawals)
+    }
+
+    pub fn has_referrer(&self) -> bool {
+        self.referrer != Pubkey::default()
+    }
+}
+
+/// Per-vault referral rebate account for a distribution partner
+#[account]
+pub struct ReferrerAccount {
+    pub referrer: Pubkey,            // Partner entitled to the rebates
+    pub vault: Pubkey,               // Vault the referrals were made into
+    pub referred_shares: u64,        // Shares currently held by users this partner referred
+    pub fee_debt: u128,              // referred_shares * referral_fees_per_share at last settlement
+    pub accrued_fees: u64,           // Settled, unclaimed rebates
+    pub total_claimed: u64,          // Cumulative rebates claimed
+    pub bump: u8,
+}
+
+impl ReferrerAccount {
+    pub const LEN: usize = 8 +        // discriminator
+        32 +                          // referrer
+        32 +                          // vault
+        8 +                           // referred_shares
+        16 +                          // fee_debt
+        8 +                           // accrued_fees
+        8 +                           // total_claimed
+        1;                            // bump
+
+    /// Move rebates earned since the last settlement into `accrued_fees`.
+    /// Must run before `referred_shares` changes.
+    pub fn settle(&mut self, fees_per_share: u128) -> Result<()> {
+        let earned = (self.referred_shares as u128).safe_mul(fees_per_share)?;
+        let pending = earned.safe_sub(self.fee_debt)?.safe_div(REFERRAL_FEE_PRECISION)?;
+        self.accrued_fees = self.accrued_fees.safe_add(to_u64(pending)?)?;
+        self.fee_debt = self.fee_debt.safe_add(pending.safe_mul(REFERRAL_FEE_PRECISION)?)?;
+        Ok(())
+    }
+
+    /// Apply a change in referred shares, settling first
+    pub fn update_shares(&mut self, fees_per_share: u128, added: u64, removed: u64) -> Result<()> {
+        self.settle(fees_per_share)?;
+        self.referred_shares = self.referred_shares.safe_add(added)?.safe_sub(removed)?;
+        self.fee_debt = (self.referred_shares as u128).safe_mul(fees_per_share)?;
+        Ok(())
+    }
+}
+
+/// Per-vault ring buffer of share-price snapshots, appended on rebalance and
+/// fee collection. Zero-copy because the buffer is too large to deserialize.
+#[account(zero_copy(unsafe))]
+pub struct VaultHistory {
+    pub vault: Pubkey,               // Vault the snapshots belong to
+    pub head: u16,                   // Slot the next snapshot is written to
+    pub count: u16,                  // Number of snapshots recorded, up to HISTORY_CAPACITY
+    pub bump: u8,
+    pub snapshots: [HistorySnapshot; HISTORY_CAPACITY],
+}
+
+impl VaultHistory {
+    pub const LEN: usize = 8 + std::mem::size_of::<VaultHistory>();
+
+    /// Snapshot `age` entries before the newest one (0 = newest)
+    pub fn snapshot(&self, age: usize) -> Option<HistorySnapshot> {
+        if age >= self.count as usize {
+            return None;
+        }
+        let slot = (self.head as usize + HISTORY_CAPACITY - 1 - age) % HISTORY_CAPACITY;
+        Some(self.snapshots[slot])
+    }
+
+    pub fn latest(&self) -> Option<HistorySnapshot> {
+        self.snapshot(0)
+    }
+
+    /// Append a snapshot, overwriting the oldest once the buffer is full.
+    /// Snapshots closer than MIN_HISTORY_SNAPSHOT_INTERVAL to the newest one
+    /// are skipped so the buffer covers the longest trailing window.
+    pub fn record(&mut self, snapshot: HistorySnapshot) -> bool {
+        if let Some(latest) = self.latest() {
+            if snapshot.timestamp < latest.timestamp.saturating_add(MIN_HISTORY_SNAPSHOT_INTERVAL) {
+                return false;
+            }
+        }
+
+        self.snapshots[self.head as usize] = snapshot;
+        self.head = ((self.head as usize + 1) % HISTORY_CAPACITY) as u16;
+        self.count = (self.count as usize + 1).min(HISTORY_CAPACITY) as u16;
+        true
+    }
+
+    /// Annualized return, in basis points, from the newest snapshot taken at
+    /// least `window` seconds before the latest one. None until the history
+    /// covers the window.
+    pub fn trailing_return_bps(&self, window: i64) -> Result<Option<i64>> {
+        let latest = match self.latest() {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+        let cutoff = latest.timestamp.safe_sub(window)?;
+
+        let start = (1..self.count as usize)
+            .filter_map(|age| self.snapshot(age))
+            .find(|s| s.timestamp <= cutoff);
+        match start {
+            Some(start) => Ok(Some(calculate_annualized_return_bps(
+                start.share_price,
+                latest.share_price,
+                latest.timestamp.safe_sub(start.timestamp)?,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Trailing 1, 7 and 30 day annualized returns
+    pub fn trailing_returns(&self) -> Result<TrailingReturns> {
+        Ok(TrailingReturns {
+            one_day_bps: self.trailing_return_bps(SECONDS_PER_DAY)?,
+            seven_day_bps: self.trailing_return_bps(7 * SECONDS_PER_DAY)?,
+            thirty_day_bps: self.trailing_return_bps(30 * SECONDS_PER_DAY)?,
+        })
+    }
+}
+
+#[zero_copy(unsafe)]
+#[derive(Debug, Default, PartialEq)]
+pub struct HistorySnapshot {
+    pub timestamp: i64,              // Unix time the snapshot was taken
+    pub share_price: u64,            // Share price (SHARE_PRICE_PRECISION)
+    pub nav: u64,                    // Vault total value
+    pub delta: i64,                  // Net delta of the vault's positions
+}
+
+impl HistorySnapshot {
+    pub fn of(vault_state: &VaultState, timestamp: i64) -> Result<Self> {
+        Ok(Self {
+            timestamp,
+            share_price: vault_state.calculate_share_price()?,
+            nav: vault_state.calculate_total_value()?,
+            delta: vault_state.calculate_delta()?,
+        })
+    }
+}
+
+/// Annualized trailing returns in basis points, None where the history is
+/// too short to cover the window
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrailingReturns {
+    pub one_day_bps: Option<i64>,
+    pub seven_day_bps: Option<i64>,
+    pub thirty_day_bps: Option<i64>,
+}
+
+/// Outcome of a withdrawal or redemption, returned by `preview_redeem`
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct WithdrawalQuote {
+    pub shares_burned: u64,
+    pub amount: u64,                  // Base tokens redeemed before the early-exit fee
+    pub early_exit_fee: u64,          // Left in the vault for the remaining holders
+    pub amount_out: u64,              // Base tokens sent to the receiver
+}
+
+impl WithdrawalQuote {
+    /// Refuse an exit paying out less than the caller's `min_amount_out`
+    pub fn ensure_min_out(self, min_amount_out: u64) -> Result<Self> {
+        require_at_least!(self.amount_out, min_amount_out, VaultError::SlippageExceeded);
+        Ok(self)
+    }
+}
+
+/// Outcome of an in-kind redemption, indexed by collateral slot
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InKindQuote {
+    pub shares_burned: u64,
+    pub base_amount: u64,             // Base tokens sent to the receiver
+    pub collateral_amounts: [u64; MAX_COLLATERALS], // Collateral withdrawn from Drift (native units)
+    pub collateral_values: [u64; MAX_COLLATERALS],  // Haircut value of each withdrawn amount
+    pub value: u64,                   // Base amount plus collateral values
+}
+
+/// Components of the vault's NAV, returned by `get_nav_breakdown`
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct NavBreakdown {
+    pub base_assets: u64,             // Base asset held by the vault
+    pub collateral_value: u64,        // Haircut value of non-base collateral at last valuation
+    pub total_value: u64,             // NAV used to price shares
+    pub total_shares: u64,
+    pub share_price: u64,             // SHARE_PRICE_PRECISION
+    pub collateral_fresh: bool,       // Deposits and withdrawals would accept this valuation
+}
+
+/// The vault's hedge state, returned by `get_delta`
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeltaExposure {
+    pub long_position: i64,
+    pub short_position: i64,
+    pub delta: i64,                   // long_position + short_position
+    pub delta_bps: u64,               // |delta| as a share of NAV
+    pub delta_threshold: u16,         // Rebalance trigger (basis points)
+    pub needs_rebalance: bool,
+}
+
+/// Singleton holding protocol-wide settings shared by every vault. There is
+/// no allowed Drift program id: every Drift CPI takes `Program<Drift>`,
+/// which already pins it to `drift::ID`.
+#[account]
+pub struct ProtocolConfig {
+    pub owner: Pubkey,               // Protocol owner, may update this config
+    pub protocol_fee_bps: u16,       // Share of each vault's collected fees (basis points)
+    pub paused: bool,                // Global pause switch
+    pub vault_count: u64,            // Vaults created so far
+    pub bump: u8,
+}
+
+impl ProtocolConfig {
+    pub const LEN: usize = 8 +        // discriminator
+        32 +                          // owner
+        2 +                           // protocol_fee_bps
+        1 +                           // paused
+        8 +                           // vault_count
+        1;                            // bump
+}
+
+/// Registry entry for one vault, so one admin can run several. Each vault
+/// pays for its own entry PDA; clients list vaults by fetching every account
+/// of this type.
+#[account]
+pub struct VaultRegistryEntry {
+    pub vault: Pubkey,               // Vault state address
+    pub admin: Pubkey,               // Vault admin
+    pub vault_id: u64,               // Admin-chosen vault id
+    pub registered_at: i64,          // Unix timestamp of vault creation
+    pub bump: u8,
+}
+
+impl VaultRegistryEntry {
+    pub const LEN: usize = 8 +        // discriminator
+        32 +                          // vault
+        32 +                          // admin
+        8 +                           // vault_id
+        8 +                           // registered_at
         1;                            // bump
-}
\ No newline at end of file
+}
+
+/// Merkle proof that a depositor is on a vault's allowlist
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, PartialEq)]
+pub struct AllowlistProof {
+    pub deposit_cap: u64,
+    pub proof: Vec<[u8; 32]>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(timestamp: i64, share_price: u64) -> HistorySnapshot {
+        HistorySnapshot { timestamp, share_price, ..Default::default() }
+    }
+
+    #[test]
+    fn test_history_ring_buffer() {
+        let mut history: VaultHistory = bytemuck::Zeroable::zeroed();
+        assert_eq!(history.latest(), None);
+
+        assert!(history.record(snapshot(0, 1_000_000)));
+        // Too soon after the previous snapshot
+        assert!(!history.record(snapshot(MIN_HISTORY_SNAPSHOT_INTERVAL - 1, 1_000_001)));
+        assert_eq!({ history.count }, 1);
+
+        // Wrapping around keeps the newest HISTORY_CAPACITY snapshots
+        for i in 1..=HISTORY_CAPACITY as i64 {
+            assert!(history.record(snapshot(i * MIN_HISTORY_SNAPSHOT_INTERVAL, 1_000_000 + i as u64)));
+        }
+        assert_eq!(history.count as usize, HISTORY_CAPACITY);
+        assert_eq!({ history.latest().unwrap().share_price }, 1_000_000 + HISTORY_CAPACITY as u64);
+        assert_eq!({ history.snapshot(HISTORY_CAPACITY - 1).unwrap().share_price }, 1_000_001);
+        assert_eq!(history.snapshot(HISTORY_CAPACITY), None);
+    }
+
+    #[test]
+    fn test_trailing_returns() {
+        let mut history: VaultHistory = bytemuck::Zeroable::zeroed();
+        history.record(snapshot(0, 1_000_000));
+        history.record(snapshot(7 * SECONDS_PER_DAY, 1_001_000));
+        history.record(snapshot(8 * SECONDS_PER_DAY, 1_002_000));
+
+        let returns = history.trailing_returns().unwrap();
+        // 1d: 1_001_000 -> 1_002_000 over one day
+        assert_eq!(returns.one_day_bps, Some(3646));
+        // 7d: 1_000_000 -> 1_002_000 over eight days
+        assert_eq!(returns.seven_day_bps, Some(912));
+        // Not enough history for 30d
+        assert_eq!(returns.thirty_day_bps, None);
+    }
+
+    #[test]
+    fn test_withdrawal_quotes() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.total_assets = 2_000_000;
+        vault.total_shares = 1_000_000;
+
+        let shares = vault.shares_for_deposit(2_000).unwrap();
+        let redeem = vault.quote_redeem(shares, false).unwrap();
+        assert!(redeem.amount_out <= 2_000);
+
+        // Withdrawing what a redemption pays never burns fewer shares
+        let withdraw = vault.quote_withdraw(redeem.amount, false).unwrap();
+        assert!(withdraw.shares_burned >= shares);
+
+        assert_eq!(
+            vault.quote_redeem(shares, true).unwrap_err(),
+            error!(VaultError::WithdrawalLocked)
+        );
+        vault.early_exit_fee_bps = 100;
+        let locked = vault.quote_redeem(shares, true).unwrap();
+        assert_eq!(locked.amount_out, locked.amount - locked.early_exit_fee);
+        assert!(locked.early_exit_fee > 0);
+
+        // Shares backed partly by collateral can't be paid fully in the base asset
+        vault.collaterals[0] = CollateralConfig {
+            mint: Pubkey::new_unique(),
+            amount: 1_000,
+            value: 2_000_000,
+            ..CollateralConfig::default()
+        };
+        assert_eq!(
+            vault.quote_redeem(shares, false).unwrap_err(),
+            error!(VaultError::InsufficientFunds)
+        );
+        assert_eq!(
+            vault.quote_withdraw(vault.liquid_share(shares).unwrap(), false).unwrap_err(),
+            error!(VaultError::InsufficientFunds)
+        );
+
+        // They exit in kind instead, taking a slice of every asset
+        let in_kind = vault.quote_in_kind(250_000).unwrap();
+        assert_eq!(in_kind.base_amount, 500_000);
+        assert_eq!(in_kind.collateral_amounts[0], 250);
+        assert_eq!(in_kind.collateral_values[0], 500_000);
+        assert_eq!(in_kind.value, 1_000_000);
+        assert!(vault.quote_in_kind(1_000_001).is_err());
+    }
+
+    #[test]
+    fn test_drift_sub_accounts() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        let primary = Pubkey::new_from_array([1; 32]);
+        let hedge = Pubkey::new_from_array([2; 32]);
+
+        // A vault from before sub-accounts keeps its positions on slot 0
+        vault.drift_user = primary;
+        vault.long_position = 500;
+        vault.last_position_sync_slot = 10;
+        assert_eq!(vault.drift_sub_account_slot(&primary), Some(0));
+        assert_eq!(vault.add_drift_sub_account(hedge).unwrap(), 1);
+        assert_eq!({ vault.drift_sub_accounts[0].long_position }, 500);
+        assert_eq!(vault.add_drift_sub_account(hedge).unwrap(), 1);
+        assert_eq!(vault.drift_sub_account_slot(&Pubkey::default()), None);
+
+        // Positions aggregate across sub-accounts
+        vault.sync_sub_account(1, 0, -300, 20).unwrap();
+        assert_eq!({ vault.long_position }, 500);
+        assert_eq!({ vault.short_position }, -300);
+        assert_eq!({ vault.last_position_sync_slot }, 10);
+
+        // A market only moves to another sub-account once flat
+        vault.route_market(3, 1).unwrap();
+        assert_eq!(vault.market_sub_account(3), Some(1));
+        vault.market_book_mut(3).unwrap().base_position = -300;
+        assert_eq!(
+            vault.route_market(3, 0).unwrap_err(),
+            error!(VaultError::MarketOnOtherSubAccount)
+        );
+        vault.market_book_mut(3).unwrap().base_position = 0;
+        vault.route_market(3, 0).unwrap();
+        assert_eq!(vault.market_sub_account(3), Some(0));
+    }
+
+    #[test]
+    fn test_vault_id_seed() {
+        let program_id = Pubkey::new_unique();
+        let admin = Pubkey::new_unique();
+
+        // Vault 0 resolves to the address vaults had before vault ids
+        let (legacy, _) = Pubkey::find_program_address(&[VAULT_SEED, admin.as_ref()], &program_id);
+        let (vault_0, _) = Pubkey::find_program_address(&[VAULT_SEED, admin.as_ref(), &vault_id_seed(0)], &program_id);
+        assert_eq!(vault_0, legacy);
+
+        let (vault_1, _) = Pubkey::find_program_address(&[VAULT_SEED, admin.as_ref(), &vault_id_seed(1)], &program_id);
+        assert_ne!(vault_1, legacy);
+        assert_eq!(vault_id_seed(1), 1u64.to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_only_admin_trades() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.admin = Pubkey::new_unique();
+
+        assert!(vault.is_admin(&{ vault.admin }));
+        assert!(!vault.is_admin(&Pubkey::new_unique()));
+        assert!(!vault.is_admin(&Pubkey::default()));
+    }
+
+    #[test]
+    fn test_position_to_close() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        assert_eq!(vault.position_to_close(0, 10).unwrap_err(), error!(VaultError::NoPositionToClose));
+
+        // A short-only market closes with a buy, capped at the position
+        vault.market_book_mut(0).unwrap().base_position = -40;
+        vault.market_book_mut(1).unwrap().base_position = 25;
+        assert_eq!(vault.position_to_close(0, 10).unwrap(), (PositionDirection::Short, 10));
+        assert_eq!(vault.position_to_close(0, 100).unwrap(), (PositionDirection::Short, 40));
+
+        // Each market is judged on its own book, not the vault-wide legs
+        vault.long_position = 100;
+        assert_eq!(vault.position_to_close(1, 100).unwrap(), (PositionDirection::Long, 25));
+        assert_eq!(vault.position_to_close(2, 100).unwrap_err(), error!(VaultError::NoPositionToClose));
+        assert_eq!(vault.position_to_close(0, 0).unwrap_err(), error!(VaultError::InvalidAmount));
+    }
+
+    #[test]
+    fn test_ensure_closable() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.ensure_closable(0).unwrap();
+
+        // Leftover base assets are dust to sweep, not a reason to stay open
+        vault.total_assets = 3;
+        vault.ensure_closable(3).unwrap();
+        vault.ensure_closable(MAX_CLOSE_DUST).unwrap();
+        assert_eq!(vault.ensure_closable(MAX_CLOSE_DUST + 1).unwrap_err(), error!(VaultError::VaultNotEmpty));
+
+        vault.total_shares = 1;
+        assert_eq!(vault.ensure_closable(0).unwrap_err(), error!(VaultError::VaultNotEmpty));
+        vault.total_shares = 0;
+
+        // Referral fees belong to the referrers until they claim them
+        vault.referral_fees_owed = 5;
+        assert_eq!(vault.ensure_closable(5).unwrap_err(), error!(VaultError::VaultNotEmpty));
+        vault.referral_fees_owed = 0;
+
+        vault.market_book_mut(0).unwrap().base_position = 10;
+        assert_eq!(vault.ensure_closable(0).unwrap_err(), error!(VaultError::PositionsOpen));
+        vault.market_book_mut(0).unwrap().base_position = 0;
+
+        vault.open_orders[0].order_id = 7;
+        assert_eq!(vault.ensure_closable(0).unwrap_err(), error!(VaultError::OrdersOpen));
+        vault.open_orders[0].order_id = 0;
+
+        vault.collaterals[0] = CollateralConfig {
+            mint: Pubkey::new_unique(),
+            amount: 1,
+            ..CollateralConfig::default()
+        };
+        assert_eq!(vault.ensure_closable(0).unwrap_err(), error!(VaultError::VaultNotEmpty));
+
+        // A configured collateral that was fully withdrawn is fine
+        vault.collaterals[0].amount = 0;
+        vault.ensure_closable(0).unwrap();
+    }
+
+    #[test]
+    fn test_record_flow_limits() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.epoch_duration = 100;
+        vault.max_epoch_inflow = 1_000;
+        vault.max_epoch_outflow = 500;
+
+        vault.record_flow(10, 600).unwrap();
+        vault.record_flow(20, 400).unwrap();
+        assert_eq!(vault.record_flow(30, 1).unwrap_err(), error!(VaultError::FlowLimitExceeded));
+        assert_eq!({ vault.epoch_net_flow }, 1_000);
+
+        // Withdrawals move the net back toward zero and then hit the outflow limit
+        vault.record_flow(40, -1_500).unwrap();
+        assert_eq!(vault.record_flow(50, -1).unwrap_err(), error!(VaultError::FlowLimitExceeded));
+
+        // A flow against a breached limit still passes
+        vault.max_epoch_outflow = 100;
+        vault.record_flow(60, 1).unwrap();
+        assert_eq!({ vault.epoch_net_flow }, -499);
+    }
+
+    #[test]
+    fn test_record_flow_rollover() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.epoch_duration = 100;
+        vault.max_epoch_inflow = 1_000;
+
+        vault.record_flow(0, 1_000).unwrap();
+        assert_eq!(vault.record_flow(99, 1).unwrap_err(), error!(VaultError::FlowLimitExceeded));
+
+        // Checking a flow in the next epoch leaves the current one untouched
+        assert_eq!(vault.check_flow(100, 1_000).unwrap(), 1_000);
+        assert_eq!({ vault.epoch_start }, 0);
+        assert_eq!({ vault.epoch_net_flow }, 1_000);
+
+        // A new epoch starts at the first flow after the old one ends
+        vault.record_flow(150, 1_000).unwrap();
+        assert_eq!({ vault.epoch_start }, 150);
+        assert_eq!({ vault.epoch_net_flow }, 1_000);
+        assert_eq!(vault.record_flow(249, 1).unwrap_err(), error!(VaultError::FlowLimitExceeded));
+        vault.record_flow(250, 1).unwrap();
+        assert_eq!({ vault.epoch_net_flow }, 1);
+    }
+
+    #[test]
+    fn test_validate_flow_limits() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.validate_flow_limits().unwrap();
+
+        // Migrated vaults start without an epoch, so a limit needs one set first
+        vault.max_epoch_outflow = 1;
+        assert_eq!(vault.validate_flow_limits().unwrap_err(), error!(VaultError::InvalidCapacityLimits));
+        vault.max_epoch_outflow = 0;
+        vault.max_epoch_inflow = 1;
+        assert_eq!(vault.validate_flow_limits().unwrap_err(), error!(VaultError::InvalidCapacityLimits));
+
+        vault.epoch_duration = DEFAULT_FLOW_EPOCH_DURATION;
+        vault.validate_flow_limits().unwrap();
+    }
+
+    fn order(order_id: u32, market_index: u16, direction: PositionDirection, reduce_only: bool, max_ts: i64) -> OpenOrder {
+        OpenOrder {
+            order_id,
+            market_index,
+            direction: direction as u8,
+            reduce_only: u8::from(reduce_only),
+            base_asset_amount: 5 * BASE_PRECISION,
+            max_ts,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_order_tracking() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        for order_id in 1..=MAX_OPEN_ORDERS as u32 {
+            vault.track_order(order(order_id, 0, PositionDirection::Long, false, 100)).unwrap();
+        }
+        assert_eq!(
+            vault.track_order(order(99, 0, PositionDirection::Long, false, 100)).unwrap_err(),
+            error!(VaultError::TooManyOpenOrders)
+        );
+
+        // A freed slot is reused by the next order
+        vault.open_orders[1] = OpenOrder::default();
+        vault.track_order(order(99, 0, PositionDirection::Short, true, 100)).unwrap();
+        assert_eq!({ vault.open_orders[1].order_id }, 99);
+
+        // Only newly reported size is booked, priced from the quote filled
+        let fill_price = vault.apply_fill(0, 2 * BASE_PRECISION, 300 * PRICE_PRECISION).unwrap();
+        assert_eq!(fill_price, Some(150 * PRICE_PRECISION));
+        assert_eq!(vault.apply_fill(0, 2 * BASE_PRECISION, 300 * PRICE_PRECISION).unwrap(), None);
+        assert_eq!({ vault.long_position }, to_i64(2 * BASE_PRECISION).unwrap());
+        assert_eq!({ vault.open_orders[0].filled_amount }, 2 * BASE_PRECISION);
+
+        // A reduce-only sell shrinks the long leg instead of adding a short
+        vault.apply_fill(1, BASE_PRECISION, 150 * PRICE_PRECISION).unwrap();
+        assert_eq!({ vault.long_position }, to_i64(BASE_PRECISION).unwrap());
+        assert_eq!({ vault.short_position }, 0);
+    }
+
+    #[test]
+    fn test_take_stale_orders() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.route_market(2, 1).unwrap();
+        vault.track_order(order(1, 0, PositionDirection::Long, false, 100)).unwrap();
+        vault.track_order(order(2, 0, PositionDirection::Short, false, 200)).unwrap();
+        vault.track_order(order(3, 2, PositionDirection::Long, false, 100)).unwrap();
+
+        // Only expired orders on the given sub-account are released
+        let stale = vault.take_stale_orders(0, 150);
+        assert_eq!(stale, vec![(order(1, 0, PositionDirection::Long, false, 100), 1)]);
+        assert!(vault.open_orders[0].is_empty());
+        assert_eq!({ vault.open_orders[1].order_id }, 2);
+        assert_eq!({ vault.open_orders[2].order_id }, 3);
+
+        // An order is live through its max_ts
+        assert!(vault.take_stale_orders(0, 200).is_empty());
+        assert_eq!(vault.take_stale_orders(1, 150).len(), 1);
+        assert_eq!({ vault.event_sequence }, 2);
+    }
+
+    #[test]
+    fn test_deposit_min_shares_out() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.total_assets = 2_000_000;
+        vault.total_shares = 1_000_000;
+
+        let shares = vault.shares_for_deposit(2_000).unwrap();
+        assert_eq!(vault.shares_for_deposit_at_least(2_000, 0).unwrap(), shares);
+        assert_eq!(vault.shares_for_deposit_at_least(2_000, shares).unwrap(), shares);
+        assert_eq!(
+            vault.shares_for_deposit_at_least(2_000, shares + 1).unwrap_err(),
+            error!(VaultError::SlippageExceeded)
+        );
+    }
+
+    #[test]
+    fn test_redeem_min_amount_out() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.total_assets = 2_000_000;
+        vault.total_shares = 1_000_000;
+
+        // The receiver gets amount_out for exactly the shares redeemed
+        let quote = vault.quote_redeem(1_000, false).unwrap();
+        assert_eq!(quote.shares_burned, 1_000);
+        assert_eq!(quote.ensure_min_out(quote.amount_out).unwrap(), quote);
+        assert_eq!(
+            quote.ensure_min_out(quote.amount_out + 1).unwrap_err(),
+            error!(VaultError::SlippageExceeded)
+        );
+
+        // The bound applies after the early-exit fee is taken
+        vault.early_exit_fee_bps = 100;
+        let locked = vault.quote_redeem(1_000, true).unwrap();
+        assert_eq!(
+            locked.ensure_min_out(locked.amount).unwrap_err(),
+            error!(VaultError::SlippageExceeded)
+        );
+        locked.ensure_min_out(locked.amount_out).unwrap();
+    }
+
+    #[test]
+    fn test_deposit_for_and_redeem_to_receiver() {
+        let mut vault: VaultState = bytemuck::Zeroable::zeroed();
+        vault.total_assets = 2_000_000;
+        vault.total_shares = 1_000_000;
+        let mut owner = VaultUser { user: Pubkey::new_unique(), ..Default::default() };
+
+        // A router's deposit is credited to the owner's position
+        let shares = vault.shares_for_deposit_at_least(4_000, 1).unwrap();
+        owner.record_deposit(shares, 4_000, 0, 0).unwrap();
+        vault.total_assets = 2_004_000;
+        vault.total_shares = 1_000_000 + shares;
+        assert_eq!(owner.shares, shares);
+        assert_eq!(owner.net_deposits(), 4_000);
+
+        // Redeeming it all pays the receiver what went in, less rounding
+        let quote = vault.quote_redeem(owner.shares, false).unwrap().ensure_min_out(3_990).unwrap();
+        assert!(quote.amount_out <= 4_000);
+        owner.record_withdrawal(quote.shares_burned, quote.amount_out, quote.early_exit_fee, 0).unwrap();
+        assert_eq!(owner.shares, 0);
+        assert!(owner.realized_pnl <= 0);
+    }
+
+    #[test]
+    fn test_user_deposits_average_time_and_price() {
+        let mut user = VaultUser::default();
+        user.record_deposit(1_000, 1_000, 100, 0).unwrap();
+        assert_eq!(user.shares, 1_000);
+        assert_eq!(user.last_deposit_time, 100);
+        assert_eq!(user.average_entry_price, 1_000_000);
+
+        // A second deposit moves both to their share-weighted averages
+        user.record_deposit(1_000, 2_000, 300, 0).unwrap();
+        assert_eq!(user.shares, 2_000);
+        assert_eq!(user.total_deposits, 3_000);
+        assert_eq!(user.last_deposit_time, 200);
+        assert_eq!(user.average_entry_price, 1_500_000);
+    }
+
+    #[test]
+    fn test_user_withdrawal_realizes_pnl() {
+        let mut user = VaultUser::default();
+        user.record_deposit(2_000, 3_000, 0, 0).unwrap();
+
+        // Half the shares cost 1_500 and pay out 1_800 after a 20 fee
+        let realized = user.record_withdrawal(1_000, 1_800, 20, 0).unwrap();
+        assert_eq!(realized, 300);
+        assert_eq!(user.shares, 1_000);
+        assert_eq!(user.total_withdrawals, 1_800);
+        assert_eq!(user.realized_pnl, 300);
+        assert_eq!(user.fees_paid, 20);
+        assert_eq!(user.net_deposits(), 1_200);
+
+        // Selling below the entry price realizes a loss
+        assert_eq!(user.record_withdrawal(1_000, 1_000, 0, 0).unwrap(), -500);
+        assert_eq!(user.realized_pnl, -200);
+        assert!(user.record_withdrawal(1, 1, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_user_settles_fees_once() {
+        let mut user = VaultUser::default();
+        user.record_deposit(1_000, 1_000, 0, 0).unwrap();
+
+        // 0.01 tokens of fees per share since the deposit
+        let fees_per_share = FEES_PER_SHARE_PRECISION / 100;
+        user.settle_fees(fees_per_share).unwrap();
+        assert_eq!(user.fees_paid, 10);
+        user.settle_fees(fees_per_share).unwrap();
+        assert_eq!(user.fees_paid, 10);
+
+        // Shares bought later don't pick up fees collected before them
+        user.record_deposit(1_000, 1_000, 0, fees_per_share).unwrap();
+        user.settle_fees(fees_per_share * 2).unwrap();
+        assert_eq!(user.fees_paid, 30);
+
+        // Withdrawals settle before burning shares
+        user.record_withdrawal(2_000, 2_000, 0, fees_per_share * 3).unwrap();
+        assert_eq!(user.fees_paid, 50);
+    }
+}
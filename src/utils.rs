@@ -1,10 +1,14 @@
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::keccak;
 use drift::state::{OracleSource, PerpMarket, SpotMarket};
-use crate::errors::VaultError;
+use crate::errors::{require_at_most, VaultError};
+use crate::math::*;
+use crate::constants::{
+    BASE_PRECISION, BASIS_POINTS_DIVISOR, MAX_ORACLE_CONFIDENCE_BPS, MAX_ORACLE_DELAY_SLOTS, PRICE_PRECISION,
+    SECONDS_PER_YEAR, VIRTUAL_ASSETS, VIRTUAL_SHARES,
+};
 
-pub const PRICE_PRECISION: u64 = 1_000_000;
-pub const BASIS_POINTS_DIVISOR: u64 = 10_000;
-pub const SECONDS_PER_YEAR: i64 = 365 * 24 * 3600;
+pub const FUNDING_PAYMENT_PRECISION: i128 = 1_000_000_000_000; // base precision * funding rate buffer
 
 /// Calculate the required position size to maintain delta neutrality
 pub fn calculate_hedge_amount(
@@ -12,12 +16,12 @@ pub fn calculate_hedge_amount(
     short_position: i64,
     target_delta: i64,
 ) -> Result<(i64, bool)> {
-    let current_delta = long_position + short_position;
-    let delta_diff = current_delta - target_delta;
+    let current_delta = long_position.safe_add(short_position)?;
+    let delta_diff = current_delta.safe_sub(target_delta)?;
     
     // If delta_diff > 0, we need to reduce long or increase short
     // If delta_diff < 0, we need to increase long or reduce short
-    let amount = delta_diff.abs();
+    let amount = to_i64(delta_diff.unsigned_abs())?;
     let should_short = delta_diff > 0;
     
     Ok((amount, should_short))
@@ -30,7 +34,7 @@ pub fn calculate_optimal_order_size(
     max_slippage_bps: u16,
 ) -> Result<u64> {
     // Limit order size to a percentage of market depth to minimize slippage
-    let max_order_size = market_depth * max_slippage_bps as u64 / BASIS_POINTS_DIVISOR;
+    let max_order_size = bps_of(market_depth, max_slippage_bps, Rounding::Down)?;
     Ok(target_amount.min(max_order_size))
 }
 
@@ -45,8 +49,8 @@ pub fn calculate_expected_slippage(
     }
     
     // Simple linear slippage model
-    let size_ratio = order_size * BASIS_POINTS_DIVISOR / market_depth;
-    let slippage = base_slippage_bps as u64 * size_ratio / BASIS_POINTS_DIVISOR;
+    let size_ratio = mul_div(order_size, BASIS_POINTS_DIVISOR, market_depth, Rounding::Up)?;
+    let slippage = mul_div(base_slippage_bps as u64, size_ratio, BASIS_POINTS_DIVISOR, Rounding::Up)?;
     
     Ok(slippage.min(u16::MAX as u64) as u16)
 }
@@ -61,52 +65,89 @@ pub fn calculate_management_fees(
         return Ok(0);
     }
     
-    let annual_fee = total_assets as u128 * fee_rate_bps as u128 / BASIS_POINTS_DIVISOR as u128;
-    let fees = annual_fee * time_elapsed_seconds as u128 / SECONDS_PER_YEAR as u128;
-    
-    Ok(fees.min(u64::MAX as u128) as u64)
+    // Fees are rounded down so depositors are never overcharged
+    let annual_fee = bps_of(total_assets, fee_rate_bps, Rounding::Down)?;
+    mul_div(annual_fee, time_elapsed_seconds as u64, SECONDS_PER_YEAR as u64, Rounding::Down)
 }
 
-/// Calculate performance fees based on profits
+/// Calculate performance fees on the share price's gain above the high-water
+/// mark, across all outstanding shares
 pub fn calculate_performance_fees(
-    total_value: u64,
-    net_deposits: i64,
+    share_price: u64,
+    high_water_mark: u64,
+    total_shares: u64,
     fee_rate_bps: u16,
 ) -> Result<u64> {
-    if net_deposits < 0 || total_value <= net_deposits as u64 {
+    // Without a high-water mark there is no cost basis to measure profit from
+    if high_water_mark == 0 || share_price <= high_water_mark {
         return Ok(0); // No profit, no performance fee
     }
     
-    let profit = total_value - net_deposits as u64;
-    let performance_fee = profit as u128 * fee_rate_bps as u128 / BASIS_POINTS_DIVISOR as u128;
-    
-    Ok(performance_fee.min(u64::MAX as u128) as u64)
+    let gain_per_share = share_price.safe_sub(high_water_mark)?;
+    let profit = mul_div(gain_per_share, total_shares, PRICE_PRECISION, Rounding::Down)?;
+    bps_of(profit, fee_rate_bps, Rounding::Down)
+}
+
+/// Simple (non-compounded) annualized return, in basis points, of a share
+/// price moving from `start_price` to `end_price` over `elapsed_seconds`
+pub fn calculate_annualized_return_bps(
+    start_price: u64,
+    end_price: u64,
+    elapsed_seconds: i64,
+) -> Result<i64> {
+    require!(start_price > 0, VaultError::PriceCalculationFailed);
+    require!(elapsed_seconds > 0, VaultError::PriceCalculationFailed);
+
+    let change = (end_price as i128).safe_sub(start_price as i128)?;
+    let annualized = change
+        .safe_mul(BASIS_POINTS_DIVISOR as i128)?
+        .safe_mul(SECONDS_PER_YEAR as i128)?
+        .safe_div((start_price as i128).safe_mul(elapsed_seconds as i128)?)?;
+    i64::try_from(annualized).map_err(|_| error!(VaultError::MathOverflow))
 }
 
-/// Calculate share price with proper precision handling
+/// Calculate share price with proper precision handling. Includes the same
+/// virtual shares and assets as minting and redemption, so the quoted price
+/// is the rate deposits and redemptions actually execute at.
 pub fn calculate_share_price(total_assets: u64, total_shares: u64) -> Result<u64> {
-    if total_shares == 0 {
-        return Ok(PRICE_PRECISION); // Initial price
-    }
-    
-    let price = total_assets as u128 * PRICE_PRECISION as u128 / total_shares as u128;
-    Ok(price.min(u64::MAX as u128) as u64)
+    mul_div(
+        total_assets.safe_add(VIRTUAL_ASSETS)?,
+        PRICE_PRECISION,
+        total_shares.safe_add(VIRTUAL_SHARES)?,
+        Rounding::Down,
+    )
 }
 
-/// Calculate shares to mint for a given deposit amount
+/// Calculate shares to mint for a given deposit amount.
+/// Equal virtual shares and assets keep the first deposit at 1:1 while making
+/// the share price too expensive to inflate with a donation.
 pub fn calculate_shares_to_mint(
     deposit_amount: u64,
     total_assets: u64,
     total_shares: u64,
 ) -> Result<u64> {
-    if total_shares == 0 {
-        return Ok(deposit_amount); // 1:1 for first deposit
-    }
-    
-    let share_price = calculate_share_price(total_assets, total_shares)?;
-    let shares = deposit_amount as u128 * PRICE_PRECISION as u128 / share_price as u128;
-    
-    Ok(shares.min(u64::MAX as u128) as u64)
+    // Round against the depositor so minting never dilutes existing holders
+    mul_div(
+        deposit_amount,
+        total_shares.safe_add(VIRTUAL_SHARES)?,
+        total_assets.safe_add(VIRTUAL_ASSETS)?,
+        Rounding::Down,
+    )
+}
+
+/// Calculate shares to burn for withdrawing a given amount of assets
+pub fn calculate_shares_to_burn(
+    withdrawal_amount: u64,
+    total_assets: u64,
+    total_shares: u64,
+) -> Result<u64> {
+    // Round against the withdrawer so redemptions never drain remaining holders
+    mul_div(
+        withdrawal_amount,
+        total_shares.safe_add(VIRTUAL_SHARES)?,
+        total_assets.safe_add(VIRTUAL_ASSETS)?,
+        Rounding::Up,
+    )
 }
 
 /// Calculate withdrawal amount for a given number of shares
@@ -119,8 +160,13 @@ pub fn calculate_withdrawal_amount(
         return Err(VaultError::InsufficientShares.into());
     }
     
-    let withdrawal_amount = shares_to_burn as u128 * total_assets as u128 / total_shares as u128;
-    Ok(withdrawal_amount.min(u64::MAX as u128) as u64)
+    // Round against the withdrawer so redemptions never drain remaining holders
+    mul_div(
+        shares_to_burn,
+        total_assets.safe_add(VIRTUAL_ASSETS)?,
+        total_shares.safe_add(VIRTUAL_SHARES)?,
+        Rounding::Down,
+    )
 }
 
 /// Calculate the current delta as a percentage of total value
@@ -133,10 +179,10 @@ pub fn calculate_delta_percentage(
         return Ok(0);
     }
     
-    let delta = long_position + short_position;
-    let delta_percentage = delta.abs() as u128 * BASIS_POINTS_DIVISOR as u128 / total_value as u128;
+    let delta = long_position.safe_add(short_position)?;
+    let delta_percentage = mul_div(delta.unsigned_abs(), BASIS_POINTS_DIVISOR, total_value, Rounding::Down)?;
     
-    Ok(delta_percentage.min(u16::MAX as u128) as u16)
+    Ok(delta_percentage.min(u16::MAX as u64) as u16)
 }
 
 /// Check if a rebalance is needed based on delta threshold
@@ -159,10 +205,10 @@ pub fn validate_vault_params(
     performance_fee: u16,
 ) -> Result<()> {
     require!(target_leverage > 0 && target_leverage <= 10, VaultError::InvalidLeverage);
-    require!(rebalance_threshold <= 1000, VaultError::InvalidThreshold); // Max 10%
+    require!(rebalance_threshold <= 1000, VaultError::InvalidRebalanceThreshold); // Max 10%
     require!(max_slippage <= 500, VaultError::InvalidSlippage); // Max 5%
-    require!(management_fee <= 500, VaultError::InvalidAmount); // Max 5% annual
-    require!(performance_fee <= 2000, VaultError::InvalidAmount); // Max 20%
+    require!(management_fee <= 500, VaultError::InvalidFeeStructure); // Max 5% annual
+    require!(performance_fee <= 2000, VaultError::InvalidFeeStructure); // Max 20%
     
     Ok(())
 }
@@ -173,10 +219,10 @@ pub fn calculate_max_position_size(
     leverage: u8,
     safety_margin_bps: u16,
 ) -> Result<u64> {
-    let max_theoretical = available_capital * leverage as u64;
-    let safety_adjustment = max_theoretical * safety_margin_bps as u64 / BASIS_POINTS_DIVISOR;
+    let max_theoretical = available_capital.safe_mul(leverage as u64)?;
+    let safety_adjustment = bps_of(max_theoretical, safety_margin_bps, Rounding::Up)?;
     
-    Ok(max_theoretical - safety_adjustment)
+    max_theoretical.safe_sub(safety_adjustment)
 }
 
 /// Format a price for display (assuming 6 decimal places)
@@ -205,7 +251,7 @@ pub fn parse_price(price_str: &str) -> Result<u64> {
         0
     };
     
-    Ok(integer_part * PRICE_PRECISION + fractional_part)
+    integer_part.safe_mul(PRICE_PRECISION)?.safe_add(fractional_part)
 }
 
 /// Check if two prices are within acceptable slippage tolerance
@@ -220,8 +266,141 @@ pub fn is_within_slippage_tolerance(
         expected_price - actual_price
     };
     
-    let max_allowed_diff = expected_price * max_slippage_bps as u64 / BASIS_POINTS_DIVISOR;
-    price_diff <= max_allowed_diff
+    // Fail closed: a tolerance that cannot be computed is never met
+    match bps_of(expected_price, max_slippage_bps, Rounding::Down) {
+        Ok(max_allowed_diff) => price_diff <= max_allowed_diff,
+        Err(_) => false,
+    }
+}
+
+/// Calculate the average fill price from filled base and quote amounts
+pub fn calculate_fill_price(base_filled: u64, quote_filled: u64) -> Result<u64> {
+    if base_filled == 0 {
+        return Err(VaultError::PriceCalculationFailed.into());
+    }
+    
+    mul_div(quote_filled, BASE_PRECISION, base_filled, Rounding::Down)
+}
+
+/// Calculate the slippage of an actual price against an expected price in basis points
+pub fn calculate_slippage_bps(expected_price: u64, actual_price: u64) -> u16 {
+    if expected_price == 0 {
+        return u16::MAX;
+    }
+    
+    let price_diff = if actual_price > expected_price {
+        actual_price - expected_price
+    } else {
+        expected_price - actual_price
+    };
+    
+    let slippage = mul_div(price_diff, BASIS_POINTS_DIVISOR, expected_price, Rounding::Up)
+        .unwrap_or(u64::MAX);
+    slippage.min(u16::MAX as u64) as u16
+}
+
+/// Apply a trade to a position carried at an average entry price.
+/// Returns the new position, the new average entry price and the PnL
+/// realized by any part of the trade that reduced the position.
+pub fn calculate_position_after_trade(
+    position: i64,
+    average_entry_price: u64,
+    trade_base: i64,
+    trade_price: u64,
+) -> Result<(i64, u64, i64)> {
+    let new_position = position.safe_add(trade_base)?;
+    if new_position == 0 && position == 0 {
+        return Ok((0, 0, 0));
+    }
+    
+    // Opening or increasing: blend the trade into the average entry price
+    if position == 0 || position.signum() == trade_base.signum() {
+        let cost = (position.unsigned_abs() as u128)
+            .safe_mul(average_entry_price as u128)?
+            .safe_add((trade_base.unsigned_abs() as u128).safe_mul(trade_price as u128)?)?;
+        let average = div_round(cost, new_position.unsigned_abs() as u128, Rounding::Up)?;
+        return Ok((new_position, to_u64(average)?, 0));
+    }
+    
+    // Reducing: realize PnL on the closed size against the entry price
+    let closed = position.unsigned_abs().min(trade_base.unsigned_abs()) as i128;
+    let price_diff = (trade_price as i128).safe_sub(average_entry_price as i128)?;
+    let realized_pnl = closed
+        .safe_mul(price_diff)?
+        .safe_mul(position.signum() as i128)?
+        .safe_div(BASE_PRECISION as i128)?;
+    
+    let new_average = if new_position == 0 {
+        0
+    } else if new_position.signum() == position.signum() {
+        average_entry_price
+    } else {
+        // Flipped through zero: the remainder is opened at the trade price
+        trade_price
+    };
+    
+    Ok((new_position, new_average, to_i64(realized_pnl)?))
+}
+
+/// Calculate the funding settled on a perp position between two cumulative
+/// funding rate observations. Positive values were received by the vault.
+pub fn calculate_funding_payment(
+    base_asset_amount: i64,
+    last_cumulative_funding_rate: i64,
+    cumulative_funding_rate: i64,
+) -> Result<i64> {
+    let rate_delta = (cumulative_funding_rate as i128).safe_sub(last_cumulative_funding_rate as i128)?;
+    let payment = rate_delta
+        .safe_mul(base_asset_amount as i128)?
+        .safe_div(FUNDING_PAYMENT_PRECISION)?;
+    to_i64(-payment)
+}
+
+/// Value `amount` of a collateral asset in quote units, less its haircut
+pub fn calculate_collateral_value(
+    amount: u64,
+    oracle_price: u64,
+    decimals: u8,
+    haircut_bps: u16,
+) -> Result<u64> {
+    require!(oracle_price > 0, VaultError::InvalidOraclePrice);
+
+    let unit = 10u64
+        .checked_pow(decimals as u32)
+        .ok_or(VaultError::MathOverflow)?;
+    let gross_value = mul_div(amount, oracle_price, unit, Rounding::Down)?;
+    let haircut = bps_of(gross_value, haircut_bps, Rounding::Up)?;
+    gross_value.safe_sub(haircut)
+}
+
+/// Accept a live oracle reading only if it is positive, at most
+/// `MAX_ORACLE_DELAY_SLOTS` old, and its confidence interval is within
+/// `MAX_ORACLE_CONFIDENCE_BPS` of the price
+pub fn validate_oracle_price(price: i64, confidence: u64, delay_slots: i64) -> Result<u64> {
+    require!(price > 0, VaultError::InvalidOraclePrice);
+    require_at_most!(delay_slots, MAX_ORACLE_DELAY_SLOTS, VaultError::OracleStale);
+
+    let price = price as u64;
+    let max_confidence = bps_of(price, MAX_ORACLE_CONFIDENCE_BPS, Rounding::Down)?;
+    require_at_most!(confidence, max_confidence, VaultError::OracleConfidenceTooWide);
+    Ok(price)
+}
+
+/// Hash an allowlist leaf committing to a depositor and their deposit cap
+pub fn allowlist_leaf(depositor: &Pubkey, deposit_cap: u64) -> [u8; 32] {
+    keccak::hashv(&[depositor.as_ref(), &deposit_cap.to_le_bytes()]).0
+}
+
+/// Verify a Merkle proof built with sorted-pair keccak hashing
+pub fn verify_merkle_proof(proof: &[[u8; 32]], root: [u8; 32], leaf: [u8; 32]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| {
+        if node <= *sibling {
+            keccak::hashv(&[&node, sibling]).0
+        } else {
+            keccak::hashv(&[sibling, &node]).0
+        }
+    });
+    computed == root
 }
 
 #[cfg(test)]
@@ -250,6 +429,76 @@ mod tests {
         assert_eq!(shares, 1000);
     }
 
+    #[test]
+    fn test_donation_does_not_zero_out_next_depositor() {
+        // Attacker holds 1 share and inflates assets with a 1000 token donation
+        let total_shares = 1;
+        let total_assets = 1 + 1_000_000_000;
+        
+        let shares = calculate_shares_to_mint(100_000_000, total_assets, total_shares).unwrap();
+        assert!(shares > 0);
+        
+        // Redeeming the minted shares never returns more than was deposited
+        let assets = calculate_withdrawal_amount(shares, total_assets + 100_000_000, total_shares + shares).unwrap();
+        assert!(assets <= 100_000_000);
+    }
+
+    #[test]
+    fn test_share_price_matches_mint_rate() {
+        // An empty vault prices shares at 1:1
+        assert_eq!(calculate_share_price(0, 0).unwrap(), PRICE_PRECISION);
+
+        // A donation into an empty vault moves the price only as far as minting does
+        let price = calculate_share_price(1_000_000, 0).unwrap();
+        assert_eq!(price, 2 * PRICE_PRECISION);
+        let shares = calculate_shares_to_mint(2_000_000, 1_000_000, 0).unwrap();
+        assert_eq!(shares, 2_000_000 * PRICE_PRECISION / price);
+    }
+
+    #[test]
+    fn test_collateral_value() {
+        // 2 SOL (9 decimals) at $150 with a 10% haircut
+        let value = calculate_collateral_value(2_000_000_000, 150_000_000, 9, 1_000).unwrap();
+        assert_eq!(value, 270_000_000);
+        
+        // Haircut rounds against the depositor
+        assert_eq!(calculate_collateral_value(1, 1_000_001, 6, 1).unwrap(), 0);
+        assert!(calculate_collateral_value(1_000_000, 0, 6, 0).is_err());
+    }
+
+    #[test]
+    fn test_validate_oracle_price() {
+        assert_eq!(validate_oracle_price(150_000_000, 1_000_000, 0).unwrap(), 150_000_000);
+        assert_eq!(validate_oracle_price(150_000_000, 3_000_000, MAX_ORACLE_DELAY_SLOTS).unwrap(), 150_000_000);
+
+        assert!(validate_oracle_price(0, 0, 0).is_err());
+        assert!(validate_oracle_price(-1, 0, 0).is_err());
+        assert_eq!(
+            validate_oracle_price(150_000_000, 0, MAX_ORACLE_DELAY_SLOTS + 1).unwrap_err(),
+            error!(VaultError::OracleStale)
+        );
+        assert_eq!(
+            validate_oracle_price(150_000_000, 3_000_001, 0).unwrap_err(),
+            error!(VaultError::OracleConfidenceTooWide)
+        );
+    }
+
+    #[test]
+    fn test_verify_merkle_proof() {
+        let alice = allowlist_leaf(&Pubkey::new_unique(), 1_000_000_000);
+        let bob = allowlist_leaf(&Pubkey::new_unique(), u64::MAX);
+        let root = if alice <= bob {
+            keccak::hashv(&[&alice, &bob]).0
+        } else {
+            keccak::hashv(&[&bob, &alice]).0
+        };
+        
+        assert!(verify_merkle_proof(&[bob], root, alice));
+        assert!(verify_merkle_proof(&[alice], root, bob));
+        assert!(!verify_merkle_proof(&[alice], root, alice));
+        assert!(!verify_merkle_proof(&[], root, alice));
+    }
+
     #[test]
     fn test_format_parse_price() {
         let price = 1_500_000; // 1.5
@@ -259,4 +508,60 @@ mod tests {
         let parsed = parse_price("1.5").unwrap();
         assert_eq!(parsed, 1_500_000);
     }
+
+    #[test]
+    fn test_fill_price_slippage() {
+        // 2 SOL filled for 300 USDC
+        let fill_price = calculate_fill_price(2_000_000_000, 300_000_000).unwrap();
+        assert_eq!(fill_price, 150_000_000);
+        
+        assert_eq!(calculate_slippage_bps(150_000_000, 151_500_000), 100);
+        assert_eq!(calculate_slippage_bps(150_000_000, 148_500_000), 100);
+        assert!(is_within_slippage_tolerance(150_000_000, 151_500_000, 100));
+        assert!(!is_within_slippage_tolerance(150_000_000, 151_500_000, 50));
+        
+        assert!(calculate_fill_price(0, 300_000_000).is_err());
+    }
+
+    #[test]
+    fn test_position_after_trade() {
+        // Open 2 SOL long at 150, add 2 more at 160
+        let (position, average, pnl) = calculate_position_after_trade(0, 0, 2_000_000_000, 150_000_000).unwrap();
+        assert_eq!((position, average, pnl), (2_000_000_000, 150_000_000, 0));
+        let (position, average, pnl) = calculate_position_after_trade(position, average, 2_000_000_000, 160_000_000).unwrap();
+        assert_eq!((position, average, pnl), (4_000_000_000, 155_000_000, 0));
+        
+        // Sell 1 at 165 realizes 10 USDC
+        let (position, average, pnl) = calculate_position_after_trade(position, average, -1_000_000_000, 165_000_000).unwrap();
+        assert_eq!((position, average, pnl), (3_000_000_000, 155_000_000, 10_000_000));
+        
+        // Sell 4 at 150 closes the long at a 15 USDC loss and opens 1 short at 150
+        let (position, average, pnl) = calculate_position_after_trade(position, average, -4_000_000_000, 150_000_000).unwrap();
+        assert_eq!((position, average, pnl), (-1_000_000_000, 150_000_000, -15_000_000));
+    }
+
+    #[test]
+    fn test_performance_fees() {
+        // 20% of a 100 USDC profit: 1000 shares up 0.10 each
+        assert_eq!(calculate_performance_fees(1_100_000, 1_000_000, 1_000_000_000, 2000).unwrap(), 20_000_000);
+        assert_eq!(calculate_performance_fees(900_000, 1_000_000, 1_000_000_000, 2000).unwrap(), 0);
+
+        // Gains already charged up to the high-water mark are not charged again
+        assert_eq!(calculate_performance_fees(1_100_000, 1_100_000, 1_000_000_000, 2000).unwrap(), 0);
+
+        // No cost basis, no fee on the whole value
+        assert_eq!(calculate_performance_fees(1_000_000, 0, 1_000_000_000, 2000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_annualized_return() {
+        // 1% in 30 days annualizes to 1% * 365 / 30
+        assert_eq!(calculate_annualized_return_bps(1_000_000, 1_010_000, 30 * 86400).unwrap(), 1216);
+        // A full year returns the raw change, losses are negative
+        assert_eq!(calculate_annualized_return_bps(1_000_000, 1_050_000, SECONDS_PER_YEAR).unwrap(), 500);
+        assert_eq!(calculate_annualized_return_bps(1_000_000, 990_000, SECONDS_PER_YEAR).unwrap(), -100);
+
+        assert!(calculate_annualized_return_bps(0, 1_000_000, 86400).is_err());
+        assert!(calculate_annualized_return_bps(1_000_000, 1_000_000, 0).is_err());
+    }
 }
\ No newline at end of file
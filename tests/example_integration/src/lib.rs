@@ -0,0 +1,204 @@
+//! Example integration that pools its users' tokens in a PDA and holds a
+//! single position in a Delta Neutral Vault. It uses only the stable CPI
+//! surface: `deposit_for`, `redeem` and the return data they set.
+
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{self, TokenInterface};
+use delta_neutral_vault::cpi::accounts::{DepositFor, Redeem};
+use delta_neutral_vault::program::DeltaNeutralVault;
+
+declare_id!("VauLtRouter11111111111111111111111111111111");
+
+pub const ROUTER_SEED: &[u8] = b"router";
+
+#[program]
+pub mod vault_router {
+    use super::*;
+
+    pub fn initialize(ctx: Context<Initialize>) -> Result<()> {
+        let router = &mut ctx.accounts.router;
+        router.vault = ctx.accounts.vault_state.key();
+        router.bump = ctx.bumps.router;
+        Ok(())
+    }
+
+    /// Deposit `amount` of the router's tokens into the vault, for at least
+    /// `min_shares_out` shares
+    pub fn deposit(ctx: Context<RouterDeposit>, amount: u64, min_shares_out: u64) -> Result<()> {
+        let bump = ctx.accounts.router.bump;
+        let signer_seeds = &[ROUTER_SEED, ctx.accounts.router.vault.as_ref(), &[bump]];
+        let signer = &[&signer_seeds[..]];
+
+        // The router PDA signs as depositor and owns the shares, while the
+        // caller pays rent for the router's vault user account
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.vault_program.to_account_info(),
+            DepositFor {
+                vault_state: ctx.accounts.vault_state.to_account_info(),
+                protocol_config: ctx.accounts.protocol_config.to_account_info(),
+                vault_token_account: ctx.accounts.vault_token_account.to_account_info(),
+                token_mint: ctx.accounts.token_mint.to_account_info(),
+                depositor_token_account: ctx.accounts.router_token_account.to_account_info(),
+                vault_user: ctx.accounts.vault_user.to_account_info(),
+                depositor: ctx.accounts.router.to_account_info(),
+                owner: ctx.accounts.router.to_account_info(),
+                referrer_account: None,
+                payer: ctx.accounts.payer.to_account_info(),
+                token_program: ctx.accounts.token_program.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            },
+            signer,
+        );
+        let shares = delta_neutral_vault::cpi::deposit_for(cpi_ctx, amount, None, min_shares_out)?.get();
+
+        let router = &mut ctx.accounts.router;
+        router.shares = router.shares.checked_add(shares).ok_or(RouterError::MathOverflow)?;
+        msg!("Router deposited {} for {} shares", amount, shares);
+        Ok(())
+    }
+
+    /// Redeem `shares` back into the router's token account, for at least
+    /// `min_amount_out` tokens
+    pub fn redeem(ctx: Context<RouterRedeem>, shares: u64, min_amount_out: u64) -> Result<()> {
+        let bump = ctx.accounts.router.bump;
+        let signer_seeds = &[ROUTER_SEED, ctx.accounts.router.vault.as_ref(), &[bump]];
+        let signer = &[&signer_seeds[..]];
+
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.vault_program.to_account_info(),
+            Redeem {
+                vault_state: ctx.accounts.vault_state.to_account_info(),
+                protocol_config: ctx.accounts.protocol_config.to_account_info(),
+                vault_token_account: ctx.accounts.vault_token_account.to_account_info(),
+                token_mint: ctx.accounts.token_mint.to_account_info(),
+                receiver_token_account: ctx.accounts.router_token_account.to_account_info(),
+                vault_user: ctx.accounts.vault_user.to_account_info(),
+                owner: ctx.accounts.router.to_account_info(),
+                referrer_account: None,
+                token_program: ctx.accounts.token_program.to_account_info(),
+            },
+            signer,
+        );
+        let amount_out = delta_neutral_vault::cpi::redeem(cpi_ctx, shares, min_amount_out)?.get();
+
+        let router = &mut ctx.accounts.router;
+        router.shares = router.shares.checked_sub(shares).ok_or(RouterError::MathOverflow)?;
+        msg!("Router redeemed {} shares for {}", shares, amount_out);
+        Ok(())
+    }
+}
+
+#[account]
+pub struct Router {
+    pub vault: Pubkey,
+    pub shares: u64,
+    pub bump: u8,
+}
+
+impl Router {
+    pub const LEN: usize = 8 + 32 + 8 + 1;
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(
+        init,
+        payer = payer,
+        space = Router::LEN,
+        seeds = [ROUTER_SEED, vault_state.key().as_ref()],
+        bump
+    )]
+    pub router: Account<'info, Router>,
+
+    /// CHECK: Vault the router deposits into, validated by the vault program on use
+    pub vault_state: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RouterDeposit<'info> {
+    #[account(
+        mut,
+        seeds = [ROUTER_SEED, vault_state.key().as_ref()],
+        bump = router.bump
+    )]
+    pub router: Account<'info, Router>,
+
+    /// CHECK: Validated by the vault program
+    #[account(mut, address = router.vault)]
+    pub vault_state: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the vault program
+    pub protocol_config: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the vault program
+    #[account(mut)]
+    pub vault_token_account: UncheckedAccount<'info>,
+
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = token_mint,
+        token::authority = router
+    )]
+    pub router_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    /// CHECK: The router's user account in the vault, created on first deposit
+    #[account(mut)]
+    pub vault_user: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub vault_program: Program<'info, DeltaNeutralVault>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RouterRedeem<'info> {
+    #[account(
+        mut,
+        seeds = [ROUTER_SEED, vault_state.key().as_ref()],
+        bump = router.bump
+    )]
+    pub router: Account<'info, Router>,
+
+    /// CHECK: Validated by the vault program
+    #[account(mut, address = router.vault)]
+    pub vault_state: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the vault program
+    pub protocol_config: UncheckedAccount<'info>,
+
+    /// CHECK: Validated by the vault program
+    #[account(mut)]
+    pub vault_token_account: UncheckedAccount<'info>,
+
+    pub token_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        token::mint = token_mint,
+        token::authority = router
+    )]
+    pub router_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    /// CHECK: The router's user account in the vault
+    #[account(mut)]
+    pub vault_user: UncheckedAccount<'info>,
+
+    pub vault_program: Program<'info, DeltaNeutralVault>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[error_code]
+pub enum RouterError {
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+}
@@ -14,14 +14,20 @@ describe("Delta Neutral Vault Integration Tests", () => {
     let adminKeypair: Keypair;
     let userKeypair: Keypair;
     let tokenMint: PublicKey;
+    let protocolConfig: PublicKey;
     let vaultState: PublicKey;
     let vaultTokenAccount: PublicKey;
     let userTokenAccount: PublicKey;
 
+    // Vault 0 keeps the pre-vault-id address `[VAULT_SEED, admin]`
+    const vaultIdSeed = (vaultId: anchor.BN) =>
+        vaultId.isZero() ? Buffer.alloc(0) : vaultId.toArrayLike(Buffer, "le", 8);
+
     const VAULT_PARAMS = {
         targetLeverage: 2,
         rebalanceThreshold: 100, // 1%
         maxSlippage: 50, // 0.5%
+        vaultId: new anchor.BN(0),
     };
 
     before(async () => {
@@ -77,12 +83,53 @@ describe("Delta Neutral Vault Integration Tests", () => {
         console.log("✅ Test setup completed");
     });
 
+    it("Should initialize protocol config", async () => {
+        console.log("🧪 Testing protocol config initialization...");
+
+        // Derive PDA for the protocol config singleton
+        const [protocolConfigPda] = PublicKey.findProgramAddressSync(
+            [Buffer.from("protocol_config")],
+            program.programId
+        );
+        protocolConfig = protocolConfigPda;
+
+        // Only the program's upgrade authority (the deploying wallet) may initialize
+        const [programData] = PublicKey.findProgramAddressSync(
+            [program.programId.toBuffer()],
+            new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+        );
+
+        try {
+            const tx = await program.methods
+                .initializeProtocol(1000) // 10% of vault fees
+                .accounts({
+                    protocolConfig: protocolConfig,
+                    owner: provider.wallet.publicKey,
+                    program: program.programId,
+                    programData: programData,
+                    systemProgram: SystemProgram.programId,
+                })
+                .rpc();
+
+            console.log(`✅ Protocol config initialized. Tx: ${tx}`);
+
+            const configAccount = await program.account.protocolConfig.fetch(protocolConfig);
+            assert.equal(configAccount.owner.toString(), provider.wallet.publicKey.toString());
+            assert.equal(configAccount.protocolFeeBps, 1000);
+            assert.equal(configAccount.paused, false);
+
+        } catch (error) {
+            console.error("❌ Protocol config initialization failed:", error);
+            throw error;
+        }
+    });
+
     it("Should initialize vault successfully", async () => {
         console.log("🧪 Testing vault initialization...");
 
         // Derive PDA for vault state
         const [vaultStatePda, vaultBump] = PublicKey.findProgramAddressSync(
-            [Buffer.from("vault"), adminKeypair.publicKey.toBuffer()],
+            [Buffer.from("vault"), adminKeypair.publicKey.toBuffer(), vaultIdSeed(VAULT_PARAMS.vaultId)],
             program.programId
         );
         vaultState = vaultStatePda;
@@ -94,6 +141,18 @@ describe("Delta Neutral Vault Integration Tests", () => {
         );
         vaultTokenAccount = vaultTokenAccountPda;
 
+        // Derive PDA for the vault's registry entry
+        const [registryEntryPda] = PublicKey.findProgramAddressSync(
+            [Buffer.from("registry"), vaultState.toBuffer()],
+            program.programId
+        );
+
+        // Derive PDA for the vault's share-price history
+        const [historyPda] = PublicKey.findProgramAddressSync(
+            [Buffer.from("history"), vaultState.toBuffer()],
+            program.programId
+        );
+
         console.log(`Vault State PDA: ${vaultState.toString()}`);
         console.log(`Vault Token Account PDA: ${vaultTokenAccount.toString()}`);
 
@@ -101,6 +160,7 @@ describe("Delta Neutral Vault Integration Tests", () => {
             // Initialize the vault
             const tx = await program.methods
                 .initializeVault(
+                    VAULT_PARAMS.vaultId,
                     VAULT_PARAMS.targetLeverage,
                     VAULT_PARAMS.rebalanceThreshold,
                     VAULT_PARAMS.maxSlippage
@@ -108,6 +168,9 @@ describe("Delta Neutral Vault Integration Tests", () => {
                 .accounts({
                     vaultState: vaultState,
                     vaultTokenAccount: vaultTokenAccount,
+                    registryEntry: registryEntryPda,
+                    history: historyPda,
+                    protocolConfig: protocolConfig,
                     admin: adminKeypair.publicKey,
                     tokenMint: tokenMint,
                     driftProgram: new PublicKey("DRiFTvSoSLjH8XJx2wSJ1GL9jR8qXhF2vC2QBK5FyL9"),
@@ -122,6 +185,7 @@ describe("Delta Neutral Vault Integration Tests", () => {
 
             // Verify vault state
             const vaultAccount = await program.account.vaultState.fetch(vaultState);
+            assert.equal(vaultAccount.version, 2);
             assert.equal(vaultAccount.admin.toString(), adminKeypair.publicKey.toString());
             assert.equal(vaultAccount.targetLeverage, VAULT_PARAMS.targetLeverage);
             assert.equal(vaultAccount.rebalanceThreshold, VAULT_PARAMS.rebalanceThreshold);
@@ -140,7 +204,7 @@ describe("Delta Neutral Vault Integration Tests", () => {
     it("Should allow deposits", async () => {
         console.log("🧪 Testing deposits...");
 
-        const depositAmount = 100000; // 0.1 tokens
+        const depositAmount = 1000000; // 1 token (minimum initial deposit)
 
         try {
             // Get initial balances
@@ -150,15 +214,26 @@ describe("Delta Neutral Vault Integration Tests", () => {
             console.log(`Initial vault balance: ${initialVaultBalance.amount}`);
             console.log(`Initial user balance: ${initialUserBalance.amount}`);
 
+            const [vaultUser] = PublicKey.findProgramAddressSync(
+                [Buffer.from("user"), vaultState.toBuffer(), userKeypair.publicKey.toBuffer()],
+                program.programId
+            );
+
             // Deposit funds
             const tx = await program.methods
-                .deposit(new anchor.BN(depositAmount))
+                .deposit(new anchor.BN(depositAmount), null)
                 .accounts({
                     vaultState: vaultState,
                     vaultTokenAccount: vaultTokenAccount,
+                    tokenMint: tokenMint,
                     depositorTokenAccount: userTokenAccount,
+                    vaultUser: vaultUser,
+                    protocolConfig: protocolConfig,
                     depositor: userKeypair.publicKey,
+                    owner: userKeypair.publicKey,
+                    referrerAccount: null,
                     tokenProgram: TOKEN_PROGRAM_ID,
+                    systemProgram: SystemProgram.programId,
                 })
                 .signers([userKeypair])
                 .rpc();
@@ -197,7 +272,7 @@ describe("Delta Neutral Vault Integration Tests", () => {
     it("Should allow withdrawals", async () => {
         console.log("🧪 Testing withdrawals...");
 
-        const withdrawAmount = 50000; // 0.05 tokens
+        const withdrawAmount = 500000; // 0.5 tokens
 
         try {
             // Get initial balances
@@ -207,14 +282,23 @@ describe("Delta Neutral Vault Integration Tests", () => {
             console.log(`Initial vault balance: ${initialVaultBalance.amount}`);
             console.log(`Initial user balance: ${initialUserBalance.amount}`);
 
+            const [vaultUser] = PublicKey.findProgramAddressSync(
+                [Buffer.from("user"), vaultState.toBuffer(), userKeypair.publicKey.toBuffer()],
+                program.programId
+            );
+
             // Withdraw funds
             const tx = await program.methods
                 .withdraw(new anchor.BN(withdrawAmount))
                 .accounts({
                     vaultState: vaultState,
                     vaultTokenAccount: vaultTokenAccount,
-                    withdrawerTokenAccount: userTokenAccount,
-                    withdrawer: userKeypair.publicKey,
+                    tokenMint: tokenMint,
+                    receiverTokenAccount: userTokenAccount,
+                    vaultUser: vaultUser,
+                    protocolConfig: protocolConfig,
+                    owner: userKeypair.publicKey,
+                    referrerAccount: null,
                     tokenProgram: TOKEN_PROGRAM_ID,
                 })
                 .signers([userKeypair])
@@ -240,8 +324,8 @@ describe("Delta Neutral Vault Integration Tests", () => {
 
             // Verify vault state
             const vaultAccount = await program.account.vaultState.fetch(vaultState);
-            assert.equal(vaultAccount.totalAssets, 50000); // 100000 - 50000
-            assert.equal(vaultAccount.totalShares, 50000);
+            assert.equal(vaultAccount.totalAssets, 500000); // 1000000 - 500000
+            assert.equal(vaultAccount.totalShares, 500000);
 
             console.log("✅ Withdrawal verification completed");
 
@@ -257,7 +341,7 @@ describe("Delta Neutral Vault Integration Tests", () => {
         try {
             // Trigger emergency stop
             const tx = await program.methods
-                .emergencyStop()
+                .emergencyStop({ adminAction: {} })
                 .accounts({
                     vaultState: vaultState,
                     admin: adminKeypair.publicKey,
@@ -269,7 +353,7 @@ describe("Delta Neutral Vault Integration Tests", () => {
 
             // Verify vault state
             const vaultAccount = await program.account.vaultState.fetch(vaultState);
-            assert.isTrue(vaultAccount.emergencyStop);
+            assert.equal(vaultAccount.emergencyStop, 1);
 
             console.log("✅ Emergency stop verification completed");
 
@@ -292,7 +376,11 @@ describe("Delta Neutral Vault Integration Tests", () => {
                 .updateVaultParams(
                     newTargetLeverage,
                     newRebalanceThreshold,
-                    newMaxSlippage
+                    newMaxSlippage,
+                    null,
+                    null,
+                    null,
+                    null
                 )
                 .accounts({
                     vaultState: vaultState,
@@ -317,6 +405,168 @@ describe("Delta Neutral Vault Integration Tests", () => {
         }
     });
 
+    it("Should close an emptied user and vault", async () => {
+        console.log("🧪 Testing user and vault closure...");
+
+        // A separate vault, so the emergency-stopped one above is left alone
+        const vaultId = new anchor.BN(1);
+        const [closableVault] = PublicKey.findProgramAddressSync(
+            [Buffer.from("vault"), adminKeypair.publicKey.toBuffer(), vaultIdSeed(vaultId)],
+            program.programId
+        );
+        const [closableTokenAccount] = PublicKey.findProgramAddressSync(
+            [Buffer.from("vault_token_account"), closableVault.toBuffer()],
+            program.programId
+        );
+        const [registryEntry] = PublicKey.findProgramAddressSync(
+            [Buffer.from("registry"), closableVault.toBuffer()],
+            program.programId
+        );
+        const [history] = PublicKey.findProgramAddressSync(
+            [Buffer.from("history"), closableVault.toBuffer()],
+            program.programId
+        );
+        const [vaultUser] = PublicKey.findProgramAddressSync(
+            [Buffer.from("user"), closableVault.toBuffer(), userKeypair.publicKey.toBuffer()],
+            program.programId
+        );
+        const adminTokenAccount = await createAccount(
+            provider.connection,
+            adminKeypair,
+            tokenMint,
+            adminKeypair.publicKey
+        );
+
+        await program.methods
+            .initializeVault(
+                vaultId,
+                VAULT_PARAMS.targetLeverage,
+                VAULT_PARAMS.rebalanceThreshold,
+                VAULT_PARAMS.maxSlippage
+            )
+            .accounts({
+                vaultState: closableVault,
+                vaultTokenAccount: closableTokenAccount,
+                registryEntry: registryEntry,
+                history: history,
+                protocolConfig: protocolConfig,
+                admin: adminKeypair.publicKey,
+                tokenMint: tokenMint,
+                driftProgram: new PublicKey("DRiFTvSoSLjH8XJx2wSJ1GL9jR8qXhF2vC2QBK5FyL9"),
+                tokenProgram: TOKEN_PROGRAM_ID,
+                systemProgram: SystemProgram.programId,
+                rent: SYSVAR_RENT_PUBKEY,
+            })
+            .signers([adminKeypair])
+            .rpc();
+
+        const depositAmount = 1000000;
+        await program.methods
+            .deposit(new anchor.BN(depositAmount), null)
+            .accounts({
+                vaultState: closableVault,
+                vaultTokenAccount: closableTokenAccount,
+                tokenMint: tokenMint,
+                depositorTokenAccount: userTokenAccount,
+                vaultUser: vaultUser,
+                protocolConfig: protocolConfig,
+                depositor: userKeypair.publicKey,
+                owner: userKeypair.publicKey,
+                referrerAccount: null,
+                tokenProgram: TOKEN_PROGRAM_ID,
+                systemProgram: SystemProgram.programId,
+            })
+            .signers([userKeypair])
+            .rpc();
+
+        const closeUser = () => program.methods
+            .closeUser()
+            .accounts({
+                vaultState: closableVault,
+                vaultUser: vaultUser,
+                owner: userKeypair.publicKey,
+                receiver: userKeypair.publicKey,
+            })
+            .signers([userKeypair])
+            .rpc();
+        const closeVault = () => program.methods
+            .closeVault()
+            .accounts({
+                vaultState: closableVault,
+                protocolConfig: protocolConfig,
+                registryEntry: registryEntry,
+                vaultTokenAccount: closableTokenAccount,
+                tokenMint: tokenMint,
+                adminTokenAccount: adminTokenAccount,
+                history: history,
+                driftUser: null,
+                driftUserStats: null,
+                driftState: null,
+                driftProgram: null,
+                admin: adminKeypair.publicKey,
+                receiver: adminKeypair.publicKey,
+                tokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .signers([adminKeypair])
+            .rpc();
+
+        // Neither closes while the user still holds shares
+        try {
+            await closeUser();
+            assert.fail("close_user should fail while shares are outstanding");
+        } catch (error) {
+            assert.include(error.toString(), "SharesOutstanding");
+        }
+        try {
+            await closeVault();
+            assert.fail("close_vault should fail while shares are outstanding");
+        } catch (error) {
+            assert.include(error.toString(), "VaultNotEmpty");
+        }
+
+        const redeem = (minAmountOut: number) =>
+            program.methods
+                .redeem(new anchor.BN(depositAmount), new anchor.BN(minAmountOut))
+                .accounts({
+                    vaultState: closableVault,
+                    vaultTokenAccount: closableTokenAccount,
+                    tokenMint: tokenMint,
+                    receiverTokenAccount: userTokenAccount,
+                    vaultUser: vaultUser,
+                    protocolConfig: protocolConfig,
+                    owner: userKeypair.publicKey,
+                    referrerAccount: null,
+                    tokenProgram: TOKEN_PROGRAM_ID,
+                })
+                .signers([userKeypair])
+                .rpc();
+
+        // A redemption paying less than the caller's minimum is rejected
+        try {
+            await redeem(depositAmount + 1);
+            assert.fail("redeem should fail below min_amount_out");
+        } catch (error) {
+            assert.include(error.toString(), "SlippageExceeded");
+        }
+        await redeem(0);
+
+        await closeUser();
+        assert.isNull(await provider.connection.getAccountInfo(vaultUser));
+
+        // Whatever the redemption left behind is swept to the admin
+        const dust = (await getAccount(provider.connection, closableTokenAccount)).amount;
+        await closeVault();
+        for (const account of [closableVault, closableTokenAccount, registryEntry, history]) {
+            assert.isNull(await provider.connection.getAccountInfo(account));
+        }
+        assert.equal(
+            (await getAccount(provider.connection, adminTokenAccount)).amount,
+            dust
+        );
+
+        console.log("✅ User and vault closure verified");
+    });
+
     after(async () => {
         console.log("🧹 Cleaning up test environment...");
         
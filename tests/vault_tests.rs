@@ -10,7 +10,7 @@ use solana_sdk::{
 use delta_neutral_vault::{
     instruction::*,
     state::*,
-    error::VaultError,
+    errors::VaultError,
 };
 
 pub struct VaultTestFixture {
@@ -426,15 +426,23 @@ async fn test_fee_calculation() {
 
     // Test performance fee calculation
     let total_value = 1_200_000_000; // 1200 tokens
-    let net_deposits = 1_000_000_000i64; // 1000 tokens deposited
+    let total_shares = 1_000_000_000; // 1000 shares
+    let share_price = 1_200_000; // 1.2 tokens per share
+    let high_water_mark = 1_000_000; // 1.0 tokens per share
     let performance_fee_bps = 1000; // 10%
 
-    let performance_fees = calculate_performance_fees(total_value, net_deposits, performance_fee_bps).unwrap();
+    let performance_fees =
+        calculate_performance_fees(share_price, high_water_mark, total_shares, performance_fee_bps).unwrap();
     assert_eq!(performance_fees, 20_000_000); // 10% of 200 profit = 20 tokens
 
+    // Nothing is charged below the high-water mark
+    let performance_fees =
+        calculate_performance_fees(high_water_mark, share_price, total_shares, performance_fee_bps).unwrap();
+    assert_eq!(performance_fees, 0);
+
     // Test share price calculation
-    let share_price = calculate_share_price(total_value, 1_000_000_000).unwrap();
-    assert_eq!(share_price, 1_200_000); // 1.2 tokens per share
+    let share_price = calculate_share_price(total_value, total_shares).unwrap();
+    assert_eq!(share_price, 1_199_800); // 1.2 tokens per share, less the virtual offset
 }
 
 #[tokio::test]